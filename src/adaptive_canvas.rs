@@ -16,6 +16,9 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use cgmath::Matrix4;
+use cgmath::SquareMatrix;
+use cgmath::Vector3;
 use glium::BlitTarget;
 use glium::Rect;
 use glium::GlObject;
@@ -34,16 +37,48 @@ use glium::texture::Texture2dMultisample;
 use glium::uniforms::MagnifySamplerFilter;
 use webvr::VRLayer;
 
+use fxaa::Fxaa;
 use geometry::Geometry;
 use geometry::Texcoord;
+use quality::Quality;
+use upscaler::TemporalUpscaler;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Foveation {
+  pub center_size: f32,
+  pub periphery_scale: f32,
+}
+
+// a part of an eye's view, rendered into `viewport` and shown at `output`
+#[derive(Copy, Clone, Debug)]
+pub struct CanvasRegion {
+  pub eye: usize,
+  pub viewport: Rect,
+  pub window: [f32; 4],
+  pub output: Rect,
+}
+
+// the buffer that holds the final image of a frame
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Output {
+  Resolved,
+  PostProcessed,
+  Composited,
+  Upscaled,
+}
 
 pub struct AdaptiveCanvas {
   pub rectangle: Geometry,
+  pub regions: Vec<CanvasRegion>,
   pub viewports: [Rect; 2],
   pub viewport: Rect,
 
   color_buffer: Texture2d,
   color_buffers_msaa: Vec<Texture2dMultisample>,
+  composite_buffer: Texture2d,
+  post_buffer: Texture2d,
+  // a copy of the upscaled image, so the GUI isn't drawn into the history of the upscaler
+  upscaled_buffer: Texture2d,
   depth_buffer: DepthTexture2d,
   depth_buffers_msaa: Vec<DepthTexture2dMultisample>,
   layer: VRLayer,
@@ -51,15 +86,25 @@ pub struct AdaptiveCanvas {
   max_height: u32,
   max_msaa_level: usize,
   current_msaa_level: usize,
+  resolution_scale: f32,
+  eye_scales: [f32; 2],
+  foveation: Option<Foveation>,
+  fxaa: Fxaa,
+  fxaa_enabled: bool,
+  upscaler: TemporalUpscaler,
+  upscaling: bool,
+  output: Output,
 }
 
 impl<'a> AdaptiveCanvas {
   pub fn new(display: &Facade, max_width: u32, max_height: u32, max_msaa_level: usize) -> AdaptiveCanvas {
-    let max_half_width = max_width / 2;
     let mut color_buffers_msaa = Vec::new();
     let mut depth_buffers_msaa = Vec::new();
 
     let color_buffer = Texture2d::empty(display, max_width, max_height).unwrap();
+    let composite_buffer = Texture2d::empty(display, max_width, max_height).unwrap();
+    let post_buffer = Texture2d::empty(display, max_width, max_height).unwrap();
+    let upscaled_buffer = Texture2d::empty(display, max_width, max_height).unwrap();
     let depth_buffer = DepthTexture2d::empty(display, max_width, max_height).unwrap();
 
     for i in 1..max_msaa_level + 1 {
@@ -76,93 +121,242 @@ impl<'a> AdaptiveCanvas {
           2u32.pow(i as u32)).unwrap());
     }
 
-    AdaptiveCanvas {
+    let empty_rect = Rect { left: 0, bottom: 0, width: 0, height: 0 };
+
+    let mut canvas = AdaptiveCanvas {
       layer: VRLayer { texture_id: color_buffer.get_id(), .. Default::default() },
       rectangle: Geometry::new_quad(display, [2.0, 2.0], true),
-      viewports: [
-          Rect {
-            left: 0,
-            bottom: 0,
-            width: max_half_width,
-            height: max_height,
-          },
-          Rect {
-            left: max_half_width,
-            bottom: 0,
-            width: max_half_width,
-            height: max_height,
-          }],
-      viewport: Rect { left: 0, bottom: 0, width: max_width, height: max_height },
+      regions: Vec::new(),
+      viewports: [empty_rect, empty_rect],
+      viewport: empty_rect,
       color_buffer: color_buffer,
       color_buffers_msaa: color_buffers_msaa,
+      composite_buffer: composite_buffer,
+      post_buffer: post_buffer,
+      upscaled_buffer: upscaled_buffer,
       depth_buffer: depth_buffer,
       depth_buffers_msaa: depth_buffers_msaa,
       max_width: max_width,
       max_height: max_height,
       max_msaa_level: max_msaa_level,
       current_msaa_level: max_msaa_level,
-    }
+      resolution_scale: 1.0,
+      eye_scales: [1.0, 1.0],
+      foveation: None,
+      fxaa: Fxaa::new(display),
+      fxaa_enabled: false,
+      upscaler: TemporalUpscaler::new(display, max_width, max_height),
+      upscaling: false,
+      output: Output::Resolved,
+    };
+
+    canvas.update_regions();
+    canvas
+  }
+
+  pub fn register_quality_knobs(quality: &mut Quality) {
+    quality.register_knob("Resolution", "Resolution weight", (0.0, 1.0), 0.5, 0.5);
+    // still called MSAA, which it was before FXAA was added, to keep logs and cost models valid
+    quality.register_knob("MSAA", "Anti-aliasing weight", (0.0, 1.0), 0.2, 0.1);
+    quality.register_knob("Upscaling", "Upscaling weight", (0.0, 1.0), 0.1, 0.3);
   }
 
   pub fn set_resolution_scale(&mut self, scale: f32) {
-    let width = scale * self.max_width as f32;
-    let height = scale * self.max_height as f32;
+    if scale <= 1.0 {
+      self.resolution_scale = scale;
+      self.update_regions();
+    } else {
+      println!("Can't set resolution scale {}: too high", scale);
+    }
+  }
+
+  pub fn set_upscaling_scale(&mut self, scale: f32) {
+    self.upscaling = scale >= 0.5;
+  }
 
-    if width as u32 <= self.max_width && height as u32 <= self.max_height {
-      self.set_resolution(width, height);
+  // upscaling only kicks in when rendering below the full resolution
+  pub fn is_upscaling(&self) -> bool {
+    self.upscaling && (self.viewport.width < self.max_width || self.viewport.height < self.max_height)
+  }
+
+  // offsets the projection by a sub-pixel amount of the given viewport for the upscaler
+  pub fn get_jitter_matrix(&self, viewport: &Rect) -> Matrix4<f32> {
+    if self.is_upscaling() {
+      let jitter = self.upscaler.get_jitter();
+      Matrix4::from_translation(Vector3::new(
+          2.0 * jitter[0] / viewport.width as f32,
+          2.0 * jitter[1] / viewport.height as f32,
+          0.0))
     } else {
-      println!("Can't set resolution {}x{}: too high", width, height);
+      Matrix4::identity()
     }
   }
 
-  fn set_resolution(&mut self, width: f32, height: f32) {
-    let bounded_width = f32::max(width, 320.0);
-    let bounded_height = f32::max(height, 240.0);
-    let fraction_width = bounded_width / self.max_width as f32;
-    let fraction_height = bounded_height / self.max_height as f32;
+  pub fn get_eye_scales(&self) -> [f32; 2] {
+    self.eye_scales
+  }
 
-    let fraction_half_width = fraction_width * 0.5;
-    let bounded_half_width = (bounded_width * 0.5) as u32;
+  // scales each eye relative to the resolution scale, e.g. to render the non-dominant eye at a
+  // lower resolution
+  pub fn set_eye_scales(&mut self, eye_scales: [f32; 2]) {
+    self.eye_scales = [
+      f32::max(0.0, f32::min(1.0, eye_scales[0])),
+      f32::max(0.0, f32::min(1.0, eye_scales[1])),
+    ];
+    self.update_regions();
+  }
+
+  pub fn get_foveation(&self) -> Option<Foveation> {
+    self.foveation
+  }
 
-    self.layer.left_bounds = [
-        0.0,
-        1.0 - fraction_height,
-        fraction_half_width,
-        fraction_height];
+  // renders the centre of each eye at full density and the periphery at a lower scale, after
+  // which `composite` puts them together
+  pub fn set_foveation(&mut self, foveation: Option<Foveation>) {
+    self.foveation = foveation.map(|f| Foveation {
+      center_size: f32::max(0.05, f32::min(1.0, f.center_size)),
+      periphery_scale: f32::max(0.05, f32::min(1.0, f.periphery_scale)),
+    });
+    self.update_regions();
+  }
+
+  // the number of pixels that are rendered for both eyes in stereo mode
+  pub fn get_rendered_pixels(&self) -> u32 {
+    self.regions.iter().map(|r| r.viewport.width * r.viewport.height).sum()
+  }
 
-    self.layer.right_bounds = [
-        fraction_half_width,
-        1.0 - fraction_height,
-        fraction_half_width,
-        fraction_height];
+  fn update_regions(&mut self) {
+    let max_half_width = self.max_width / 2;
+    let mut eye_sizes = [(0, 0); 2];
+
+    for eye in 0..2 {
+      let scale = self.resolution_scale * self.eye_scales[eye];
+      let width = f32::min(max_half_width as f32, f32::max(160.0, scale * max_half_width as f32));
+      let height = f32::min(self.max_height as f32, f32::max(240.0, scale * self.max_height as f32));
+      eye_sizes[eye] = (width as u32, height as u32);
+    }
+
+    self.viewports = [
+      Rect { left: 0, bottom: 0, width: eye_sizes[0].0, height: eye_sizes[0].1 },
+      Rect { left: eye_sizes[0].0, bottom: 0, width: eye_sizes[1].0, height: eye_sizes[1].1 },
+    ];
+
+    self.viewport = Rect {
+      left: 0,
+      bottom: 0,
+      width: eye_sizes[0].0 + eye_sizes[1].0,
+      height: u32::max(eye_sizes[0].1, eye_sizes[1].1),
+    };
+
+    self.regions = match self.foveation {
+      None => (0..2).map(|eye| CanvasRegion {
+        eye: eye,
+        viewport: self.viewports[eye],
+        window: [0.0, 0.0, 1.0, 1.0],
+        output: self.viewports[eye],
+      }).collect(),
+      Some(foveation) => {
+        let center = foveation.center_size;
+        let periphery = foveation.periphery_scale;
+        let offset = (1.0 - center) / 2.0;
+
+        // all regions are laid out next to each other, so shrink them if they don't fit
+        let needed_width = (center + periphery) * self.viewport.width as f32;
+        let shrink = f32::min(1.0, self.max_width as f32 / needed_width);
+
+        let mut regions = Vec::new();
+        let mut left = 0;
+
+        for eye in 0..2 {
+          let output = self.viewports[eye];
+          let width = (output.width as f32 * periphery * shrink) as u32;
+          let height = (output.height as f32 * periphery * shrink) as u32;
+
+          regions.push(CanvasRegion {
+            eye: eye,
+            viewport: Rect { left: left, bottom: 0, width: width, height: height },
+            window: [0.0, 0.0, 1.0, 1.0],
+            output: output,
+          });
+
+          left += width;
+        }
+
+        for eye in 0..2 {
+          let output = self.viewports[eye];
+          let width = (output.width as f32 * center * shrink) as u32;
+          let height = (output.height as f32 * center * shrink) as u32;
+
+          regions.push(CanvasRegion {
+            eye: eye,
+            viewport: Rect { left: left, bottom: 0, width: width, height: height },
+            window: [offset, offset, center, center],
+            output: Rect {
+              left: output.left + (output.width as f32 * offset) as u32,
+              bottom: output.bottom + (output.height as f32 * offset) as u32,
+              width: (output.width as f32 * center) as u32,
+              height: (output.height as f32 * center) as u32,
+            },
+          });
+
+          left += width;
+        }
+
+        regions
+      },
+    };
+
+    let fraction_width = self.viewport.width as f32 / self.max_width as f32;
+    let fraction_height = self.viewport.height as f32 / self.max_height as f32;
+
+    self.reset_layer();
 
     self.rectangle.texcoords.write(&[
         Texcoord { texcoord: (0.0, 0.0) },
         Texcoord { texcoord: (0.0, fraction_height) },
         Texcoord { texcoord: (fraction_width, fraction_height) },
         Texcoord { texcoord: (fraction_width, 0.0) }]);
+  }
+
+  fn reset_layer(&mut self) {
+    self.layer.texture_id = self.color_buffer.get_id();
+    self.layer.left_bounds = layer_bounds(&self.viewports[0], self.max_width, self.max_height);
+    self.layer.right_bounds = layer_bounds(&self.viewports[1], self.max_width, self.max_height);
+  }
 
-    self.viewports[0].width = bounded_half_width as u32;
-    self.viewports[0].height = bounded_height as u32;
+  // the part of the render buffers that is covered by the mono viewport or any of the regions
+  fn get_render_rect(&self) -> Rect {
+    let mut width = self.viewport.width;
+    let mut height = self.viewport.height;
 
-    self.viewports[1].left = bounded_half_width as u32;
-    self.viewports[1].width = bounded_half_width as u32;
-    self.viewports[1].height = bounded_height as u32;
+    for region in &self.regions {
+      width = u32::max(width, region.viewport.left + region.viewport.width);
+      height = u32::max(height, region.viewport.bottom + region.viewport.height);
+    }
 
-    self.viewport.width = bounded_width as u32;
-    self.viewport.height = bounded_height as u32;
+    Rect { left: 0, bottom: 0, width: width, height: height }
   }
 
-  pub fn set_msaa_scale(&mut self, scale: f32) {
-    let level = (scale * (self.max_msaa_level as f32)) as usize;
+  // the anti-aliasing levels are: none, FXAA and then each MSAA level
+  pub fn set_aa_scale(&mut self, scale: f32) {
+    let level = (scale * ((self.max_msaa_level + 1) as f32)) as usize;
 
-    if level <= self.max_msaa_level {
-      self.set_msaa_level(level);
+    if level <= self.max_msaa_level + 1 {
+      self.fxaa_enabled = level == 1;
+      self.set_msaa_level(level.saturating_sub(1));
     } else {
-      println!("Can't set MSAA level {}: {} is maximum", level, self.max_msaa_level);
+      println!("Can't set anti-aliasing level {}: {} is maximum", level, self.max_msaa_level + 1);
     }
   }
 
+  pub fn is_fxaa_enabled(&self) -> bool {
+    self.fxaa_enabled
+  }
+
+  pub fn get_msaa_level(&self) -> usize {
+    self.current_msaa_level
+  }
+
   fn set_msaa_level(&mut self, msaa_level: usize) {
     if msaa_level < self.color_buffers_msaa.len() + 1 {
       if self.current_msaa_level != msaa_level {
@@ -195,7 +389,10 @@ impl<'a> AdaptiveCanvas {
     }
   }
 
-  pub fn resolve(&self, display: &Facade) {
+  pub fn resolve(&mut self, display: &Facade) {
+    self.output = Output::Resolved;
+    self.reset_layer();
+
     // if we're doing MSAA, resolve it to non-MSAA
     if self.current_msaa_level > 0 {
       let framebuffer = SimpleFrameBuffer::with_depth_buffer(
@@ -210,12 +407,7 @@ impl<'a> AdaptiveCanvas {
           display,
           msaa_color_attachment,
           msaa_depth_attachment).unwrap();
-      let rect = Rect {
-        left: 0,
-        bottom: 0,
-        width: self.viewport.width,
-        height: self.viewport.height,
-      };
+      let rect = self.get_render_rect();
       let blit_target = BlitTarget {
         left: 0,
         bottom: 0,
@@ -234,7 +426,152 @@ impl<'a> AdaptiveCanvas {
         self.depth_buffer.to_depth_attachment())
   }
 
+  // applies screen-space anti-aliasing to the resolved regions of either both eyes or the mono
+  // viewport
+  pub fn post_process(&mut self, display: &Facade, stereo: bool) {
+    if !self.fxaa_enabled { return; }
+
+    let rects: Vec<Rect> = if stereo {
+      self.regions.iter().map(|r| r.viewport).collect()
+    } else {
+      vec![self.viewport]
+    };
+
+    self.fxaa.apply(display, &self.color_buffer, &self.post_buffer, &rects);
+
+    self.output = Output::PostProcessed;
+    self.layer.texture_id = self.post_buffer.get_id();
+  }
+
+  // scales the regions of the (post-processed) resolved buffer up to their place in the output
+  pub fn composite(&mut self, display: &Facade) {
+    if self.foveation.is_none() { return; }
+
+    {
+      let source_framebuffer = SimpleFrameBuffer::new(display, self.get_output_texture())
+          .unwrap();
+      let composite_framebuffer = SimpleFrameBuffer::new(display, &self.composite_buffer).unwrap();
+
+      for region in &self.regions {
+        let blit_target = BlitTarget {
+          left: region.output.left,
+          bottom: region.output.bottom,
+          width: region.output.width as i32,
+          height: region.output.height as i32,
+        };
+
+        source_framebuffer.blit_color(&region.viewport, &composite_framebuffer, &blit_target,
+            MagnifySamplerFilter::Linear);
+      }
+    }
+
+    self.output = Output::Composited;
+    self.layer.texture_id = self.composite_buffer.get_id();
+  }
+
+  // reconstructs the output at full resolution, for either both eyes or the mono viewport
+  pub fn upscale(&mut self, display: &Facade, stereo: bool) {
+    if !self.is_upscaling() {
+      self.upscaler.invalidate();
+      return;
+    }
+
+    let full_viewports = self.get_full_viewports();
+    let rects = if stereo {
+      vec![(self.viewports[0], full_viewports[0]), (self.viewports[1], full_viewports[1])]
+    } else {
+      vec![(self.viewport, self.get_full_viewport())]
+    };
+
+    {
+      let source = match self.output {
+        Output::PostProcessed => &self.post_buffer,
+        Output::Composited => &self.composite_buffer,
+        _ => &self.color_buffer,
+      };
+
+      self.upscaler.upscale(display, source, &rects);
+
+      let history_framebuffer = SimpleFrameBuffer::new(display, self.upscaler.get_output())
+          .unwrap();
+      let upscaled_framebuffer = SimpleFrameBuffer::new(display, &self.upscaled_buffer).unwrap();
+
+      for &(_, target_rect) in &rects {
+        let blit_target = BlitTarget {
+          left: target_rect.left,
+          bottom: target_rect.bottom,
+          width: target_rect.width as i32,
+          height: target_rect.height as i32,
+        };
+
+        history_framebuffer.blit_color(&target_rect, &upscaled_framebuffer, &blit_target,
+            MagnifySamplerFilter::Nearest);
+      }
+    }
+
+    self.output = Output::Upscaled;
+    self.layer.texture_id = self.upscaled_buffer.get_id();
+    self.layer.left_bounds = layer_bounds(&full_viewports[0], self.max_width, self.max_height);
+    self.layer.right_bounds = layer_bounds(&full_viewports[1], self.max_width, self.max_height);
+  }
+
+  fn get_full_viewports(&self) -> [Rect; 2] {
+    let max_half_width = self.max_width / 2;
+
+    [
+      Rect { left: 0, bottom: 0, width: max_half_width, height: self.max_height },
+      Rect { left: max_half_width, bottom: 0, width: max_half_width, height: self.max_height },
+    ]
+  }
+
+  fn get_full_viewport(&self) -> Rect {
+    Rect { left: 0, bottom: 0, width: self.max_width, height: self.max_height }
+  }
+
+  // where the eyes are in the output framebuffer
+  pub fn get_output_viewports(&self, stereo: bool) -> Vec<Rect> {
+    match (self.output == Output::Upscaled, stereo) {
+      (true, true) => self.get_full_viewports().to_vec(),
+      (true, false) => vec![self.get_full_viewport()],
+      (false, true) => self.viewports.to_vec(),
+      (false, false) => vec![self.viewport],
+    }
+  }
+
+  // the part of the output framebuffer that holds the image
+  pub fn get_output_viewport(&self) -> Rect {
+    if self.output == Output::Upscaled {
+      self.get_full_viewport()
+    } else {
+      self.viewport
+    }
+  }
+
+  // the final image: upscaled, composited, post-processed or just resolved
+  pub fn get_output_framebuffer(&self, display: &Facade)
+      -> Result<SimpleFrameBuffer, ValidationError> {
+    SimpleFrameBuffer::new(display, self.get_output_texture())
+  }
+
+  fn get_output_texture(&self) -> &Texture2d {
+    match self.output {
+      Output::Resolved => &self.color_buffer,
+      Output::PostProcessed => &self.post_buffer,
+      Output::Composited => &self.composite_buffer,
+      Output::Upscaled => &self.upscaled_buffer,
+    }
+  }
+
   pub fn get_resolved_layer(&self) -> &VRLayer {
     &self.layer
   }
 }
+
+fn layer_bounds(rect: &Rect, max_width: u32, max_height: u32) -> [f32; 4] {
+  [
+    rect.left as f32 / max_width as f32,
+    1.0 - (rect.bottom + rect.height) as f32 / max_height as f32,
+    rect.width as f32 / max_width as f32,
+    rect.height as f32 / max_height as f32,
+  ]
+}
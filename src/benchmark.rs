@@ -18,7 +18,9 @@
 
 use cgmath::Matrix4;
 use cgmath::SquareMatrix;
+use csv;
 use csv::Reader;
+use csv::StringRecord;
 use glium::backend::Facade;
 use glium::DrawParameters;
 use glium::framebuffer::SimpleFrameBuffer;
@@ -26,6 +28,7 @@ use glium::index::NoIndices;
 use glium::index::PrimitiveType;
 use glium::PolygonMode;
 use glium::Program;
+use glium::ProgramCreationError;
 use glium::Surface;
 use glium::vertex::BufferCreationError;
 use glium::VertexBuffer;
@@ -35,9 +38,10 @@ use std::hash::Hasher;
 use std::mem;
 use std::path::Path;
 
+use error::EngynError;
 use geometry::Vertex;
 use gui::Action;
-use light::Light;
+use light::Lighting;
 use math;
 use drawable::Drawable;
 use object::Object;
@@ -114,26 +118,29 @@ struct Key2d(HashableF32, HashableF32);
 struct Key3d(HashableF32, HashableF32, HashableF32);
 
 impl Benchmark {
-  pub fn from_file<F>(context: &F, path: &Path) -> Benchmark
+  pub fn from_file<F>(context: &F, path: &Path) -> Result<Benchmark, EngynError>
       where F: Facade {
-    let (vertices_1d, colors_1d) = Benchmark::construct_1d_data(path);
-    let (vertices_2d, colors_2d) = Benchmark::construct_2d_data(path);
-    let (vertices_3d, colors_3d) = Benchmark::construct_3d_data(path);
+    let samples = read_samples(path, 3)?;
+    let (vertices_1d, colors_1d) = Benchmark::construct_1d_data(&samples);
+    let (vertices_2d, colors_2d) = Benchmark::construct_2d_data(&samples);
+    let (vertices_3d, colors_3d) = Benchmark::construct_3d_data(&samples);
 
-    Benchmark {
+    Ok(Benchmark {
       entries: Vec::new(),
       num_samples_per_weight: 0,
       grid: Benchmark::construct_grid(context).unwrap(),
-      grid_program: construct_grid_program(context),
+      grid_program: construct_grid_program(context)
+          .map_err(|e| EngynError::shader("benchmark grid program", e))?,
       points_1d: VertexBuffer::new(context, &vertices_1d).unwrap(),
       colors_1d: VertexBuffer::new(context, &colors_1d).unwrap(),
       points_2d: VertexBuffer::new(context, &vertices_2d).unwrap(),
       colors_2d: VertexBuffer::new(context, &colors_2d).unwrap(),
       points_3d: VertexBuffer::new(context, &vertices_3d).unwrap(),
       colors_3d: VertexBuffer::new(context, &colors_3d).unwrap(),
-      program: construct_program(context),
+      program: construct_program(context)
+          .map_err(|e| EngynError::shader("benchmark program", e))?,
       mode: VisualizeMode::OneD,
-    }
+    })
   }
 
   pub fn as_object(self) -> Object {
@@ -142,31 +149,24 @@ impl Benchmark {
       drawable: Some(Box::new(self)),
       transform: Matrix4::identity(),
       size: 0.0,
+      bounds: None,
     }
   }
 
-  fn construct_1d_data(path: &Path) -> (Vec<Vertex>, Vec<Color>) {
+  fn construct_1d_data(samples: &[Sample]) -> (Vec<Vertex>, Vec<Color>) {
     let scale_factor = 1.0 / 64_000_000f32;
 
     let mut data = HashMap::new();
     let mut vertices = Vec::new();
     let mut colors = Vec::new();
 
-    let mut reader = Reader::from_path(path).unwrap();
-
-    for result in reader.records() {
-      match result {
-        Ok(record) => {
-          let level = record[7].parse::<f32>().unwrap();
-          let time = record[4].parse::<f32>().unwrap();
+    for sample in samples {
+      let (level, time) = (sample.levels[0], sample.time);
 
-          let d = data.entry(HashableF32(level)).or_insert((0.0, 0));
+      let d = data.entry(HashableF32(level)).or_insert((0.0, 0));
 
-          // calculate mean time for each level
-          *d = (d.0 + time, d.1 + 1)
-        },
-        Err(e) => println!("Could not parse line from benchmark: {}", e),
-      }
+      // calculate mean time for each level
+      *d = (d.0 + time, d.1 + 1)
     }
 
     for (&HashableF32(level), &(time_sum, time_count)) in &data {
@@ -184,32 +184,23 @@ impl Benchmark {
     (vertices, colors)
   }
 
-  fn construct_2d_data(path: &Path) -> (Vec<Vertex>, Vec<Color>) {
+  fn construct_2d_data(samples: &[Sample]) -> (Vec<Vertex>, Vec<Color>) {
     let scale_factor = 1.0 / 64_000_000f32;
 
     let mut data = HashMap::new();
     let mut vertices = Vec::new();
     let mut colors = Vec::new();
 
-    let mut reader = Reader::from_path(path).unwrap();
+    for sample in samples {
+      let (level0, level1, time) = (sample.levels[0], sample.levels[1], sample.time);
 
-    for result in reader.records() {
-      match result {
-        Ok(record) => {
-          let level0 = record[7].parse::<f32>().unwrap();
-          let level1 = record[8].parse::<f32>().unwrap();
-          let time = record[4].parse::<f32>().unwrap();
+      let d = data.entry(Key2d(
+        HashableF32(level0),
+        HashableF32(level1),
+      )).or_insert((0.0, 0));
 
-          let d = data.entry(Key2d(
-            HashableF32(level0),
-            HashableF32(level1),
-          )).or_insert((0.0, 0));
-
-          // calculate mean time for each level
-          *d = (d.0 + time, d.1 + 1)
-        },
-        Err(e) => println!("Could not parse line from benchmark: {}", e),
-      }
+      // calculate mean time for each level
+      *d = (d.0 + time, d.1 + 1)
     }
 
     for (&Key2d(HashableF32(level0), HashableF32(level1)), &(time_sum, time_count)) in &data {
@@ -227,34 +218,25 @@ impl Benchmark {
     (vertices, colors)
   }
 
-  fn construct_3d_data(path: &Path) -> (Vec<Vertex>, Vec<Color>) {
+  fn construct_3d_data(samples: &[Sample]) -> (Vec<Vertex>, Vec<Color>) {
     let scale_factor = 1.0 / 64_000_000f32;
 
     let mut data = HashMap::new();
     let mut vertices = Vec::new();
     let mut colors = Vec::new();
 
-    let mut reader = Reader::from_path(path).unwrap();
-
-    for result in reader.records() {
-      match result {
-        Ok(record) => {
-          let level0 = record[7].parse::<f32>().unwrap();
-          let level1 = record[8].parse::<f32>().unwrap();
-          let level2 = record[9].parse::<f32>().unwrap();
-          let time = record[4].parse::<f32>().unwrap();
-
-          let d = data.entry(Key3d(
-            HashableF32(level0),
-            HashableF32(level1),
-            HashableF32(level2),
-          )).or_insert((0.0, 0));
-
-          // calculate mean time for each level
-          *d = (d.0 + time, d.1 + 1)
-        },
-        Err(e) => println!("Could not parse line from benchmark: {}", e),
-      }
+    for sample in samples {
+      let (level0, level1, level2, time) =
+          (sample.levels[0], sample.levels[1], sample.levels[2], sample.time);
+
+      let d = data.entry(Key3d(
+        HashableF32(level0),
+        HashableF32(level1),
+        HashableF32(level2),
+      )).or_insert((0.0, 0));
+
+      // calculate mean time for each level
+      *d = (d.0 + time, d.1 + 1)
     }
 
     for (&Key3d(HashableF32(level0), HashableF32(level1), HashableF32(level2)), &(time_sum, time_count)) in &data {
@@ -298,8 +280,8 @@ impl Benchmark {
 
 impl Drawable for Benchmark {
   fn draw(&mut self, target: &mut SimpleFrameBuffer, _: &Facade, projection: [[f32; 4]; 4],
-      view: [[f32; 4]; 4], model_transform: Matrix4<f32>, render_params: &DrawParameters, _: i32,
-      _: &[Light; 32], _: usize, _: bool, _: bool) {
+      view: [[f32; 4]; 4], model_transform: Matrix4<f32>, _: f32, render_params: &DrawParameters,
+      _: &Lighting, _: usize, _: bool, _: bool) {
     let uniforms = uniform! {
       projection: projection,
       view: view,
@@ -346,7 +328,80 @@ impl Drawable for Benchmark {
   }
 }
 
-fn construct_grid_program<F>(context: &F) -> Program
+// a frame of a benchmark log: the target levels of the knobs and its frame time in nanoseconds
+struct Sample {
+  levels: Vec<f32>,
+  time: f32,
+}
+
+// Reads the frames of a benchmark log with the levels of its first `num_knobs` knobs. The columns
+// are found by name in the header, so this works for any set of quality knobs.
+fn read_samples(path: &Path, num_knobs: usize) -> Result<Vec<Sample>, EngynError> {
+  let mut reader = Reader::from_path(path).map_err(|e| EngynError::csv(path, e))?;
+  let headers = reader.headers().map_err(|e| EngynError::csv(path, e))?.clone();
+  let find_column = |name: &str| -> Result<usize, EngynError> {
+    headers.iter().position(|h| h == name).ok_or_else(|| EngynError::Csv {
+      path: path.to_path_buf(),
+      line: None,
+      message: format!("missing column {}", name),
+    })
+  };
+
+  let mut columns = vec![find_column("TimeStart")?, find_column("TimeEnd")?];
+  columns.extend(headers.iter().enumerate()
+      .filter(|&(_, h)| h.starts_with("Target") && h != "TargetFrameTime")
+      .map(|(i, _)| i)
+      .take(num_knobs));
+
+  if columns.len() < num_knobs + 2 {
+    return Err(EngynError::Csv {
+      path: path.to_path_buf(),
+      line: None,
+      message: format!("expected the Target columns of {} knobs, found {}", num_knobs,
+          columns.len() - 2),
+    });
+  }
+
+  let mut samples = Vec::new();
+
+  for result in reader.records() {
+    match parse_columns(path, result, &columns) {
+      Ok(values) => samples.push(Sample {
+        levels: values[2..].iter().map(|&level| level as f32).collect(),
+        time: (values[1] - values[0]) as f32,
+      }),
+      Err(e) => eprintln!("Warning: skipping benchmark line: {}", e),
+    }
+  }
+
+  Ok(samples)
+}
+
+// the given columns of a line of a benchmark file, as numbers
+fn parse_columns(path: &Path, result: Result<StringRecord, csv::Error>, columns: &[usize])
+    -> Result<Vec<f64>, EngynError> {
+  let record = result.map_err(|e| EngynError::csv(path, e))?;
+
+  columns.iter().map(|&column| parse_column(path, &record, column)).collect()
+}
+
+fn parse_column(path: &Path, record: &StringRecord, column: usize) -> Result<f64, EngynError> {
+  let message = match record.get(column) {
+    Some(field) => match field.parse::<f64>() {
+      Ok(value) => return Ok(value),
+      Err(e) => format!("column {}: {}", column + 1, e),
+    },
+    None => format!("missing column {}", column + 1),
+  };
+
+  Err(EngynError::Csv {
+    path: path.to_path_buf(),
+    line: record.position().map(|p| p.line()),
+    message: message,
+  })
+}
+
+fn construct_grid_program<F>(context: &F) -> Result<Program, ProgramCreationError>
     where F: Facade {
   Program::from_source(
     context,
@@ -378,10 +433,10 @@ fn construct_grid_program<F>(context: &F) -> Program
         color = vec4(0.0, 0.0, 0.0, 1.0);
       }
     "#,
-    None).unwrap()
+    None)
 }
 
-fn construct_program<F>(context: &F) -> Program
+fn construct_program<F>(context: &F) -> Result<Program, ProgramCreationError>
     where F: Facade {
   Program::from_source(
     context,
@@ -421,5 +476,5 @@ fn construct_program<F>(context: &F) -> Program
         color = vec4(v_color, 1.0); //vec4(color_gamma_corrected, 1.0);
       }
     "#,
-    None).unwrap()
+    None)
 }
@@ -1,76 +1,104 @@
              extern crate csv;
-#[macro_use] extern crate serde_derive;
 
 use csv::Reader;
+use csv::StringRecord;
 use csv::Writer;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::env;
 
-#[derive(Debug, Deserialize, Eq, PartialEq)]
-struct LogEntry {
-    AnalysisTarget: String,
-    Frame: u32,
-    Sample: u32,
-    Dropped: u32,
-    TimeStart: u32,
-    TimeEnd: u32,
-    frame_start: u32,
-    pre_input: u32,
-    post_input: u32,
-    pre_update_camera: u32,
-    post_update_camera: u32,
-    pre_update_world: u32,
-    post_update_world: u32,
-    pre_sync_poses: u32,
-    post_sync_poses: u32,
-    pre_sync_frame_data: u32,
-    post_sync_frame_data: u32,
-    pre_draw: u32,
-    post_draw: u32,
-    frame_end: u32,
-    Level: String,
-    WeightResolution: String,
-    WeightMSAA: String,
-    WeightLOD: String,
-    TargetResolution: String,
-    TargetMSAA: String,
-    TargetLOD: String,
-    TargetFrameTime: u64,
-    PredictedRemainingTime: u64,
-    RatioRemaining: String,
+// Merges the samples of a benchmark log: frames with the same analysis target, controller, frame
+// number and quality weights are combined into one row. The weight columns are taken from the
+// header, so this works for any set of quality knobs. Counters logged after the PredictionError
+// column are averaged as well. Empty cells, e.g. frames without a prediction, are left out of the
+// averages.
+
+fn is_key_column(name: &str) -> bool {
+    name == "AnalysisTarget" || name == "Controller" || name == "Frame" || name.starts_with("Weight")
+}
+
+fn is_averaged_column(name: &str) -> bool {
+    name == "Level" || name == "TargetFrameTime" || name == "PredictedRemainingTime"
+        || name == "PredictionError" || (name.starts_with("Target") && !is_key_column(name))
+}
+
+struct MergedEntry {
+    num_samples: u32,
+    dropped: u32,
+    sums: Vec<f64>,
+    counts: Vec<u32>,
 }
 
 fn main() {
     let filename = env::args().nth(1).expect("Could not parse command line arguments");
-    let mut rdr = Reader::from_path(filename).unwrap();
-    let mut logs = HashMap::new();
-
-    for result in rdr.deserialize() {
-        let r: LogEntry = result.expect("Could not read record");
-        match logs.get_mut((r.AnalysisTarget, r.Frame, r.WeightResolution)) {
-            Some(entry) => {
-                *entry.Dropped += r.Dropped;
-                entry.Level += r.Level;
-                entry.TargetFrameTime += r.TargetFrameTime;
-                entry.PredictedRemainingTime += r.PredictedRemainingTime;
-            },
-            None => logs.insert(
-                (r.AnalysisTarget, r.Frame, r.WeightResolution),
-                r
-            ),
+    let mut rdr = Reader::from_path(&filename).unwrap();
+    let headers = rdr.headers().expect("Could not read header").clone();
+
+    let key_columns: Vec<usize> = (0..headers.len()).filter(|i| is_key_column(&headers[*i])).collect();
+    let first_counter_column = headers.iter()
+        .position(|h| h == "PredictionError")
+        .map_or(headers.len(), |i| i + 1);
+    let averaged_columns: Vec<usize> = (0..headers.len())
+        .filter(|i| is_averaged_column(&headers[*i]) || *i >= first_counter_column)
+        .collect();
+    let dropped_column = headers.iter().position(|h| h == "Dropped").expect("No Dropped column");
+
+    let mut logs: BTreeMap<Vec<String>, MergedEntry> = BTreeMap::new();
+
+    for result in rdr.records() {
+        let r = result.expect("Could not read record");
+        let key = key_columns.iter().map(|i| r[*i].to_owned()).collect();
+        let entry = logs.entry(key).or_insert(MergedEntry {
+            num_samples: 0,
+            dropped: 0,
+            sums: vec![0.0; averaged_columns.len()],
+            counts: vec![0; averaged_columns.len()],
+        });
+
+        entry.num_samples += 1;
+        entry.dropped += r[dropped_column].parse::<u32>().expect("Could not parse Dropped");
+
+        for (j, i) in averaged_columns.iter().enumerate() {
+            if r[*i].is_empty() {
+                continue;
+            }
+
+            entry.sums[j] += r[*i].parse::<f64>().expect(&format!("Could not parse {}", &headers[*i]));
+            entry.counts[j] += 1;
         }
     }
 
-    for (_, value) in logs.iter_mut() {
-        (*value).Level /= 10.0;
-        (*value).RatioRemaining = (*value).TargetFrameTime as f32 / (*value).PredictedRemainingTime as f32;
-        (*value).TargetFrameTime /= 10;
-        (*value).PredictedRemainingTime /= 10;
-    }
+    let mut wtr = Writer::from_path(filename.clone() + ".mergesamples.csv").unwrap();
+
+    let mut output_headers: Vec<&str> = key_columns.iter().map(|i| &headers[*i]).collect();
+    output_headers.push("Samples");
+    output_headers.push("Dropped");
+    output_headers.extend(averaged_columns.iter().map(|i| &headers[*i]));
+    output_headers.push("RatioRemaining");
+    wtr.write_record(&output_headers).expect("Could not write header");
+
+    let target_frame_time = averaged_columns.iter().position(|i| &headers[*i] == "TargetFrameTime");
+    let predicted_remaining_time = averaged_columns.iter()
+        .position(|i| &headers[*i] == "PredictedRemainingTime");
+
+    for (key, value) in logs.iter() {
+        let mut record = StringRecord::from(key.clone());
+        record.push_field(&value.num_samples.to_string());
+        record.push_field(&value.dropped.to_string());
 
-    let mut wtr = Writer::from_path(filename + ".mergesamples.csv").unwrap();
+        let means: Vec<f64> = value.sums.iter().zip(value.counts.iter())
+            .map(|(s, c)| s / *c as f64)
+            .collect();
 
-    for(_, value) in logs.iter() {
-        wtr.serialize(value).expect("Could not serialize record");
+        for (mean, count) in means.iter().zip(value.counts.iter()) {
+            record.push_field(&if *count > 0 { mean.to_string() } else { String::new() });
+        }
+
+        let ratio_remaining = match (target_frame_time, predicted_remaining_time) {
+            (Some(t), Some(p)) => means[p] / means[t],
+            _ => 0.0,
+        };
+        record.push_field(&ratio_remaining.to_string());
+
+        wtr.write_record(&record).expect("Could not serialize record");
     }
- }
+}
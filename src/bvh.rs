@@ -0,0 +1,423 @@
+// Copyright (c) 2018 Remco Kranenburg
+//
+// GNU GENERAL PUBLIC LICENSE
+//    Version 3, 29 June 2007
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use cgmath::Matrix4;
+use std::cmp::Ordering;
+use std::f32;
+use std::mem;
+
+use frustum::Frustum;
+use frustum::Intersection;
+use geometry::BoundingBox;
+use math;
+
+// leaves hold at most this many items
+const MAX_LEAF_SIZE: usize = 4;
+
+// Something to put in the hierarchy, e.g. an object of the world. `path` holds the child indices
+// that lead to it from the root, `bounds` is in world space and None if the item has no known
+// extent, in which case it is always considered visible.
+#[derive(Clone, Debug)]
+pub struct BvhItem {
+  pub path: Vec<usize>,
+  pub bounds: Option<BoundingBox>,
+  pub transform: Matrix4<f32>,
+  pub num_triangles: usize,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum BvhNodeKind {
+  Leaf { first: usize, count: usize },
+  Interior { left: usize, right: usize },
+}
+
+#[derive(Copy, Clone, Debug)]
+struct BvhNode {
+  bounds: BoundingBox,
+  kind: BvhNodeKind,
+}
+
+// A bounding volume hierarchy over items with world space bounds. It doesn't depend on any GL
+// state, so it can be built and queried on its own.
+pub struct Bvh {
+  items: Vec<BvhItem>,
+  nodes: Vec<BvhNode>,
+  order: Vec<usize>,
+  unbounded: Vec<usize>,
+  // items were updated since the last refit, and whether one of them gained or lost its bounds
+  is_dirty: bool,
+  needs_rebuild: bool,
+}
+
+impl Bvh {
+  pub fn build(items: Vec<BvhItem>) -> Bvh {
+    let mut bvh = Bvh {
+      items: items,
+      nodes: Vec::new(),
+      order: Vec::new(),
+      unbounded: Vec::new(),
+      is_dirty: false,
+      needs_rebuild: false,
+    };
+
+    let mut bounded = Vec::new();
+
+    for (i, item) in bvh.items.iter().enumerate() {
+      if item.bounds.is_some() {
+        bounded.push(i);
+      } else {
+        bvh.unbounded.push(i);
+      }
+    }
+
+    if !bounded.is_empty() {
+      bvh.build_recurse(&mut bounded);
+    }
+
+    bvh
+  }
+
+  pub fn get_items(&self) -> &Vec<BvhItem> {
+    &self.items
+  }
+
+  // Replaces the transform and bounds of an item, e.g. because its object moved. The nodes above
+  // it are updated by the next `refit`.
+  pub fn update_item(&mut self, index: usize, transform: Matrix4<f32>,
+      bounds: Option<BoundingBox>) {
+    let item = &mut self.items[index];
+
+    if item.bounds.is_some() != bounds.is_some() {
+      self.needs_rebuild = true;
+    }
+
+    item.transform = transform;
+    item.bounds = bounds;
+    self.is_dirty = true;
+  }
+
+  // Updates the bounds of the nodes above the items that were updated, if any. An item that
+  // gained or lost its bounds changes the structure, so then the hierarchy is built again. The
+  // tree itself isn't rebalanced, so it gets less efficient when items move far.
+  pub fn refit(&mut self) {
+    if self.needs_rebuild {
+      let items = mem::replace(&mut self.items, Vec::new());
+      *self = Bvh::build(items);
+      return;
+    }
+
+    if !self.is_dirty {
+      return;
+    }
+
+    self.is_dirty = false;
+
+    // children always come after their parent
+    for n in (0..self.nodes.len()).rev() {
+      let bounds = match self.nodes[n].kind {
+        BvhNodeKind::Leaf { first, count } => self.get_bounds(&self.order[first..first + count]),
+        BvhNodeKind::Interior { left, right } => {
+          math::merge_bounding_boxes(&self.nodes[left].bounds, &self.nodes[right].bounds)
+        },
+      };
+
+      self.nodes[n].bounds = bounds;
+    }
+  }
+
+  // the indices of the items that may be visible in the frustum
+  pub fn cull(&self, frustum: &Frustum) -> Vec<usize> {
+    let mut result = self.unbounded.clone();
+
+    if self.nodes.is_empty() {
+      return result;
+    }
+
+    let mut stack = vec![(0, false)];
+
+    while let Some((n, is_inside)) = stack.pop() {
+      let node = &self.nodes[n];
+      let intersection = if is_inside {
+        Intersection::Inside
+      } else {
+        frustum.intersect_box(&node.bounds)
+      };
+
+      if intersection == Intersection::Outside {
+        continue;
+      }
+
+      match node.kind {
+        BvhNodeKind::Leaf { first, count } => {
+          for item in &self.order[first..first + count] {
+            let bounds = self.items[*item].bounds.unwrap();
+
+            if intersection == Intersection::Inside
+                || frustum.intersect_box(&bounds) != Intersection::Outside {
+              result.push(*item);
+            }
+          }
+        },
+        BvhNodeKind::Interior { left, right } => {
+          let is_inside = intersection == Intersection::Inside;
+          stack.push((right, is_inside));
+          stack.push((left, is_inside));
+        },
+      }
+    }
+
+    result
+  }
+
+  // the indices of the items whose bounds are hit by the ray, with the distance along the ray
+  // where it enters them, nearest first
+  pub fn intersect_ray(&self, origin: [f32; 3], direction: [f32; 3]) -> Vec<(usize, f32)> {
+    let mut result = Vec::new();
+
+    if self.nodes.is_empty() {
+      return result;
+    }
+
+    let inverse_direction = [1.0 / direction[0], 1.0 / direction[1], 1.0 / direction[2]];
+    let mut stack = vec![0];
+
+    while let Some(n) = stack.pop() {
+      let node = &self.nodes[n];
+
+      if intersect_ray_box(&origin, &inverse_direction, &node.bounds).is_none() {
+        continue;
+      }
+
+      match node.kind {
+        BvhNodeKind::Leaf { first, count } => {
+          for item in &self.order[first..first + count] {
+            let bounds = self.items[*item].bounds.unwrap();
+
+            if let Some(distance) = intersect_ray_box(&origin, &inverse_direction, &bounds) {
+              result.push((*item, distance));
+            }
+          }
+        },
+        BvhNodeKind::Interior { left, right } => {
+          stack.push(right);
+          stack.push(left);
+        },
+      }
+    }
+
+    result.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal));
+    result
+  }
+
+  fn build_recurse(&mut self, indices: &mut [usize]) -> usize {
+    let node_index = self.nodes.len();
+    let bounds = self.get_bounds(indices);
+
+    self.nodes.push(BvhNode {
+      bounds: bounds,
+      kind: BvhNodeKind::Leaf { first: 0, count: 0 },
+    });
+
+    if indices.len() <= MAX_LEAF_SIZE {
+      self.nodes[node_index].kind = BvhNodeKind::Leaf {
+        first: self.order.len(),
+        count: indices.len(),
+      };
+      self.order.extend_from_slice(indices);
+      return node_index;
+    }
+
+    // split at the median along the axis where the centres are spread out the most
+    let mut centroid_bounds = math::empty_bounding_box();
+    for i in indices.iter() {
+      let c = centroid(&self.items[*i].bounds.unwrap());
+      centroid_bounds = math::merge_bounding_boxes(&centroid_bounds, &(c, c));
+    }
+
+    let extent: Vec<f32> = (0..3).map(|a| centroid_bounds.1[a] - centroid_bounds.0[a]).collect();
+    let axis = if extent[0] >= extent[1] && extent[0] >= extent[2] {
+      0
+    } else if extent[1] >= extent[2] {
+      1
+    } else {
+      2
+    };
+
+    {
+      let items = &self.items;
+      indices.sort_by(|a, b| {
+        let ca = centroid(&items[*a].bounds.unwrap())[axis];
+        let cb = centroid(&items[*b].bounds.unwrap())[axis];
+        ca.partial_cmp(&cb).unwrap_or(Ordering::Equal)
+      });
+    }
+
+    let middle = indices.len() / 2;
+    let (left_indices, right_indices) = indices.split_at_mut(middle);
+    let left = self.build_recurse(left_indices);
+    let right = self.build_recurse(right_indices);
+
+    self.nodes[node_index].kind = BvhNodeKind::Interior { left: left, right: right };
+    node_index
+  }
+
+  fn get_bounds(&self, indices: &[usize]) -> BoundingBox {
+    indices.iter().fold(math::empty_bounding_box(), |bounds, i| {
+      math::merge_bounding_boxes(&bounds, &self.items[*i].bounds.unwrap())
+    })
+  }
+}
+
+fn centroid(bounds: &BoundingBox) -> [f32; 3] {
+  [
+    (bounds.0[0] + bounds.1[0]) * 0.5,
+    (bounds.0[1] + bounds.1[1]) * 0.5,
+    (bounds.0[2] + bounds.1[2]) * 0.5,
+  ]
+}
+
+// the distance along the ray where it enters the box, if it hits it at all
+pub fn intersect_ray_box(origin: &[f32; 3], inverse_direction: &[f32; 3], bounds: &BoundingBox)
+    -> Option<f32> {
+  let mut t_min = 0.0f32;
+  let mut t_max = f32::INFINITY;
+
+  for i in 0..3 {
+    let t1 = (bounds.0[i] - origin[i]) * inverse_direction[i];
+    let t2 = (bounds.1[i] - origin[i]) * inverse_direction[i];
+    t_min = t_min.max(t1.min(t2));
+    t_max = t_max.min(t1.max(t2));
+  }
+
+  if t_min <= t_max { Some(t_min) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+  use cgmath::Matrix4;
+  use cgmath::SquareMatrix;
+  use cgmath::Vector3;
+
+  use frustum::Frustum;
+  use super::*;
+
+  fn unit_box(x: f32) -> BvhItem {
+    BvhItem {
+      path: vec![0],
+      bounds: Some(([x - 0.5, -0.5, -0.5], [x + 0.5, 0.5, 0.5])),
+      transform: Matrix4::from_translation(Vector3::new(x, 0.0, 0.0)),
+      num_triangles: 12,
+    }
+  }
+
+  // boxes along the x axis, three units apart, and one item without bounds at the end
+  fn row_of_boxes() -> Bvh {
+    let mut items: Vec<BvhItem> = (0..11).map(|i| unit_box(i as f32 * 3.0)).collect();
+    items.push(BvhItem { bounds: None, .. unit_box(0.0) });
+    Bvh::build(items)
+  }
+
+  // the cube from -1 to 1 around the given centre
+  fn cube_frustum(x: f32) -> Frustum {
+    Frustum::from_matrix(&Matrix4::from_translation(Vector3::new(-x, 0.0, 0.0)))
+  }
+
+  fn cull_sorted(bvh: &Bvh, frustum: &Frustum) -> Vec<usize> {
+    let mut result = bvh.cull(frustum);
+    result.sort();
+    result
+  }
+
+  #[test]
+  fn build_keeps_all_items() {
+    let bvh = row_of_boxes();
+    let everything = Frustum::from_matrix(&Matrix4::from_scale(0.01));
+
+    assert_eq!(bvh.get_items().len(), 12);
+    assert_eq!(cull_sorted(&bvh, &everything), (0..12).collect::<Vec<usize>>());
+  }
+
+  #[test]
+  fn build_without_bounded_items() {
+    let bvh = Bvh::build(vec![BvhItem { bounds: None, .. unit_box(0.0) }]);
+
+    assert_eq!(bvh.cull(&cube_frustum(0.0)), vec![0]);
+    assert!(bvh.intersect_ray([-10.0, 0.0, 0.0], [1.0, 0.0, 0.0]).is_empty());
+  }
+
+  #[test]
+  fn cull_keeps_items_in_frustum_and_unbounded_items() {
+    let bvh = row_of_boxes();
+
+    assert_eq!(cull_sorted(&bvh, &cube_frustum(0.0)), vec![0, 11]);
+    assert_eq!(cull_sorted(&bvh, &cube_frustum(1.5)), vec![0, 1, 11]);
+    assert_eq!(cull_sorted(&bvh, &cube_frustum(100.0)), vec![11]);
+  }
+
+  #[test]
+  fn refit_moves_items() {
+    let mut bvh = row_of_boxes();
+    let moved = unit_box(30.0);
+
+    bvh.update_item(0, moved.transform, moved.bounds);
+    bvh.refit();
+
+    assert_eq!(cull_sorted(&bvh, &cube_frustum(0.0)), vec![11]);
+    assert_eq!(cull_sorted(&bvh, &cube_frustum(30.0)), vec![0, 10, 11]);
+    assert_eq!(bvh.get_items()[0].transform, moved.transform);
+  }
+
+  #[test]
+  fn refit_rebuilds_when_bounds_appear_or_disappear() {
+    let mut bvh = row_of_boxes();
+
+    bvh.update_item(5, Matrix4::identity(), None);
+    bvh.update_item(11, Matrix4::identity(), unit_box(60.0).bounds);
+    bvh.refit();
+
+    assert_eq!(cull_sorted(&bvh, &cube_frustum(0.0)), vec![0, 5]);
+    assert_eq!(cull_sorted(&bvh, &cube_frustum(60.0)), vec![5, 11]);
+  }
+
+  #[test]
+  fn intersect_ray_returns_nearest_first() {
+    let bvh = row_of_boxes();
+    let hits = bvh.intersect_ray([-10.0, 0.1, 0.1], [1.0, 0.0, 0.0]);
+
+    assert_eq!(hits.iter().map(|h| h.0).collect::<Vec<usize>>(), (0..11).collect::<Vec<usize>>());
+    assert!((hits[0].1 - 9.5).abs() < 1e-5);
+    assert!(hits.windows(2).all(|w| w[0].1 <= w[1].1));
+  }
+
+  #[test]
+  fn intersect_ray_misses() {
+    let bvh = row_of_boxes();
+
+    assert!(bvh.intersect_ray([-10.0, 2.0, 0.1], [1.0, 0.0, 0.0]).is_empty());
+    assert!(bvh.intersect_ray([-10.0, 0.1, 0.1], [-1.0, 0.0, 0.0]).is_empty());
+  }
+
+  #[test]
+  fn nan_bounds_dont_panic() {
+    let mut items: Vec<BvhItem> = (0..8).map(|i| unit_box(i as f32)).collect();
+    items[3].bounds = Some(([f32::NAN; 3], [f32::NAN; 3]));
+
+    let bvh = Bvh::build(items);
+    bvh.intersect_ray([-10.0, 0.1, 0.1], [1.0, 0.0, 0.0]);
+  }
+}
@@ -68,6 +68,12 @@ impl FpsCamera {
     m.invert().unwrap()
   }
 
+  // the pose of the camera in the world, as of the last call to `get_view`
+  pub fn get_transform(&self) -> Matrix4<f32> {
+    Matrix4::from_translation(self.position) * Matrix4::from_angle_y(self.yaw)
+        * Matrix4::from_angle_x(self.pitch)
+  }
+
   pub fn process_actions(&mut self, actions: &Vec<Action>) {
     for action in actions {
       match *action {
@@ -0,0 +1,399 @@
+// Copyright (c) 2018 Remco Kranenburg
+//
+// GNU GENERAL PUBLIC LICENSE
+//    Version 3, 29 June 2007
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use cgmath::Matrix4;
+use cgmath::Vector3;
+use cgmath::Vector4;
+use glium::Rect;
+use glium::backend::Facade;
+use glium::texture::MipmapsOption;
+use glium::texture::UncompressedUintFormat;
+use glium::texture::UnsignedTexture2d;
+use std::f32;
+use std::path::Path;
+
+use error::EngynError;
+use light::Light;
+use light::LightKind;
+use math;
+
+pub const CLUSTERS_X: usize = 16;
+pub const CLUSTERS_Y: usize = 8;
+pub const CLUSTERS_Z: usize = 16;
+pub const CLUSTER_INDICES_WIDTH: usize = 1024;
+const NUM_CLUSTERS: usize = CLUSTERS_X * CLUSTERS_Y * CLUSTERS_Z;
+const MAX_CLUSTER_INDICES: usize = CLUSTER_INDICES_WIDTH * 64;
+const MAX_FAR: f32 = 1000.0;
+
+// Assigns the lights to the clusters of a view, so that each fragment only shades the lights that
+// can reach it. The clusters divide the screen into tiles, and the depth into slices that grow
+// exponentially with the distance. Lights without a range reach every fragment, so instead of
+// being repeated in every cluster they are in one global list that is always shaded.
+pub struct Clusters {
+  // from world space to clip space, of the view that the clusters were made for
+  pub matrix: [[f32; 4]; 4],
+  // the near and far planes of that view, between which the slices are spread
+  pub depth_range: [f32; 2],
+  pub num_indices: u32,
+  // the global lights, whose indices come before those of the clusters
+  pub num_global_lights: u32,
+
+  // the offset and number of indices of each cluster, a row per slice
+  grid: UnsignedTexture2d,
+  // the indices of the global lights and then those of all clusters, one after the other
+  indices: UnsignedTexture2d,
+  cluster_lights: Vec<Vec<u32>>,
+  is_overflow_reported: bool,
+}
+
+impl Clusters {
+  pub fn new(display: &Facade) -> Result<Clusters, EngynError> {
+    let grid = UnsignedTexture2d::with_format(display,
+        vec![vec![(0u32, 0u32); CLUSTERS_X * CLUSTERS_Y]; CLUSTERS_Z],
+        UncompressedUintFormat::U32U32, MipmapsOption::NoMipmap)
+        .map_err(|e| EngynError::texture(&Path::new("cluster_grid"), &e.to_string()))?;
+    let indices = UnsignedTexture2d::with_format(display,
+        vec![vec![0u32; CLUSTER_INDICES_WIDTH]; MAX_CLUSTER_INDICES / CLUSTER_INDICES_WIDTH],
+        UncompressedUintFormat::U32, MipmapsOption::NoMipmap)
+        .map_err(|e| EngynError::texture(&Path::new("cluster_indices"), &e.to_string()))?;
+
+    Ok(Clusters {
+      matrix: [[0.0; 4]; 4],
+      depth_range: [0.01, MAX_FAR],
+      num_indices: 0,
+      num_global_lights: 0,
+      grid: grid,
+      indices: indices,
+      cluster_lights: vec![Vec::new(); NUM_CLUSTERS],
+      is_overflow_reported: false,
+    })
+  }
+
+  pub fn get_grid(&self) -> &UnsignedTexture2d {
+    &self.grid
+  }
+
+  pub fn get_indices(&self) -> &UnsignedTexture2d {
+    &self.indices
+  }
+
+  // finds the clusters that each of the given lights reaches, for a perspective projection
+  pub fn assign(&mut self, projection: Matrix4<f32>, view: Matrix4<f32>, lights: &[Light],
+      considered: &[usize]) {
+    let (near, far) = get_depth_range(&projection);
+    let lists = get_light_lists(&projection, &view, near, far, lights, considered,
+        &mut self.cluster_lights);
+
+    if lists.num_left_out > 0 && !self.is_overflow_reported {
+      eprintln!("Warning: {} light indices don't fit in the clusters, so the lights at the back \
+          are left out", lists.num_left_out);
+      self.is_overflow_reported = true;
+    }
+
+    let grid = lists.grid;
+    let indices = lists.indices;
+
+    self.num_indices = indices.len() as u32;
+    self.num_global_lights = lists.num_global_lights;
+    self.matrix = math::matrix_to_uniform(projection * view);
+    self.depth_range = [near, far];
+
+    self.grid.write(Rect {
+      left: 0,
+      bottom: 0,
+      width: (CLUSTERS_X * CLUSTERS_Y) as u32,
+      height: CLUSTERS_Z as u32,
+    }, grid);
+
+    if indices.is_empty() { return; }
+
+    // only the rows that are used are uploaded
+    let rows: Vec<Vec<u32>> = indices.chunks(CLUSTER_INDICES_WIDTH).map(|row| {
+      let mut row = row.to_vec();
+      row.resize(CLUSTER_INDICES_WIDTH, 0);
+      row
+    }).collect();
+
+    self.indices.write(Rect {
+      left: 0,
+      bottom: 0,
+      width: CLUSTER_INDICES_WIDTH as u32,
+      height: rows.len() as u32,
+    }, rows);
+  }
+}
+
+// the lights of a view, ready to be uploaded to the grid and index textures
+struct LightLists {
+  grid: Vec<Vec<(u32, u32)>>,
+  indices: Vec<u32>,
+  num_global_lights: u32,
+  // the indices that didn't fit anymore
+  num_left_out: usize,
+}
+
+fn is_global(light: &Light) -> bool {
+  light.kind == LightKind::Directional || !(light.range > 0.0)
+}
+
+// puts the global lights at the front of the indices, followed by the lights of each cluster;
+// `cluster_lights` is only passed in so that its lists can be reused
+fn get_light_lists(projection: &Matrix4<f32>, view: &Matrix4<f32>, near: f32, far: f32,
+    lights: &[Light], considered: &[usize], cluster_lights: &mut [Vec<u32>]) -> LightLists {
+  let mut indices = Vec::new();
+
+  for cluster in cluster_lights.iter_mut() {
+    cluster.clear();
+  }
+
+  for &i in considered {
+    if is_global(&lights[i]) {
+      indices.push(i as u32);
+      continue;
+    }
+
+    let (min, max) = match get_cluster_bounds(&lights[i], projection, view, near, far) {
+      Some(bounds) => bounds,
+      None => continue,
+    };
+
+    for z in min[2] .. max[2] + 1 {
+      for y in min[1] .. max[1] + 1 {
+        for x in min[0] .. max[0] + 1 {
+          let cluster = x + (y + z * CLUSTERS_Y) * CLUSTERS_X;
+          cluster_lights[cluster].push(i as u32);
+        }
+      }
+    }
+  }
+
+  let num_global_lights = indices.len();
+
+  // lights that don't fit anymore are left out of the clusters at the back
+  let mut grid = vec![vec![(0u32, 0u32); CLUSTERS_X * CLUSTERS_Y]; CLUSTERS_Z];
+  let mut num_left_out = 0;
+
+  for (c, cluster) in cluster_lights.iter().enumerate() {
+    let count = usize::min(cluster.len(), MAX_CLUSTER_INDICES - indices.len());
+    grid[c / (CLUSTERS_X * CLUSTERS_Y)][c % (CLUSTERS_X * CLUSTERS_Y)] =
+        (indices.len() as u32, count as u32);
+    indices.extend_from_slice(&cluster[..count]);
+    num_left_out += cluster.len() - count;
+  }
+
+  LightLists {
+    grid: grid,
+    indices: indices,
+    num_global_lights: num_global_lights as u32,
+    num_left_out: num_left_out,
+  }
+}
+
+// the near and far planes of a perspective projection; jitter and cropping leave these alone
+fn get_depth_range(projection: &Matrix4<f32>) -> (f32, f32) {
+  let near = projection.w.z / (projection.z.z - 1.0);
+  let far = projection.w.z / (projection.z.z + 1.0);
+
+  let near = if near.is_finite() && near > 0.0 { near } else { 0.01 };
+  let far = if far.is_finite() && far > near { f32::min(far, MAX_FAR) } else { MAX_FAR };
+
+  (near, f32::max(far, near * 2.0))
+}
+
+fn get_slice(depth: f32, near: f32, far: f32) -> usize {
+  let slice = (depth / near).ln() / (far / near).ln() * CLUSTERS_Z as f32;
+  f32::max(f32::min(slice, (CLUSTERS_Z - 1) as f32), 0.0) as usize
+}
+
+fn get_tile(ndc: f32, num_tiles: usize) -> usize {
+  let tile = (ndc * 0.5 + 0.5) * num_tiles as f32;
+  f32::max(f32::min(tile, (num_tiles - 1) as f32), 0.0) as usize
+}
+
+// the first and last cluster of the bounding sphere of a light that is not global, or none if it
+// can't be seen
+fn get_cluster_bounds(light: &Light, projection: &Matrix4<f32>, view: &Matrix4<f32>, near: f32,
+    far: f32) -> Option<([usize; 3], [usize; 3])> {
+  let position = Vector3::from(light.position);
+  let center = view * position.extend(1.0);
+  let radius = light.range;
+  let min_depth = -center.z - radius;
+  let max_depth = -center.z + radius;
+
+  if max_depth < near || min_depth > far { return None; }
+
+  let min_slice = get_slice(f32::max(min_depth, near), near, far);
+  let max_slice = get_slice(f32::min(max_depth, far), near, far);
+
+  // the projection of a box that crosses the near plane covers the whole screen
+  if min_depth <= near {
+    return Some(([0, 0, min_slice], [CLUSTERS_X - 1, CLUSTERS_Y - 1, max_slice]));
+  }
+
+  let mut ndc_min = [f32::INFINITY; 2];
+  let mut ndc_max = [f32::NEG_INFINITY; 2];
+
+  for corner in 0..8 {
+    let offset = Vector4::new(
+        if corner & 1 == 0 { -radius } else { radius },
+        if corner & 2 == 0 { -radius } else { radius },
+        if corner & 4 == 0 { -radius } else { radius },
+        0.0);
+    let clip = projection * (center + offset);
+
+    for axis in 0..2 {
+      ndc_min[axis] = f32::min(ndc_min[axis], clip[axis] / clip.w);
+      ndc_max[axis] = f32::max(ndc_max[axis], clip[axis] / clip.w);
+    }
+  }
+
+  if ndc_max[0] < -1.0 || ndc_min[0] > 1.0 || ndc_max[1] < -1.0 || ndc_min[1] > 1.0 {
+    return None;
+  }
+
+  Some(([get_tile(ndc_min[0], CLUSTERS_X), get_tile(ndc_min[1], CLUSTERS_Y), min_slice],
+      [get_tile(ndc_max[0], CLUSTERS_X), get_tile(ndc_max[1], CLUSTERS_Y), max_slice]))
+}
+
+#[cfg(test)]
+mod tests {
+  use cgmath;
+  use cgmath::Deg;
+  use cgmath::Matrix4;
+  use cgmath::SquareMatrix;
+
+  use light::Light;
+  use light::LightKind;
+  use super::*;
+
+  fn get_projection() -> Matrix4<f32> {
+    cgmath::perspective(Deg(90.0), 1.0, 0.1, 100.0)
+  }
+
+  fn point_light(position: [f32; 3], range: f32) -> Light {
+    Light { kind: LightKind::Point, position: position, range: range, .. Default::default() }
+  }
+
+  #[test]
+  fn depth_range_comes_from_projection() {
+    let (near, far) = get_depth_range(&get_projection());
+    assert!((near - 0.1).abs() < 1e-4);
+    assert!((far - 100.0).abs() < 1e-1);
+  }
+
+  #[test]
+  fn slices_grow_with_depth() {
+    assert_eq!(get_slice(0.1, 0.1, 100.0), 0);
+    assert_eq!(get_slice(100.0, 0.1, 100.0), CLUSTERS_Z - 1);
+    assert_eq!(get_slice(1000.0, 0.1, 100.0), CLUSTERS_Z - 1);
+    assert_eq!(get_slice(0.01, 0.1, 100.0), 0);
+
+    let mut previous = 0;
+    for i in 1..100 {
+      let slice = get_slice(i as f32, 0.1, 100.0);
+      assert!(slice >= previous);
+      previous = slice;
+    }
+
+    // exponential slices are thinner close to the camera
+    assert!(get_slice(1.0, 0.1, 100.0) > CLUSTERS_Z / 4);
+  }
+
+  fn get_lists(lights: &[Light]) -> LightLists {
+    let considered: Vec<usize> = (0..lights.len()).collect();
+    let mut cluster_lights = vec![Vec::new(); NUM_CLUSTERS];
+
+    get_light_lists(&get_projection(), &Matrix4::identity(), 0.1, 100.0, lights, &considered,
+        &mut cluster_lights)
+  }
+
+  #[test]
+  fn lights_without_range_are_global() {
+    let directional = Light { kind: LightKind::Directional, .. Default::default() };
+    let lists = get_lists(&[point_light([0.0, 0.0, -5.0], 0.0), directional,
+        point_light([0.0, 0.0, -5.0], 1.0)]);
+
+    assert_eq!(lists.num_global_lights, 2);
+    assert_eq!(&lists.indices[..2], &[0, 1]);
+    assert!(lists.grid.iter().flat_map(|row| row.iter()).all(|&(offset, _)| offset >= 2));
+    assert!(lists.indices[2..].iter().all(|&i| i == 2));
+  }
+
+  #[test]
+  fn many_unranged_lights_reach_every_cluster() {
+    let lights: Vec<Light> = (0..256).map(|i| point_light([i as f32, 0.0, -5.0], 0.0)).collect();
+    let lists = get_lists(&lights);
+
+    assert_eq!(lists.num_global_lights, 256);
+    assert_eq!(lists.indices, (0..256).collect::<Vec<u32>>());
+    assert_eq!(lists.num_left_out, 0);
+    assert!(lists.grid.iter().flat_map(|row| row.iter()).all(|&(_, count)| count == 0));
+  }
+
+  #[test]
+  fn overflowing_clusters_are_counted() {
+    // lights around the camera cover the whole screen up to some depth
+    let lights: Vec<Light> = (0..256).map(|_| point_light([0.0, 0.0, 0.0], 50.0)).collect();
+    let lists = get_lists(&lights);
+
+    assert_eq!(lists.indices.len(), MAX_CLUSTER_INDICES);
+    assert!(lists.num_left_out > 0);
+  }
+
+  #[test]
+  fn lights_out_of_view_reach_nothing() {
+    let view = Matrix4::identity();
+
+    // behind the camera, beyond the far plane and far to the side
+    for position in &[[0.0, 0.0, 5.0], [0.0, 0.0, -200.0], [100.0, 0.0, -5.0]] {
+      let light = point_light(*position, 1.0);
+      assert_eq!(get_cluster_bounds(&light, &get_projection(), &view, 0.1, 100.0), None);
+    }
+  }
+
+  #[test]
+  fn small_light_reaches_clusters_around_it() {
+    let light = point_light([0.0, 0.0, -10.0], 1.0);
+    let (min, max) = get_cluster_bounds(&light, &get_projection(), &Matrix4::identity(), 0.1,
+        100.0).unwrap();
+
+    assert!(min[0] <= CLUSTERS_X / 2 && max[0] >= CLUSTERS_X / 2 - 1);
+    assert!(min[1] <= CLUSTERS_Y / 2 && max[1] >= CLUSTERS_Y / 2 - 1);
+    assert!(max[0] - min[0] < CLUSTERS_X / 2);
+    assert_eq!(min[2], get_slice(9.0, 0.1, 100.0));
+    assert_eq!(max[2], get_slice(11.0, 0.1, 100.0));
+  }
+
+  #[test]
+  fn light_around_camera_covers_screen() {
+    let light = point_light([0.0, 0.0, -1.0], 2.0);
+    let (min, max) = get_cluster_bounds(&light, &get_projection(), &Matrix4::identity(), 0.1,
+        100.0).unwrap();
+
+    assert_eq!((min[0], min[1], min[2]), (0, 0, 0));
+    assert_eq!((max[0], max[1]), (CLUSTERS_X - 1, CLUSTERS_Y - 1));
+    assert_eq!(max[2], get_slice(3.0, 0.1, 100.0));
+  }
+
+  #[test]
+  fn view_moves_lights() {
+    let light = point_light([0.0, 0.0, 5.0], 1.0);
+    let view = Matrix4::from_translation(cgmath::Vector3::new(0.0, 0.0, -10.0));
+    assert!(get_cluster_bounds(&light, &get_projection(), &view, 0.1, 100.0).is_some());
+  }
+}
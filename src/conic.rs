@@ -31,7 +31,7 @@ use std::f32;
 use drawable::Drawable;
 use gui::Action;
 use math;
-use light::Light;
+use light::Lighting;
 
 #[derive(Copy, Clone)]
 pub struct ConicVertex {
@@ -136,8 +136,8 @@ impl Conic {
 
 impl Drawable for Conic {
   fn draw(&mut self, target: &mut SimpleFrameBuffer, _: &Facade, projection: [[f32; 4]; 4],
-      view: [[f32; 4]; 4], model_transform: Matrix4<f32>, render_params: &DrawParameters, _: i32,
-      _: &[Light; 32], _: usize, _: bool, _: bool) {
+      view: [[f32; 4]; 4], model_transform: Matrix4<f32>, _: f32, render_params: &DrawParameters,
+      _: &Lighting, _: usize, _: bool, _: bool) {
     let uniforms = uniform! {
       projection: projection,
       view: view,
@@ -0,0 +1,179 @@
+// Copyright (c) 2018 Remco Kranenburg
+//
+// GNU GENERAL PUBLIC LICENSE
+//    Version 3, 29 June 2007
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::f32;
+
+use performance::FramePerformance;
+
+pub const CONTROLLER_NAMES: [&str; 2] = ["zone", "pid"];
+
+#[derive(Copy, Clone, Debug)]
+pub struct PidParameters {
+  pub setpoint: f32,
+  pub gain_p: f32,
+  pub gain_i: f32,
+  pub gain_d: f32,
+  pub derivative_filter: f32,
+}
+
+impl Default for PidParameters {
+  fn default() -> PidParameters {
+    PidParameters {
+      setpoint: 0.2,  // the middle of the zone controller's safe zone
+      gain_p: 0.5,
+      gain_i: 0.05,
+      gain_d: 0.1,
+      derivative_filter: 0.2,
+    }
+  }
+}
+
+pub trait QualityController {
+  fn get_name(&self) -> &str;
+
+  // Decides on the quality level for the next frame, based on the current level, the frame
+  // history and how much of the target frame time is predicted to be left over at the current
+  // level. Other levels can be tried with frame_performance.predict_remaining_time.
+  fn next_level(&mut self, level: f32, frame_performance: &FramePerformance,
+      predicted_remaining_time: u32, target_frame_time: u32) -> f32;
+
+  // Returns the setpoint and gains (setpoint, p, i, d) for the performance log, if any.
+  fn get_gains(&self) -> (f32, f32, f32, f32) {
+    (0.0, 0.0, 0.0, 0.0)
+  }
+}
+
+pub fn from_name(name: &str, pid_parameters: &PidParameters) -> Option<Box<QualityController>> {
+  match name {
+    "zone" => Some(Box::new(ZoneController::new())),
+    "pid" => Some(Box::new(PidController::new(*pid_parameters))),
+    _ => None,
+  }
+}
+
+pub struct ZoneController;
+
+impl ZoneController {
+  pub fn new() -> ZoneController {
+    ZoneController
+  }
+}
+
+impl QualityController for ZoneController {
+  fn get_name(&self) -> &str {
+    "zone"
+  }
+
+  fn next_level(&mut self, level: f32, _: &FramePerformance, predicted_remaining_time: u32,
+      target_frame_time: u32) -> f32 {
+    let ratio_remaining = f32::max(0.0, predicted_remaining_time as f32 / target_frame_time as f32);
+
+    const EMERGENCY_ZONE: f32 = 0.05;   // 0.00 - 0.05
+    const DANGER_ZONE: f32 = 0.1;       // 0.05 - 0.10
+    const SAFE_ZONE: f32 = 0.3;         // 0.10 - 0.30
+    const EASY_ZONE: f32 = 0.8;         // 0.30 - 0.80
+    // IDLE_ZONE                           0.80 - 1.00
+
+    if ratio_remaining < EMERGENCY_ZONE {
+      f32::max(level * 0.5, 0.0001)
+    } else if ratio_remaining < DANGER_ZONE {
+      f32::max(level * 0.99, 0.0001)
+    } else if ratio_remaining < SAFE_ZONE {
+      // in safe zone, do nothing
+      level
+    } else if ratio_remaining < EASY_ZONE {
+      f32::min(level * 1.01, 1.0)
+    } else {
+      f32::min(level * 2.0, 1.0)
+    }
+  }
+}
+
+pub struct PidController {
+  pub parameters: PidParameters,
+
+  integral: f32,
+  derivative: f32,
+  previous_error: Option<f32>,
+}
+
+impl PidController {
+  pub fn new(parameters: PidParameters) -> PidController {
+    PidController {
+      parameters: parameters,
+      integral: 0.0,
+      derivative: 0.0,
+      previous_error: None,
+    }
+  }
+}
+
+impl QualityController for PidController {
+  fn get_name(&self) -> &str {
+    "pid"
+  }
+
+  fn next_level(&mut self, level: f32, _: &FramePerformance, predicted_remaining_time: u32,
+      target_frame_time: u32) -> f32 {
+    const MIN_LEVEL: f32 = 0.0001;
+    const MAX_LEVEL: f32 = 1.0;
+
+    let p = self.parameters;
+    let ratio_remaining = f32::max(0.0, predicted_remaining_time as f32 / target_frame_time as f32);
+
+    // a positive error means we have time to spare, so quality can go up
+    let error = ratio_remaining - p.setpoint;
+
+    // the integral term carries the level, so the first frame starts out at the current level
+    // instead of jumping to whatever the proportional term says; without an integral gain (or
+    // one so small that dividing by it overflows) there is no integral term to carry it
+    let has_integral = p.gain_i > 0.0 && p.gain_i.is_normal();
+    let integral_limit = if has_integral { MAX_LEVEL / p.gain_i } else { 0.0 };
+
+    let derivative = match self.previous_error {
+      Some(previous_error) => error - previous_error,
+      None => {
+        self.integral = if has_integral { level / p.gain_i } else { 0.0 };
+        0.0
+      },
+    };
+    self.previous_error = Some(error);
+
+    // low-pass filter the derivative, frame times are noisy and would make it flicker
+    self.derivative += p.derivative_filter * (derivative - self.derivative);
+
+    let integral = f32::max(-integral_limit, f32::min(integral_limit, self.integral + error));
+    let output = p.gain_p * error + p.gain_i * integral + p.gain_d * self.derivative;
+
+    // anti-windup: stop integrating while the output is saturated in the direction of the error
+    let saturated = (output > MAX_LEVEL && error > 0.0) || (output < MIN_LEVEL && error < 0.0);
+
+    if !saturated {
+      self.integral = integral;
+    }
+
+    let output = p.gain_p * error + p.gain_i * self.integral + p.gain_d * self.derivative;
+
+    f32::max(MIN_LEVEL, f32::min(MAX_LEVEL, output))
+  }
+
+  fn get_gains(&self) -> (f32, f32, f32, f32) {
+    (self.parameters.setpoint, self.parameters.gain_p, self.parameters.gain_i,
+        self.parameters.gain_d)
+  }
+}
@@ -0,0 +1,356 @@
+// Copyright (c) 2018 Remco Kranenburg
+//
+// GNU GENERAL PUBLIC LICENSE
+//    Version 3, 29 June 2007
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use csv;
+use csv::Reader;
+use csv::StringRecord;
+use serde_yaml;
+use std::cmp::Ordering;
+use std::f64;
+use std::fs::File;
+use std::path::Path;
+
+use error::EngynError;
+
+// A quadratic polynomial over the quality knobs that predicts the draw time (in nanoseconds) of a
+// frame. It is fitted with least squares on the benchmark logs, which have a TargetX column for
+// each knob X.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CostModel {
+  pub knobs: Vec<String>,
+  pub coefficients: Vec<f64>,
+  pub num_samples: usize,
+  pub rms_error: f64,
+}
+
+// Terms of the polynomial: 1, x_i and x_i * x_j for i <= j.
+fn get_terms(values: &[f64]) -> Vec<f64> {
+  let mut terms = vec![1.0];
+
+  for i in 0..values.len() {
+    terms.push(values[i]);
+  }
+
+  for i in 0..values.len() {
+    for j in i..values.len() {
+      terms.push(values[i] * values[j]);
+    }
+  }
+
+  terms
+}
+
+// Solves the square system a * x = b with Gaussian elimination and partial pivoting. Returns None
+// if the system is singular or holds NaNs.
+fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+  let n = b.len();
+
+  for column in 0..n {
+    let pivot = (column..n).max_by(|&i, &j| {
+      a[i][column].abs().partial_cmp(&a[j][column].abs()).unwrap_or(Ordering::Equal)
+    }).unwrap();
+
+    // also true for NaN
+    if !(a[pivot][column].abs() >= 1e-12) {
+      return None;
+    }
+
+    a.swap(column, pivot);
+    b.swap(column, pivot);
+
+    for row in column + 1..n {
+      let factor = a[row][column] / a[column][column];
+
+      for k in column..n {
+        a[row][k] -= factor * a[column][k];
+      }
+
+      b[row] -= factor * b[column];
+    }
+  }
+
+  let mut x = vec![0.0; n];
+
+  for row in (0..n).rev() {
+    let sum: f64 = (row + 1..n).map(|k| a[row][k] * x[k]).sum();
+    x[row] = (b[row] - sum) / a[row][row];
+  }
+
+  if x.iter().all(|x| x.is_finite()) { Some(x) } else { None }
+}
+
+// the given columns of a line of a benchmark log, as numbers
+fn parse_columns(path: &Path, result: Result<StringRecord, csv::Error>, columns: &[usize])
+    -> Result<Vec<f64>, EngynError> {
+  let record = result.map_err(|e| EngynError::csv(path, e))?;
+
+  columns.iter().map(|&column| {
+    let message = match record.get(column) {
+      Some(field) => match field.parse::<f64>() {
+        Ok(value) => return Ok(value),
+        Err(e) => format!("column {}: {}", column + 1, e),
+      },
+      None => format!("missing column {}", column + 1),
+    };
+
+    Err(EngynError::Csv {
+      path: path.to_path_buf(),
+      line: record.position().map(|p| p.line()),
+      message: message,
+    })
+  }).collect()
+}
+
+impl CostModel {
+  // fits a model to the benchmark logs at `paths`; lines that can't be read are skipped
+  pub fn fit(paths: &[&Path], knobs: &[&str]) -> Result<CostModel, EngynError> {
+    let mut samples: Vec<(Vec<f64>, f64)> = Vec::new();
+
+    for path in paths {
+      let mut reader = Reader::from_path(path).map_err(|e| EngynError::csv(path, e))?;
+      let headers = reader.headers().map_err(|e| EngynError::csv(path, e))?.clone();
+      let find_column = |name: &str| -> Result<usize, EngynError> {
+        headers.iter().position(|h| h == name).ok_or_else(|| EngynError::Csv {
+          path: path.to_path_buf(),
+          line: None,
+          message: format!("missing column {}", name),
+        })
+      };
+
+      let mut columns = vec![find_column("pre_draw")?, find_column("post_draw")?];
+
+      for knob in knobs {
+        columns.push(find_column(&format!("Target{}", knob))?);
+      }
+
+      for result in reader.records() {
+        let mut values = match parse_columns(path, result, &columns) {
+          Ok(values) => values,
+          Err(e) => {
+            eprintln!("Warning: skipping cost sample: {}", e);
+            continue;
+          },
+        };
+
+        let draw_time = values[1] - values[0];
+        let values = values.split_off(2);
+
+        // "NaN" and "inf" parse fine, but would spoil the whole fit
+        if !draw_time.is_finite() || values.iter().any(|v| !v.is_finite()) {
+          continue;
+        }
+
+        samples.push((values, draw_time));
+      }
+    }
+
+    let num_terms = get_terms(&vec![0.0; knobs.len()]).len();
+
+    if samples.len() < num_terms {
+      return Err(EngynError::CostModel {
+        message: format!("need at least {} samples to fit a cost model, got {}", num_terms,
+            samples.len()),
+      });
+    }
+
+    // least squares through the normal equations: (A^T A) c = A^T y
+    let mut ata = vec![vec![0.0; num_terms]; num_terms];
+    let mut aty = vec![0.0; num_terms];
+
+    for &(ref values, time) in &samples {
+      let terms = get_terms(values);
+
+      for i in 0..num_terms {
+        aty[i] += terms[i] * time;
+
+        for j in 0..num_terms {
+          ata[i][j] += terms[i] * terms[j];
+        }
+      }
+    }
+
+    // a tiny bit of ridge regularization keeps the system solvable when a knob was never varied
+    let trace: f64 = (0..num_terms).map(|i| ata[i][i]).sum();
+    for i in 0..num_terms {
+      ata[i][i] += trace * 1e-9;
+    }
+
+    let coefficients = solve(ata, aty).ok_or_else(|| EngynError::CostModel {
+      message: "benchmark data does not determine a cost model".to_owned(),
+    })?;
+
+    let mut model = CostModel {
+      knobs: knobs.iter().map(|k| k.to_string()).collect(),
+      coefficients: coefficients,
+      num_samples: samples.len(),
+      rms_error: 0.0,
+    };
+
+    let squared_error: f64 = samples.iter().map(|&(ref values, time)| {
+      (model.predict_f64(values) - time).powi(2)
+    }).sum();
+
+    model.rms_error = (squared_error / samples.len() as f64).sqrt();
+
+    Ok(model)
+  }
+
+  fn predict_f64(&self, values: &[f64]) -> f64 {
+    get_terms(values).iter().zip(self.coefficients.iter()).map(|(t, c)| t * c).sum()
+  }
+
+  // Predicts the draw time in nanoseconds for the given target levels, in the order of `knobs`.
+  pub fn predict(&self, values: &[f32]) -> f32 {
+    let values: Vec<f64> = values.iter().map(|v| *v as f64).collect();
+    f64::max(0.0, self.predict_f64(&values)) as f32
+  }
+
+  pub fn from_yaml(filename: &str) -> Result<CostModel, EngynError> {
+    let path = Path::new(filename);
+    let file = File::open(path).map_err(|e| EngynError::io(path, e))?;
+    serde_yaml::from_reader(&file).map_err(|e| EngynError::yaml(path, e))
+  }
+
+  pub fn to_yaml(&self, filename: &str) -> Result<(), EngynError> {
+    let path = Path::new(filename);
+    let file = File::create(path).map_err(|e| EngynError::io(path, e))?;
+    serde_yaml::to_writer(file, self).map_err(|e| EngynError::yaml(path, e))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+  use std::fs::File;
+  use std::io::Write;
+  use std::path::PathBuf;
+
+  use super::*;
+
+  fn write_log(name: &str, lines: &[&str]) -> PathBuf {
+    let path = env::temp_dir().join(format!("engyn_cost_model_{}.csv", name));
+    let mut file = File::create(&path).unwrap();
+
+    for line in lines {
+      writeln!(file, "{}", line).unwrap();
+    }
+
+    path
+  }
+
+  fn assert_close(a: f64, b: f64) {
+    assert!((a - b).abs() < 1e-3 * f64::max(1.0, b.abs()), "{} != {}", a, b);
+  }
+
+  #[test]
+  fn solve_finds_solution() {
+    let x = solve(vec![vec![2.0, 1.0], vec![1.0, 3.0]], vec![3.0, 5.0]).unwrap();
+    assert_close(x[0], 0.8);
+    assert_close(x[1], 1.4);
+  }
+
+  #[test]
+  fn solve_pivots_around_zero() {
+    let x = solve(vec![vec![0.0, 1.0], vec![1.0, 0.0]], vec![2.0, 3.0]).unwrap();
+    assert_close(x[0], 3.0);
+    assert_close(x[1], 2.0);
+  }
+
+  #[test]
+  fn solve_rejects_singular_and_nan() {
+    assert_eq!(solve(vec![vec![1.0, 2.0], vec![2.0, 4.0]], vec![1.0, 2.0]), None);
+    assert_eq!(solve(vec![vec![f64::NAN, 0.0], vec![0.0, 1.0]], vec![1.0, 1.0]), None);
+  }
+
+  #[test]
+  fn ridge_makes_singular_system_solvable() {
+    let mut a = vec![vec![1.0, 2.0], vec![2.0, 4.0]];
+    let trace = a[0][0] + a[1][1];
+
+    for i in 0..2 {
+      a[i][i] += trace * 1e-9;
+    }
+
+    let x = solve(a, vec![1.0, 2.0]).unwrap();
+    assert_close(x[0] + 2.0 * x[1], 1.0);
+  }
+
+  #[test]
+  fn predict_evaluates_polynomial() {
+    // 1, a, b, a * a, a * b, b * b
+    let model = CostModel {
+      knobs: vec!["A".to_owned(), "B".to_owned()],
+      coefficients: vec![100.0, 10.0, 20.0, 1.0, 2.0, 3.0],
+      num_samples: 0,
+      rms_error: 0.0,
+    };
+
+    assert_close(model.predict(&[0.0, 0.0]) as f64, 100.0);
+    assert_close(model.predict(&[1.0, 2.0]) as f64, 100.0 + 10.0 + 40.0 + 1.0 + 4.0 + 12.0);
+
+    let negative = CostModel { coefficients: vec![-100.0, 0.0, 0.0, 0.0, 0.0, 0.0], .. model };
+    assert_eq!(negative.predict(&[0.5, 0.5]), 0.0);
+  }
+
+  #[test]
+  fn fit_recovers_quadratic_and_skips_bad_lines() {
+    let path = write_log("quadratic", &[
+      "pre_draw,post_draw,TargetA",
+      "0,100,0",
+      "1000,1113.125,0.25",
+      "2000,2127.5,0.5",
+      "not,a,number",
+      "3000,3143.125,0.75",
+      "4000,4160,1",
+      "5000,5100",
+    ]);
+
+    let model = CostModel::fit(&[path.as_path()], &["A"]).unwrap();
+
+    assert_eq!(model.num_samples, 5);
+    assert_close(model.coefficients[0], 100.0);
+    assert_close(model.coefficients[1], 50.0);
+    assert_close(model.coefficients[2], 10.0);
+    assert!(model.rms_error < 1e-3);
+  }
+
+  #[test]
+  fn fit_survives_knob_that_never_varies() {
+    let path = write_log("constant_knob", &[
+      "pre_draw,post_draw,TargetA,TargetB",
+      "0,100,0,0.5",
+      "0,125,0.25,0.5",
+      "0,150,0.5,0.5",
+      "0,175,0.75,0.5",
+      "0,200,1,0.5",
+      "0,200,1,0.5",
+      "0,100,0,0.5",
+    ]);
+
+    let model = CostModel::fit(&[path.as_path()], &["A", "B"]).unwrap();
+    assert_close(model.predict(&[0.5, 0.5]) as f64, 150.0);
+  }
+
+  #[test]
+  fn fit_needs_enough_samples_and_columns() {
+    let path = write_log("too_few", &["pre_draw,post_draw,TargetA", "0,100,0"]);
+    assert!(CostModel::fit(&[path.as_path()], &["A"]).is_err());
+    assert!(CostModel::fit(&[path.as_path()], &["B"]).is_err());
+  }
+}
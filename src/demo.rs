@@ -19,9 +19,11 @@
 use bincode;
 use bincode::Infinite;
 use std::fs::File;
-use std::io::Result;
 use std::io::Read;
 use std::io::Write;
+use std::path::Path;
+
+use error::EngynError;
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 pub struct DemoEntry {
@@ -39,19 +41,21 @@ impl Demo {
     Demo { entries: Vec::new() }
   }
 
-  pub fn from_bincode(filename: &str) -> Result<Demo> {
+  pub fn from_bincode(filename: &str) -> Result<Demo, EngynError> {
+    let path = Path::new(filename);
     let mut bytes = Vec::new();
-    let mut file = File::open(filename)?;
+    let mut file = File::open(path).map_err(|e| EngynError::io(path, e))?;
 
-    file.read_to_end(&mut bytes)?;
+    file.read_to_end(&mut bytes).map_err(|e| EngynError::io(path, e))?;
 
-    let demo: Demo = bincode::deserialize(&bytes).unwrap();
+    let demo: Demo = bincode::deserialize(&bytes).map_err(|e| EngynError::demo(path, e))?;
     Ok(demo)
   }
 
-  pub fn to_bincode(&self, filename: &str) -> Result<()> {
-    let mut file = File::create(filename)?;
-    let bytes: Vec<u8> = bincode::serialize(self, Infinite).unwrap();
-    file.write_all(&bytes)
+  pub fn to_bincode(&self, filename: &str) -> Result<(), EngynError> {
+    let path = Path::new(filename);
+    let mut file = File::create(path).map_err(|e| EngynError::io(path, e))?;
+    let bytes: Vec<u8> = bincode::serialize(self, Infinite).map_err(|e| EngynError::demo(path, e))?;
+    file.write_all(&bytes).map_err(|e| EngynError::io(path, e))
   }
 }
@@ -20,16 +20,54 @@ use cgmath::Matrix4;
 use glium::DrawParameters;
 use glium::backend::Facade;
 use glium::framebuffer::SimpleFrameBuffer;
+use std::cell::RefCell;
 use std::f32;
+use std::rc::Rc;
 
+use error::EngynError;
+use geometry::Geometry;
+use geometry::MeshData;
 use gui::Action;
-use light::Light;
+use light::Lighting;
+use material::Material;
+use resources::ResourceManager;
 
 pub trait Drawable {
   fn draw(&mut self, target: &mut SimpleFrameBuffer, context: &Facade, projection: [[f32; 4]; 4],
-      view: [[f32; 4]; 4], model_transform: Matrix4<f32>, render_params: &DrawParameters,
-      num_lights: i32, lights: &[Light; 32], eye_i: usize, is_anaglyph: bool,
-      show_bbox: bool);
+      view: [[f32; 4]; 4], model_transform: Matrix4<f32>, max_error: f32,
+      render_params: &DrawParameters, lighting: &Lighting, eye_i: usize,
+      is_anaglyph: bool, show_bbox: bool);
+
+  // the geometry that is drawn at full detail, if any
+  fn get_geometry(&self) -> Option<Rc<RefCell<Geometry>>> {
+    None
+  }
+
+  // the material it is drawn with, for drawables that have one
+  fn get_material(&self) -> Option<Rc<RefCell<Material>>> {
+    None
+  }
+
+  // the full detail triangles on the CPU, for picking
+  fn get_mesh_data(&self) -> Option<Rc<MeshData>> {
+    None
+  }
+
+  // whether this is drawn in the transparent pass, after everything that is opaque
+  fn is_blended(&self) -> bool {
+    false
+  }
+
+  // replaces the material, for drawables that have one
+  fn set_material(&mut self, _context: &Facade, _resource_manager: &ResourceManager,
+      _material: &Rc<RefCell<Material>>) -> Result<(), EngynError> {
+    Ok(())
+  }
+
+  // the number of triangles that `draw` draws with the given `max_error`
+  fn get_num_triangles(&self, _max_error: f32) -> usize {
+    0
+  }
 
   fn update(&mut self, context: &Facade, model_transform: Matrix4<f32>, actions: &Vec<Action>);
 }
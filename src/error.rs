@@ -0,0 +1,135 @@
+// Copyright (c) 2018 Remco Kranenburg
+//
+// GNU GENERAL PUBLIC LICENSE
+//    Version 3, 29 June 2007
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use bincode;
+use csv;
+use glium::ProgramCreationError;
+use serde_yaml;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use tobj;
+
+// Everything that can go wrong while loading the files that make up a scene, with the file (and
+// line, where known) that caused it.
+#[derive(Debug)]
+pub enum EngynError {
+  Io { path: PathBuf, error: io::Error },
+  Yaml { path: PathBuf, error: serde_yaml::Error },
+  Scene { path: PathBuf, message: String },
+  Obj { path: PathBuf, error: tobj::LoadError },
+  Texture { path: PathBuf, message: String },
+  Resource { path: PathBuf, message: String },
+  Shader { name: String, error: ProgramCreationError },
+  Csv { path: PathBuf, line: Option<u64>, message: String },
+  Demo { path: PathBuf, error: bincode::Error },
+  CostModel { message: String },
+}
+
+impl EngynError {
+  pub fn io(path: &Path, error: io::Error) -> EngynError {
+    EngynError::Io { path: path.to_path_buf(), error: error }
+  }
+
+  pub fn yaml(path: &Path, error: serde_yaml::Error) -> EngynError {
+    EngynError::Yaml { path: path.to_path_buf(), error: error }
+  }
+
+  pub fn obj(path: &Path, error: tobj::LoadError) -> EngynError {
+    EngynError::Obj { path: path.to_path_buf(), error: error }
+  }
+
+  pub fn texture(path: &Path, message: &str) -> EngynError {
+    EngynError::Texture { path: path.to_path_buf(), message: message.to_owned() }
+  }
+
+  pub fn resource(path: &Path, message: &str) -> EngynError {
+    EngynError::Resource { path: path.to_path_buf(), message: message.to_owned() }
+  }
+
+  pub fn shader(name: &str, error: ProgramCreationError) -> EngynError {
+    EngynError::Shader { name: name.to_owned(), error: error }
+  }
+
+  pub fn csv(path: &Path, error: csv::Error) -> EngynError {
+    EngynError::Csv {
+      path: path.to_path_buf(),
+      line: error.position().map(|p| p.line()),
+      message: error.to_string(),
+    }
+  }
+
+  pub fn demo(path: &Path, error: bincode::Error) -> EngynError {
+    EngynError::Demo { path: path.to_path_buf(), error: error }
+  }
+}
+
+impl fmt::Display for EngynError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      &EngynError::Io { ref path, ref error } => write!(f, "{}: {}", path.display(), error),
+      &EngynError::Yaml { ref path, ref error } => match error.location() {
+        Some(location) => write!(f, "{}:{}:{}: invalid YAML: {}", path.display(), location.line(),
+            location.column(), error),
+        None => write!(f, "{}: invalid YAML: {}", path.display(), error),
+      },
+      &EngynError::Scene { ref path, ref message } => write!(f, "{}: {}", path.display(), message),
+      &EngynError::Obj { ref path, ref error } => {
+        write!(f, "{}: could not load OBJ: {}", path.display(), error)
+      },
+      &EngynError::Texture { ref path, ref message } => {
+        write!(f, "{}: could not load texture: {}", path.display(), message)
+      },
+      &EngynError::Resource { ref path, ref message } => {
+        write!(f, "{}: {}", path.display(), message)
+      },
+      &EngynError::Shader { ref name, ref error } => {
+        write!(f, "could not compile {}: {}", name, error)
+      },
+      &EngynError::Csv { ref path, line: Some(line), ref message } => {
+        write!(f, "{}:{}: {}", path.display(), line, message)
+      },
+      &EngynError::Csv { ref path, line: None, ref message } => {
+        write!(f, "{}: {}", path.display(), message)
+      },
+      &EngynError::Demo { ref path, ref error } => {
+        write!(f, "{}: invalid demo: {}", path.display(), error)
+      },
+      &EngynError::CostModel { ref message } => write!(f, "{}", message),
+    }
+  }
+}
+
+impl Error for EngynError {
+  fn description(&self) -> &str {
+    match self {
+      &EngynError::Io { .. } => "could not read or write file",
+      &EngynError::Yaml { .. } => "invalid YAML",
+      &EngynError::Scene { .. } => "invalid scene",
+      &EngynError::Obj { .. } => "could not load OBJ",
+      &EngynError::Texture { .. } => "could not load texture",
+      &EngynError::Resource { .. } => "wrong kind of resource",
+      &EngynError::Shader { .. } => "could not compile shader",
+      &EngynError::Csv { .. } => "invalid CSV",
+      &EngynError::Demo { .. } => "invalid demo",
+      &EngynError::CostModel { .. } => "could not fit cost model",
+    }
+  }
+}
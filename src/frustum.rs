@@ -0,0 +1,97 @@
+// Copyright (c) 2018 Remco Kranenburg
+//
+// GNU GENERAL PUBLIC LICENSE
+//    Version 3, 29 June 2007
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use cgmath::Matrix4;
+
+use geometry::BoundingBox;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Intersection {
+  Outside,
+  Intersecting,
+  Inside,
+}
+
+// the six planes of a view frustum, pointing inwards, as (a, b, c, d) with ax + by + cz + d >= 0
+// for points inside
+pub struct Frustum {
+  planes: [[f32; 4]; 6],
+}
+
+impl Frustum {
+  // extracts the planes from a combined projection and view matrix, so they are in world space
+  pub fn from_matrix(m: &Matrix4<f32>) -> Frustum {
+    let row = |i: usize| [m.x[i], m.y[i], m.z[i], m.w[i]];
+    let (r0, r1, r2, r3) = (row(0), row(1), row(2), row(3));
+    let add = |a: [f32; 4], b: [f32; 4]| [a[0] + b[0], a[1] + b[1], a[2] + b[2], a[3] + b[3]];
+    let sub = |a: [f32; 4], b: [f32; 4]| [a[0] - b[0], a[1] - b[1], a[2] - b[2], a[3] - b[3]];
+
+    let mut planes = [
+      add(r3, r0), // left
+      sub(r3, r0), // right
+      add(r3, r1), // bottom
+      sub(r3, r1), // top
+      add(r3, r2), // near
+      sub(r3, r2), // far
+    ];
+
+    for plane in planes.iter_mut() {
+      let length = (plane[0] * plane[0] + plane[1] * plane[1] + plane[2] * plane[2]).sqrt();
+
+      if length > 0.0 {
+        for x in plane.iter_mut() {
+          *x /= length;
+        }
+      }
+    }
+
+    Frustum { planes: planes }
+  }
+
+  // tests an axis-aligned box in world space against the frustum
+  pub fn intersect_box(&self, bounding_box: &BoundingBox) -> Intersection {
+    let mut result = Intersection::Inside;
+
+    for plane in &self.planes {
+      // the corners that are furthest along and against the plane normal
+      let mut positive = bounding_box.0;
+      let mut negative = bounding_box.1;
+
+      for i in 0..3 {
+        if plane[i] >= 0.0 {
+          positive[i] = bounding_box.1[i];
+          negative[i] = bounding_box.0[i];
+        }
+      }
+
+      if distance(plane, &positive) < 0.0 {
+        return Intersection::Outside;
+      }
+
+      if distance(plane, &negative) < 0.0 {
+        result = Intersection::Intersecting;
+      }
+    }
+
+    result
+  }
+}
+
+fn distance(plane: &[f32; 4], point: &[f32; 3]) -> f32 {
+  plane[0] * point[0] + plane[1] * point[1] + plane[2] * point[2] + plane[3]
+}
@@ -0,0 +1,166 @@
+// Copyright (c) 2018 Remco Kranenburg
+//
+// GNU GENERAL PUBLIC LICENSE
+//    Version 3, 29 June 2007
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use glium::DrawParameters;
+use glium::Program;
+use glium::Rect;
+use glium::Surface;
+use glium::backend::Facade;
+use glium::framebuffer::SimpleFrameBuffer;
+use glium::texture::Texture2d;
+use glium::uniforms::MagnifySamplerFilter;
+use glium::uniforms::MinifySamplerFilter;
+
+use geometry::Geometry;
+
+// Screen-space anti-aliasing in the style of FXAA: blurs along edges that are found by looking at
+// the luminance of neighbouring pixels. Much cheaper than MSAA, but also blurrier.
+pub struct Fxaa {
+  program: Program,
+  quad: Geometry,
+}
+
+impl Fxaa {
+  pub fn new(display: &Facade) -> Fxaa {
+    Fxaa {
+      program: create_program(display),
+      quad: Geometry::new_quad(display, [2.0, 2.0], false),
+    }
+  }
+
+  // filters each rect of `source` into the same rect of `target`
+  pub fn apply(&self, display: &Facade, source: &Texture2d, target: &Texture2d, rects: &[Rect]) {
+    let size = (source.get_width() as f32, source.get_height().unwrap() as f32);
+    let mut framebuffer = SimpleFrameBuffer::new(display, target).unwrap();
+
+    for rect in rects {
+      let uniforms = uniform! {
+        source: source.sampled()
+            .magnify_filter(MagnifySamplerFilter::Linear)
+            .minify_filter(MinifySamplerFilter::Linear),
+        source_rect: [
+          rect.left as f32 / size.0,
+          rect.bottom as f32 / size.1,
+          rect.width as f32 / size.0,
+          rect.height as f32 / size.1,
+        ],
+        texel: [1.0 / size.0, 1.0 / size.1],
+      };
+
+      let params = DrawParameters {
+        viewport: Some(*rect),
+        .. Default::default()
+      };
+
+      framebuffer.draw(
+          (&self.quad.vertices, &self.quad.texcoords),
+          self.quad.indices.as_ref().unwrap(),
+          &self.program,
+          &uniforms,
+          &params).unwrap();
+    }
+  }
+}
+
+fn create_program(display: &Facade) -> Program {
+  Program::from_source(
+      display,
+      &r#"
+        #version 140
+
+        in vec3 position;
+        in vec2 texcoord;
+
+        out vec2 v_texcoord;
+
+        void main() {
+          v_texcoord = texcoord;
+          gl_Position = vec4(position, 1.0);
+        }
+      "#,
+      &r#"
+        #version 140
+
+        const float SPAN_MAX = 8.0;
+        const float REDUCE_MUL = 1.0 / 8.0;
+        const float REDUCE_MIN = 1.0 / 128.0;
+
+        uniform sampler2D source;
+        uniform vec4 source_rect;
+        uniform vec2 texel;
+
+        in vec2 v_texcoord;
+
+        out vec4 color;
+
+        vec2 uv_min;
+        vec2 uv_max;
+
+        vec3 fetch(vec2 uv) {
+          return texture(source, clamp(uv, uv_min, uv_max)).rgb;
+        }
+
+        float luma(vec3 rgb) {
+          return dot(rgb, vec3(0.299, 0.587, 0.114));
+        }
+
+        void main() {
+          uv_min = source_rect.xy + 0.5 * texel;
+          uv_max = source_rect.xy + source_rect.zw - 0.5 * texel;
+
+          vec2 uv = source_rect.xy + v_texcoord * source_rect.zw;
+
+          float luma_nw = luma(fetch(uv + vec2(-1.0,  1.0) * texel));
+          float luma_ne = luma(fetch(uv + vec2( 1.0,  1.0) * texel));
+          float luma_sw = luma(fetch(uv + vec2(-1.0, -1.0) * texel));
+          float luma_se = luma(fetch(uv + vec2( 1.0, -1.0) * texel));
+          float luma_m = luma(fetch(uv));
+
+          float luma_min = min(luma_m, min(min(luma_nw, luma_ne), min(luma_sw, luma_se)));
+          float luma_max = max(luma_m, max(max(luma_nw, luma_ne), max(luma_sw, luma_se)));
+
+          vec2 direction = vec2(
+              -((luma_nw + luma_ne) - (luma_sw + luma_se)),
+              (luma_nw + luma_sw) - (luma_ne + luma_se));
+
+          float direction_reduce = max((luma_nw + luma_ne + luma_sw + luma_se) * 0.25 * REDUCE_MUL,
+              REDUCE_MIN);
+          float inverse_direction_min = 1.0 / (min(abs(direction.x), abs(direction.y))
+              + direction_reduce);
+
+          direction = clamp(direction * inverse_direction_min, vec2(-SPAN_MAX), vec2(SPAN_MAX))
+              * texel;
+
+          vec3 rgb_a = 0.5 * (
+              fetch(uv + direction * (1.0 / 3.0 - 0.5)) +
+              fetch(uv + direction * (2.0 / 3.0 - 0.5)));
+          vec3 rgb_b = rgb_a * 0.5 + 0.25 * (
+              fetch(uv + direction * -0.5) +
+              fetch(uv + direction * 0.5));
+
+          float luma_b = luma(rgb_b);
+
+          if (luma_b < luma_min || luma_b > luma_max) {
+            color = vec4(rgb_a, 1.0);
+          } else {
+            color = vec4(rgb_b, 1.0);
+          }
+        }
+      "#,
+      None).unwrap()
+}
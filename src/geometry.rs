@@ -16,11 +16,16 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use cgmath::InnerSpace;
+use cgmath::Vector3;
 use glium::backend::Facade;
 use glium::index::IndexBuffer;
 use glium::index::PrimitiveType;
 use glium::vertex::VertexBuffer;
 use std::f32;
+use std::mem;
+use std::rc::Rc;
+use tobj;
 
 #[derive(Copy, Clone)]
 pub struct Vertex {
@@ -43,15 +48,208 @@ pub struct Normal {
 
 implement_vertex!(Normal, normal);
 
+// the direction of increasing u in texture space, with the handedness of the bitangent in w
+#[derive(Copy, Clone)]
+pub struct Tangent {
+    pub tangent: (f32, f32, f32, f32)
+}
+
+implement_vertex!(Tangent, tangent);
+
+// a triangle mesh on the CPU, with one normal and texcoord per vertex like the buffers of
+// `Geometry`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MeshData {
+  pub positions: Vec<[f32; 3]>,
+  pub normals: Vec<[f32; 3]>,
+  pub texcoords: Vec<[f32; 2]>,
+  pub indices: Vec<u32>,
+}
+
+impl MeshData {
+  pub fn from_obj(mesh: &tobj::Mesh) -> MeshData {
+    let num_vertices = mesh.positions.len() / 3;
+
+    MeshData {
+      positions: (0..num_vertices)
+          .map(|i| [mesh.positions[3 * i], mesh.positions[3 * i + 1], mesh.positions[3 * i + 2]])
+          .collect(),
+      normals: (0..num_vertices)
+          .map(|i| if mesh.normals.len() >= 3 * (i + 1) {
+            [mesh.normals[3 * i], mesh.normals[3 * i + 1], mesh.normals[3 * i + 2]]
+          } else {
+            [0.0, 0.0, 0.0]
+          })
+          .collect(),
+      texcoords: (0..num_vertices)
+          .map(|i| if mesh.texcoords.len() >= 2 * (i + 1) {
+            [mesh.texcoords[2 * i], mesh.texcoords[2 * i + 1]]
+          } else {
+            [0.0, 0.0]
+          })
+          .collect(),
+      indices: mesh.indices.clone(),
+    }
+  }
+
+  pub fn get_num_triangles(&self) -> usize {
+    self.indices.len() / 3
+  }
+
+  // per-vertex tangents from the texture coordinates, orthogonal to the normals
+  pub fn get_tangents(&self) -> Vec<[f32; 4]> {
+    let zero = Vector3::new(0.0, 0.0, 0.0);
+    let mut tangents = vec![zero; self.positions.len()];
+    let mut bitangents = vec![zero; self.positions.len()];
+
+    for t in self.indices.chunks(3).filter(|t| t.len() == 3) {
+      let (i0, i1, i2) = (t[0] as usize, t[1] as usize, t[2] as usize);
+      let edge1 = Vector3::from(self.positions[i1]) - Vector3::from(self.positions[i0]);
+      let edge2 = Vector3::from(self.positions[i2]) - Vector3::from(self.positions[i0]);
+      let du1 = self.texcoords[i1][0] - self.texcoords[i0][0];
+      let dv1 = self.texcoords[i1][1] - self.texcoords[i0][1];
+      let du2 = self.texcoords[i2][0] - self.texcoords[i0][0];
+      let dv2 = self.texcoords[i2][1] - self.texcoords[i0][1];
+
+      let determinant = du1 * dv2 - du2 * dv1;
+      if determinant.abs() < f32::EPSILON {
+        continue;
+      }
+
+      let tangent = (edge1 * dv2 - edge2 * dv1) / determinant;
+      let bitangent = (edge2 * du1 - edge1 * du2) / determinant;
+
+      for &i in &[i0, i1, i2] {
+        tangents[i] += tangent;
+        bitangents[i] += bitangent;
+      }
+    }
+
+    (0..self.positions.len()).map(|i| {
+      let normal = Vector3::from(self.normals[i]);
+      let mut tangent = tangents[i] - normal * normal.dot(tangents[i]);
+
+      // vertices without usable texture coordinates get any tangent that is orthogonal
+      if tangent.magnitude2() < f32::EPSILON {
+        let axis = if normal.x.abs() < 0.9 { Vector3::unit_x() } else { Vector3::unit_y() };
+        tangent = axis - normal * normal.dot(axis);
+      }
+
+      let tangent = tangent.normalize();
+      let handedness = if normal.cross(tangent).dot(bitangents[i]) < 0.0 { -1.0 } else { 1.0 };
+      [tangent.x, tangent.y, tangent.z, handedness]
+    }).collect()
+  }
+
+  // the number of bytes taken up by the vertex attributes and indices
+  pub fn get_memory_size(&self) -> usize {
+    self.positions.len() * mem::size_of::<[f32; 3]>()
+        + self.normals.len() * mem::size_of::<[f32; 3]>()
+        + self.texcoords.len() * mem::size_of::<[f32; 2]>()
+        + self.indices.len() * mem::size_of::<u32>()
+  }
+
+  pub fn get_area(&self) -> f32 {
+    self.indices.chunks(3).filter(|t| t.len() == 3).map(|t| {
+      let a = Vector3::from(self.positions[t[0] as usize]);
+      let b = Vector3::from(self.positions[t[1] as usize]);
+      let c = Vector3::from(self.positions[t[2] as usize]);
+      (b - a).cross(c - a).magnitude() * 0.5
+    }).sum()
+  }
+
+  pub fn get_statistics(&self) -> MeshStatistics {
+    MeshStatistics {
+      num_vertices: self.positions.len(),
+      num_triangles: self.get_num_triangles(),
+      area: self.get_area(),
+      bounding_box: self.get_bounding_box(),
+    }
+  }
+
+  // the bounding box of the vertices that are used by triangles
+  pub fn get_bounding_box(&self) -> BoundingBox {
+    let mut bounding_box = (
+      [f32::INFINITY; 3],
+      [f32::NEG_INFINITY; 3],
+    );
+
+    for idx in &self.indices {
+      let pos = self.positions[*idx as usize];
+
+      for i in 0..pos.len() {
+        bounding_box.0[i] = bounding_box.0[i].min(pos[i]);
+        bounding_box.1[i] = bounding_box.1[i].max(pos[i]);
+      }
+    }
+
+    bounding_box
+  }
+}
+
+pub type BoundingBox = ([f32; 3], [f32; 3]);
+
+// the size of a mesh, in model space
+#[derive(Clone, Copy, Debug)]
+pub struct MeshStatistics {
+  pub num_vertices: usize,
+  pub num_triangles: usize,
+  pub area: f32,
+  pub bounding_box: BoundingBox,
+}
+
 pub struct Geometry {
-  pub bounding_box: ([f32; 3], [f32; 3]),
+  pub bounding_box: BoundingBox,
+  // a CPU-side copy of the buffers, if it was kept
+  pub mesh_data: Option<Rc<MeshData>>,
   pub indices: Option<IndexBuffer<u32>>,
   pub normals: VertexBuffer<Normal>,
+  pub tangents: VertexBuffer<Tangent>,
   pub vertices: VertexBuffer<Vertex>,
   pub texcoords: VertexBuffer<Texcoord>,
 }
 
 impl Geometry {
+  pub fn get_num_triangles(&self) -> usize {
+    match self.indices {
+      Some(ref indices) => indices.len() / 3,
+      None => self.vertices.len() / 3,
+    }
+  }
+
+  pub fn get_statistics(&self) -> Option<MeshStatistics> {
+    self.mesh_data.as_ref().map(|mesh| mesh.get_statistics())
+  }
+
+  pub fn from_mesh_data(context: &Facade, mesh: &MeshData) -> Geometry {
+    let vertices: Vec<Vertex> = mesh.positions.iter()
+        .map(|p| Vertex { position: (p[0], p[1], p[2]) })
+        .collect();
+    let normals: Vec<Normal> = mesh.normals.iter()
+        .map(|n| Normal { normal: (n[0], n[1], n[2]) })
+        .collect();
+    let texcoords: Vec<Texcoord> = mesh.texcoords.iter()
+        .map(|t| Texcoord { texcoord: (t[0], t[1]) })
+        .collect();
+    let tangents: Vec<Tangent> = mesh.get_tangents().iter()
+        .map(|t| Tangent { tangent: (t[0], t[1], t[2], t[3]) })
+        .collect();
+
+    Geometry {
+      bounding_box: mesh.get_bounding_box(),
+      mesh_data: None,
+      indices: if mesh.indices.len() > 0 {
+        Some(IndexBuffer::new(context, PrimitiveType::TrianglesList, &mesh.indices).unwrap())
+      } else {
+        None
+      },
+      normals: VertexBuffer::new(context, &normals).unwrap(),
+      tangents: VertexBuffer::new(context, &tangents).unwrap(),
+      vertices: VertexBuffer::new(context, &vertices).unwrap(),
+      texcoords: VertexBuffer::new(context, &texcoords).unwrap(),
+    }
+  }
+
   pub fn new_quad(context: &Facade, size: [f32; 2], dynamic_texcoords: bool) -> Geometry {
     let width_half = size[0] * 0.5;
     let height_half = size[1] * 0.5;
@@ -61,6 +259,16 @@ impl Geometry {
         [-width_half, -height_half, 0.0],
         [width_half, height_half, 0.0],
       ),
+      mesh_data: Some(Rc::new(MeshData {
+        positions: vec![
+          [-width_half, -height_half, 0.0],
+          [-width_half,  height_half, 0.0],
+          [ width_half,  height_half, 0.0],
+          [ width_half, -height_half, 0.0]],
+        normals: vec![[0.0, 0.0, 1.0]; 4],
+        texcoords: vec![[0.0, 0.0], [0.0, 1.0], [1.0, 1.0], [1.0, 0.0]],
+        indices: vec![1, 2, 0, 0, 2, 3],
+      })),
       indices: Some(IndexBuffer::new(
           context,
           PrimitiveType::TriangleStrip,
@@ -70,6 +278,7 @@ impl Geometry {
           Normal { normal: (0.0, 0.0, 1.0) },
           Normal { normal: (0.0, 0.0, 1.0) },
           Normal { normal: (0.0, 0.0, 1.0) }]).unwrap(),
+      tangents: VertexBuffer::new(context, &[Tangent { tangent: (1.0, 0.0, 0.0, 1.0) }; 4]).unwrap(),
       vertices: VertexBuffer::new(context, &[
           Vertex { position: (-width_half, -height_half, 0.0) },
           Vertex { position: (-width_half,  height_half, 0.0) },
@@ -101,11 +310,21 @@ impl Geometry {
         [-width_half, -height_half, 0.0],
         [width_half, height_half, 0.0],
       ),
+      mesh_data: Some(Rc::new(MeshData {
+        positions: vec![
+          [-width_half, -height_half, 0.0],
+          [ width_half, -height_half, 0.0],
+          [        0.0,  height_half, 0.0]],
+        normals: vec![[0.0, 0.0, 1.0]; 3],
+        texcoords: vec![[0.0, 0.0], [1.0, 0.0], [0.5, 1.0]],
+        indices: vec![0, 1, 2],
+      })),
       indices: None,
       normals: VertexBuffer::new(context, &[
           Normal { normal: (0.0, 0.0, 1.0) },
           Normal { normal: (0.0, 0.0, 1.0) },
           Normal { normal: (0.0, 0.0, 1.0) }]).unwrap(),
+      tangents: VertexBuffer::new(context, &[Tangent { tangent: (1.0, 0.0, 0.0, 1.0) }; 3]).unwrap(),
       vertices: VertexBuffer::new(context, &[
           Vertex { position: (-width_half, -height_half, 0.0) },
           Vertex { position: ( width_half, -height_half, 0.0) },
@@ -118,3 +337,58 @@ impl Geometry {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // a unit square in the xy plane of two triangles, and one vertex that no triangle uses
+  fn square() -> MeshData {
+    MeshData {
+      positions: vec![
+        [0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0], [5.0, 5.0, 5.0],
+      ],
+      normals: vec![[0.0, 0.0, 1.0]; 5],
+      texcoords: vec![[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0], [0.0, 0.0]],
+      indices: vec![0, 1, 2, 0, 2, 3],
+    }
+  }
+
+  #[test]
+  fn area_sums_triangles() {
+    assert_eq!(square().get_area(), 1.0);
+
+    let mut scaled = square();
+    for position in &mut scaled.positions {
+      position[0] *= 3.0;
+    }
+    assert_eq!(scaled.get_area(), 3.0);
+  }
+
+  #[test]
+  fn area_ignores_incomplete_triangles() {
+    let mut mesh = square();
+    mesh.indices.push(1);
+    assert_eq!(mesh.get_area(), 1.0);
+  }
+
+  #[test]
+  fn memory_size_counts_attributes_and_indices() {
+    // 5 * 12 bytes of positions and of normals, 5 * 8 of texcoords and 6 * 4 of indices
+    assert_eq!(square().get_memory_size(), 60 + 60 + 40 + 24);
+
+    let empty = MeshData { positions: vec![], normals: vec![], texcoords: vec![], indices: vec![] };
+    assert_eq!(empty.get_memory_size(), 0);
+  }
+
+  #[test]
+  fn statistics_describe_the_mesh() {
+    let statistics = square().get_statistics();
+
+    assert_eq!(statistics.num_vertices, 5);
+    assert_eq!(statistics.num_triangles, 2);
+    assert_eq!(statistics.area, 1.0);
+    // the unused vertex is left out of the bounds
+    assert_eq!(statistics.bounding_box, ([0.0, 0.0, 0.0], [1.0, 1.0, 0.0]));
+  }
+}
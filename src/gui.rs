@@ -59,6 +59,7 @@ use std::time::Duration;
 use std::f32;
 
 use adaptive_canvas::AdaptiveCanvas;
+use quality::QualityKnob;
 
 widget_ids! {
   pub struct Ids {
@@ -67,9 +68,7 @@ widget_ids! {
     help_text,
     resume_button,
     quality_text,
-    resolution_slider,
-    msaa_slider,
-    lod_slider,
+    knob_sliders[],
     quit_button,
   }
 }
@@ -92,6 +91,9 @@ pub enum Action {
   GuiDecreaseSlider,
   GuiIncreaseSlider,
   GuiToggleMenu,
+  // x and y are in normalized device coordinates
+  PickCamera { x: f32, y: f32 },
+  PickGamepad(usize),
   Quit,
   Resize(u32, u32),
   Resume,
@@ -99,6 +101,7 @@ pub enum Action {
   StereoCross,
   StereoAnaglyph,
   ToggleBoundingBox,
+  ToggleOcclusionCulling,
   VisualizeOneD,
   VisualizeTwoD,
   VisualizeThreeD,
@@ -119,14 +122,14 @@ pub struct Gui<'a> {
   display: &'a Display,
   ids: Ids,
   image_map: Map<Texture2d>,
+  knob_labels: Vec<String>,
   renderer: Renderer,
   ui: Ui,
   widget_order: HashMap<String, usize>,
 }
 
 impl<'a> Gui<'a> {
-  pub fn new(display: &'a Display, weight_resolution: Rc<RefCell<f32>>,
-      weight_msaa: Rc<RefCell<f32>>, weight_lod: Rc<RefCell<f32>>) -> Gui<'a> {
+  pub fn new(display: &'a Display, knobs: &[QualityKnob]) -> Gui<'a> {
     // TODO: put this in a 'system integration' module
     let executable_string = env::args().nth(0).unwrap();
     let executable_path = Path::new(&executable_string).parent().unwrap();
@@ -154,30 +157,37 @@ impl<'a> Gui<'a> {
     let mut ui = UiBuilder::new([768.0, 960.0]).theme(theme).build();
     ui.fonts.insert_from_file(project_path.join("data").join("Cantarell-Regular.ttf")).unwrap();
 
+    let mut ids = Ids::new(ui.widget_id_generator());
+    ids.knob_sliders.resize(knobs.len(), &mut ui.widget_id_generator());
+
+    // a slider for each quality knob, between the resume and quit buttons
+    let mut widgets = vec![
+      GuiElement { action: Action::Resume, weight: Rc::new(RefCell::new(0.0)) },
+    ];
+    let mut widget_order = HashMap::new();
+    widget_order.insert("Resume".to_owned(), 0);
+
+    for knob in knobs {
+      widget_order.insert(knob.name.clone(), widgets.len());
+      widgets.push(GuiElement { action: Action::None, weight: Rc::clone(&knob.weight) });
+    }
+
+    widget_order.insert("Quit".to_owned(), widgets.len());
+    widgets.push(GuiElement { action: Action::Quit, weight: Rc::new(RefCell::new(0.0)) });
+
     Gui {
       is_visible: false,
       selected_widget: 0,
-      widgets: vec![
-        GuiElement { action: Action::Resume, weight: Rc::new(RefCell::new(0.0)) },
-        GuiElement { action: Action::None, weight: weight_resolution },
-        GuiElement { action: Action::None, weight: weight_msaa },
-        GuiElement { action: Action::None, weight: weight_lod },
-        GuiElement { action: Action::Quit, weight: Rc::new(RefCell::new(0.0)) },
-      ],
+      widgets: widgets,
 
       canvas: AdaptiveCanvas::new(display, 768, 960, 0),
       display: display,
-      ids: Ids::new(ui.widget_id_generator()),
+      ids: ids,
       image_map: Map::<Texture2d>::new(),
+      knob_labels: knobs.iter().map(|k| k.label.clone()).collect(),
       renderer: Renderer::new(display).unwrap(),
       ui: ui,
-      widget_order: [
-        ("Resume".to_owned(), 0),
-        ("Resolution".to_owned(), 1),
-        ("MSAA".to_owned(), 2),
-        ("LOD".to_owned(), 3),
-        ("Quit".to_owned(), 4),
-      ].iter().cloned().collect(),
+      widget_order: widget_order,
     }
   }
 
@@ -232,61 +242,25 @@ impl<'a> Gui<'a> {
         action = self.widgets[resume_index].action;
       }
 
-      let resolution_index = *self.widget_order.get("Resolution").unwrap();
-      let resolution_weight_ref = Rc::clone(&self.widgets[resolution_index].weight);
-      let resolution_weight = *resolution_weight_ref.borrow();
-
-      if let Some(weight) = Slider::new(resolution_weight, 0.0, 1.0)
-          .parent(self.ids.container)
-          .padded_w_of(self.ids.container, 25.0)
-          .color(if self.selected_widget == resolution_index {
-              slider_focussed_color
-            } else {
-              slider_default_color
-            })
-          .label(&format!("Resolution weight: {}", resolution_weight))
-          .small_font(ui)
-          .set(self.ids.resolution_slider, ui) {
-        *resolution_weight_ref.borrow_mut() = weight;
-        self.selected_widget = resolution_index;
-      }
-
-      let msaa_index = *self.widget_order.get("MSAA").unwrap();
-      let msaa_weight_ref = Rc::clone(&self.widgets[msaa_index].weight);
-      let msaa_weight = *msaa_weight_ref.borrow();
-
-      if let Some(weight) = Slider::new(msaa_weight, 0.0, 1.0)
-          .parent(self.ids.container)
-          .padded_w_of(self.ids.container, 25.0)
-          .color(if self.selected_widget == msaa_index {
-              slider_focussed_color
-            } else {
-              slider_default_color
-            })
-          .label(&format!("Anti-aliasing weight: {}", msaa_weight))
-          .small_font(ui)
-          .set(self.ids.msaa_slider, ui) {
-        *msaa_weight_ref.borrow_mut() = weight;
-        self.selected_widget = msaa_index;
-      }
-
-      let lod_index = *self.widget_order.get("LOD").unwrap();
-      let lod_weight_ref = Rc::clone(&self.widgets[lod_index].weight);
-      let lod_weight = *lod_weight_ref.borrow();
-
-      if let Some(weight) = Slider::new(lod_weight, 0.0, 1.0)
-          .parent(self.ids.container)
-          .padded_w_of(self.ids.container, 25.0)
-          .color(if self.selected_widget == lod_index {
-              slider_focussed_color
-            } else {
-              slider_default_color
-            })
-          .label(&format!("Level-of-detail weight: {}", lod_weight))
-          .small_font(ui)
-          .set(self.ids.lod_slider, ui) {
-        *lod_weight_ref.borrow_mut() = weight;
-        self.selected_widget = lod_index;
+      for (i, label) in self.knob_labels.iter().enumerate() {
+        let knob_index = resume_index + 1 + i;
+        let knob_weight_ref = Rc::clone(&self.widgets[knob_index].weight);
+        let knob_weight = *knob_weight_ref.borrow();
+
+        if let Some(weight) = Slider::new(knob_weight, 0.0, 1.0)
+            .parent(self.ids.container)
+            .padded_w_of(self.ids.container, 25.0)
+            .color(if self.selected_widget == knob_index {
+                slider_focussed_color
+              } else {
+                slider_default_color
+              })
+            .label(&format!("{}: {}", label, knob_weight))
+            .small_font(ui)
+            .set(self.ids.knob_sliders[i], ui) {
+          *knob_weight_ref.borrow_mut() = weight;
+          self.selected_widget = knob_index;
+        }
       }
 
       let quit_index = *self.widget_order.get("Quit").unwrap();
@@ -25,6 +25,7 @@ use glium::glutin::Window;
 use glium::glutin::WindowEvent;
 use glium::glutin::KeyboardInput;
 use glium::glutin::ElementState;
+use glium::glutin::MouseButton;
 use glium::glutin::VirtualKeyCode;
 use std::f32;
 use webvr::VREvent;
@@ -39,7 +40,8 @@ pub struct InputHandler {
   grip_button_pressed: Vec<bool>,
   menu_button_pressed: Vec<bool>,
   trigger_button_pressed: Vec<bool>,
-
+  // in normalized device coordinates; while looking around, the cursor is kept in the centre
+  cursor_position: (f32, f32),
 }
 
 impl InputHandler {
@@ -58,6 +60,7 @@ impl InputHandler {
       grip_button_pressed: g,
       menu_button_pressed: m,
       trigger_button_pressed: t,
+      cursor_position: (0.0, 0.0),
     }
   }
 
@@ -68,7 +71,7 @@ impl InputHandler {
     let actions = {
       let mut actions = Vec::new();
       actions.push(gui.process_gui_action(gui_action, window, vr_mode));
-      actions.append(&mut self.process_gamepad_state(gamepads));
+      actions.append(&mut self.process_gamepad_state(gamepads, gui.is_visible));
       actions.append(&mut self.process_vr_events(vr));
       actions.append(&mut self.process_glutin_events(display, window, vr_mode, events_loop, gui));
       actions
@@ -81,7 +84,8 @@ impl InputHandler {
     result_actions
   }
 
-  fn process_gamepad_state(&mut self, gamepads: &Vec<VRGamepadPtr>) -> Vec<Action> {
+  fn process_gamepad_state(&mut self, gamepads: &Vec<VRGamepadPtr>, gui_is_visible: bool)
+      -> Vec<Action> {
     let mut actions = Vec::new();
 
     for (i, ref gamepad) in gamepads.iter().enumerate() {
@@ -108,7 +112,7 @@ impl InputHandler {
       } else if self.trigger_button_pressed[i] {
         self.trigger_button_pressed[i] = false;
         println!("trigger button clicked");
-        actions.push(Action::GuiActivateMenuItem);
+        actions.push(if gui_is_visible { Action::GuiActivateMenuItem } else { Action::PickGamepad(i) });
       }
 
       if state.axes[0] > 0.0 {
@@ -141,9 +145,10 @@ impl InputHandler {
     vec![]
   }
 
-  fn process_glutin_events(&self, display: &Display, window: &Window, vr_mode: bool,
+  fn process_glutin_events(&mut self, display: &Display, window: &Window, vr_mode: bool,
       events_loop: &mut EventsLoop, gui: &mut Gui) -> Vec<Action> {
     let mut actions = Vec::new();
+    let mut cursor_position = self.cursor_position;
 
     events_loop.poll_events(|event| {
       if let Some(event) = conrod::backend::winit::convert_event(event.clone(), display) {
@@ -179,6 +184,7 @@ impl InputHandler {
                 Some(VirtualKeyCode::F2)        => if key_is_pressed { actions.push(Action::StereoCross) },
                 Some(VirtualKeyCode::F3)        => if key_is_pressed { actions.push(Action::StereoAnaglyph) },
                 Some(VirtualKeyCode::B)         => if key_is_pressed { actions.push(Action::ToggleBoundingBox) },
+                Some(VirtualKeyCode::O)         => if key_is_pressed { actions.push(Action::ToggleOcclusionCulling) },
                 Some(VirtualKeyCode::Key1)      => if key_is_pressed { actions.push(Action::VisualizeOneD) },
                 Some(VirtualKeyCode::Key2)      => if key_is_pressed { actions.push(Action::VisualizeTwoD) },
                 Some(VirtualKeyCode::Key3)      => if key_is_pressed { actions.push(Action::VisualizeThreeD) },
@@ -192,20 +198,35 @@ impl InputHandler {
               },
             }
           },
+          // left picks at the centre while looking around, right at the cursor when it is free
+          WindowEvent::MouseInput { state: ElementState::Pressed, button, .. } => {
+            let is_picking = match button {
+              MouseButton::Left => !gui.is_visible,
+              MouseButton::Right => gui.is_visible,
+              _ => false,
+            };
+
+            if !vr_mode && is_picking {
+              actions.push(Action::PickCamera { x: cursor_position.0, y: cursor_position.1 });
+            }
+          },
           WindowEvent::CursorMoved { position, .. } => {
-            if !vr_mode && !gui.is_visible {
-              let (width, height) = window.get_inner_size().unwrap();
-              let origin_x = width as f64 / 4.0;
-              let origin_y = height as f64 / 4.0;
-              let rel_x = position.0 - origin_x;
-              let rel_y = position.1 - origin_y;
+            let (width, height) = window.get_inner_size().unwrap();
+            let origin_x = width as f64 / 4.0;
+            let origin_y = height as f64 / 4.0;
+            let rel_x = position.0 - origin_x;
+            let rel_y = position.1 - origin_y;
 
+            if !vr_mode && !gui.is_visible {
               actions.push(Action::CameraRotate {
                 pitch: -Rad(rel_y as f32 / 1000.0),
                 yaw: -Rad(rel_x as f32 / 1000.0),
               });
 
               window.set_cursor_position(origin_x as i32, origin_y as i32).unwrap();
+              cursor_position = (0.0, 0.0);
+            } else {
+              cursor_position = ((rel_x / origin_x) as f32, (-rel_y / origin_y) as f32);
             }
           },
           _ => (),
@@ -214,6 +235,7 @@ impl InputHandler {
       };
     });
 
+    self.cursor_position = cursor_position;
     actions
   }
 }
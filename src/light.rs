@@ -16,8 +16,192 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Copy, Clone, Default)]
+use cgmath::InnerSpace;
+use cgmath::Matrix4;
+use cgmath::SquareMatrix;
+use cgmath::Vector3;
+use cgmath::Zero;
+use glium::backend::Facade;
+use glium::uniforms::UniformBuffer;
+use std::cmp::Ordering;
+use std::f32;
+use std::mem;
+use std::path::Path;
+
+use bvh::Bvh;
+use cluster::Clusters;
+use error::EngynError;
+use object::Object;
+use quality::Quality;
+use resources::ResourceManager;
+use shadow::MAX_NUM_SHADOWS;
+use shadow::ShadowMaps;
+use uniforms::LIGHT_BLOCK_STRIDE;
+use uniforms::LightBlock;
+use uniforms::MAX_NUM_LIGHTS;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Copy, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum LightKind {
+  // infinitely far away, e.g. the sun; only the direction matters
+  Directional,
+  Point,
+  Spot,
+}
+
+// Point and spot lights fall off with the square of the distance, and smoothly reach zero at
+// their range if it is not zero. Spot lights are at full intensity inside the inner cone and fade
+// out towards the outer cone; the angles are in radians from the direction.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Copy, Clone)]
+#[serde(rename_all = "camelCase", default)]
 pub struct Light {
+  #[serde(rename = "type")] pub kind: LightKind,
   pub color: [f32; 3],
+  pub intensity: f32,
   pub position: [f32; 3],
+  pub direction: [f32; 3],
+  pub range: f32,
+  pub inner_cone_angle: f32,
+  pub outer_cone_angle: f32,
+}
+
+impl Default for Light {
+  fn default() -> Light {
+    Light {
+      kind: LightKind::Point,
+      color: [1.0, 1.0, 1.0],
+      intensity: 1.0,
+      position: [0.0, 0.0, 0.0],
+      direction: [0.0, -1.0, 0.0],
+      range: 0.0,
+      inner_cone_angle: 0.0,
+      outer_cone_angle: f32::consts::PI / 4.0,
+    }
+  }
+}
+
+impl Light {
+  // what the shader needs: the color scaled by the intensity and the cosines of the cone angles
+  pub fn get_radiance(&self) -> [f32; 3] {
+    [self.color[0] * self.intensity, self.color[1] * self.intensity,
+        self.color[2] * self.intensity]
+  }
+
+  pub fn get_cone_cosines(&self) -> (f32, f32) {
+    let outer = self.outer_cone_angle.max(0.0);
+    let inner = self.inner_cone_angle.max(0.0).min(outer);
+    (inner.cos(), outer.cos())
+  }
+}
+
+// the lights of the world and how to shade with them
+pub struct Lighting {
+  pub lights: Vec<Light>,
+  // the lights that are brightest at the camera are shaded, as many as the quality allows
+  pub num_considered_lights: usize,
+  // when disabled, materials that ask for physically based shading get Blinn-Phong instead
+  pub is_pbr_enabled: bool,
+  pub shadow_maps: ShadowMaps,
+  pub clusters: Clusters,
+
+  light_scale: f32,
+  light_buffer: UniformBuffer<LightBlock>,
+}
+
+impl Lighting {
+  pub fn new(display: &Facade, resource_manager: &ResourceManager)
+      -> Result<Lighting, EngynError> {
+    let light_buffer = UniformBuffer::empty_unsized(display,
+        MAX_NUM_LIGHTS * LIGHT_BLOCK_STRIDE * mem::size_of::<[f32; 4]>())
+        .map_err(|e| EngynError::resource(&Path::new("light_buffer"), &e.to_string()))?;
+
+    Ok(Lighting {
+      lights: Vec::new(),
+      num_considered_lights: 0,
+      is_pbr_enabled: true,
+      shadow_maps: ShadowMaps::new(display, resource_manager)?,
+      clusters: Clusters::new(display)?,
+      light_scale: 1.0,
+      light_buffer: light_buffer,
+    })
+  }
+
+  pub fn register_quality_knobs(quality: &mut Quality) {
+    quality.register_knob("Shading", "Shading weight", (0.0, 1.0), 0.2, 0.2);
+    quality.register_knob("Lights", "Light count weight", (0.0, 1.0), 0.3, 0.2);
+  }
+
+  pub fn set_lights(&mut self, lights: &[Light]) {
+    self.lights = lights.iter().take(MAX_NUM_LIGHTS).cloned().collect();
+  }
+
+  pub fn set_shading_scale(&mut self, scale: f32) {
+    self.is_pbr_enabled = scale >= 0.5;
+  }
+
+  // the fraction of the lights that is considered, but always at least one
+  pub fn set_light_scale(&mut self, scale: f32) {
+    self.light_scale = scale;
+  }
+
+  pub fn get_light_buffer(&self) -> &UniformBuffer<LightBlock> {
+    &self.light_buffer
+  }
+
+  pub fn render_shadows(&mut self, display: &Facade, world: &[Object], bvh: &Bvh) {
+    self.shadow_maps.render(display, world, bvh, &self.lights);
+  }
+
+  // writes the lights to the light buffer, with the shadow tiles of the last shadow pass
+  pub fn upload_lights(&mut self) {
+    let mut mapping = self.light_buffer.map();
+
+    for (i, light) in self.lights.iter().enumerate() {
+      let radiance = light.get_radiance();
+      let (cos_inner, cos_outer) = light.get_cone_cosines();
+      let kind = match light.kind {
+        LightKind::Directional => 0.0,
+        LightKind::Point => 1.0,
+        LightKind::Spot => 2.0,
+      };
+      let shadow_tile = if i < MAX_NUM_SHADOWS { self.shadow_maps.light_tiles[i] } else { -1 };
+      let offset = i * LIGHT_BLOCK_STRIDE;
+
+      mapping.light_data[offset] = [light.position[0], light.position[1], light.position[2], kind];
+      mapping.light_data[offset + 1] = [radiance[0], radiance[1], radiance[2], light.range];
+      mapping.light_data[offset + 2] = [light.direction[0], light.direction[1], light.direction[2],
+          shadow_tile as f32];
+      mapping.light_data[offset + 3] = [cos_inner, cos_outer, 0.0, 0.0];
+    }
+  }
+
+  // picks the lights to shade for this view and finds the clusters they reach
+  pub fn assign_clusters(&mut self, projection: Matrix4<f32>, view: Matrix4<f32>) {
+    let camera = view.invert().map_or(Vector3::zero(), |v| v.w.truncate());
+    let num_considered = usize::min(
+        usize::max((self.light_scale * self.lights.len() as f32).ceil() as usize, 1),
+        self.lights.len());
+
+    let lights = &self.lights;
+    let mut considered: Vec<usize> = (0..lights.len()).collect();
+    considered.sort_by(|&a, &b| {
+      get_importance(&lights[b], camera).partial_cmp(&get_importance(&lights[a], camera))
+          .unwrap_or(Ordering::Equal)
+    });
+    considered.truncate(num_considered);
+
+    self.num_considered_lights = considered.len();
+    self.clusters.assign(projection, view, lights, &considered);
+  }
+}
+
+// how bright a light is at the camera, so the ones that matter least are left out first
+fn get_importance(light: &Light, camera: Vector3<f32>) -> f32 {
+  if light.kind == LightKind::Directional { return f32::INFINITY; }
+
+  let radiance = light.get_radiance();
+  let brightness = f32::max(radiance[0], f32::max(radiance[1], radiance[2]));
+  let distance_squared = (Vector3::from(light.position) - camera).magnitude2();
+
+  brightness / f32::max(distance_squared, 1.0)
 }
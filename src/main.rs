@@ -34,10 +34,17 @@
 
 mod adaptive_canvas;
 mod benchmark;
+mod bvh;
 mod camera;
+mod cluster;
 mod conic;
+mod controller;
+mod cost_model;
 mod demo;
 mod drawable;
+mod error;
+mod frustum;
+mod fxaa;
 mod geometry;
 mod gui;
 mod input;
@@ -47,12 +54,18 @@ mod math;
 mod mesh;
 mod network_graph;
 mod object;
+mod occlusion;
 mod performance;
+mod picking;
+mod prediction;
 mod quality;
 mod resources;
 mod scene;
+mod shadow;
+mod simplify;
 mod teapot;
 mod uniforms;
+mod upscaler;
 
 use argparse::ArgumentParser;
 use argparse::List;
@@ -73,7 +86,6 @@ use glium::Depth;
 use glium::DepthTest;
 use glium::Display;
 use glium::DrawParameters;
-use glium::Rect;
 use glium::Surface;
 use glium::glutin::EventsLoop;
 use glium::glutin::MouseCursor;
@@ -94,6 +106,7 @@ use std::f32;
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::Path;
+use std::process;
 use std::rc::Rc;
 use webvr::VRDisplayPtr;
 use webvr::VRFramebufferAttributes;
@@ -101,29 +114,40 @@ use webvr::VRGamepadPtr;
 use webvr::VRServiceManager;
 
 use adaptive_canvas::AdaptiveCanvas;
+use adaptive_canvas::Foveation;
 use benchmark::Benchmark;
+use bvh::Bvh;
 use camera::FpsCamera;
 use conic::Conic;
+use controller::PidParameters;
+use controller::QualityController;
+use cost_model::CostModel;
 use demo::Demo;
 use demo::DemoEntry;
+use error::EngynError;
 use light::Light;
+use light::Lighting;
 use geometry::Geometry;
+use geometry::Tangent;
 use geometry::Texcoord;
 use gui::Action;
 use gui::Gui;
 use input::InputHandler;
+use material::AlphaMode;
 use material::Material;
+use material::MaterialMaps;
+use material::Shading;
 use mesh::Mesh;
 use network_graph::Network;
+use object::DrawStats;
 use object::Object;
+use occlusion::OcclusionCuller;
 use performance::FramePerformance;
+use picking::Ray;
 use quality::Quality;
 use resources::ResourceManager;
 use scene::Scene;
-
-fn calculate_num_objects(objects: &Vec<Object>) -> u32 {
-  objects.iter().fold(0, |acc, o| acc + 1 + calculate_num_objects(&o.children))
-}
+use shadow::ShadowMaps;
 
 fn update_camera(fps_camera: &mut FpsCamera, actions: &Vec<Action>) {
   fps_camera.process_actions(actions);
@@ -139,12 +163,82 @@ fn update_world(display: &Display, world: &mut Vec<Object>, gui: &mut Gui, actio
   }
 }
 
+fn create_quality(controller: Box<QualityController>, weights: &Vec<f32>,
+    cost_model: &Option<Rc<CostModel>>) -> Quality {
+  let mut quality = Quality::new(controller);
+
+  AdaptiveCanvas::register_quality_knobs(&mut quality);
+  Object::register_quality_knobs(&mut quality);
+  Lighting::register_quality_knobs(&mut quality);
+  ShadowMaps::register_quality_knobs(&mut quality);
+  OcclusionCuller::register_quality_knobs(&mut quality);
+
+  if !weights.is_empty() {
+    quality.set_weights(weights);
+  }
+
+  quality.cost_model = cost_model.clone();
+  quality
+}
+
 enum StereoMode {
   StereoNone,
   StereoCross,
   StereoAnaglyph,
 }
 
+fn record_canvas_counters(canvas: &AdaptiveCanvas, frame_performance: &mut FramePerformance) {
+  let eye_scales = canvas.get_eye_scales();
+  let (center_size, periphery_scale) = match canvas.get_foveation() {
+    Some(f) => (f.center_size, f.periphery_scale),
+    None => (1.0, 1.0),
+  };
+
+  frame_performance.set_counter("EyeScaleLeft", eye_scales[0] as f64);
+  frame_performance.set_counter("EyeScaleRight", eye_scales[1] as f64);
+  frame_performance.set_counter("FoveationCenter", center_size as f64);
+  frame_performance.set_counter("FoveationPeriphery", periphery_scale as f64);
+  frame_performance.set_counter("RenderedPixels", canvas.get_rendered_pixels() as f64);
+  frame_performance.set_counter("FXAA", if canvas.is_fxaa_enabled() { 1.0 } else { 0.0 });
+  frame_performance.set_counter("MSAALevel", canvas.get_msaa_level() as f64);
+  frame_performance.set_counter("Upscaling", if canvas.is_upscaling() { 1.0 } else { 0.0 });
+}
+
+fn get_standing_transform(vr_display: Option<&VRDisplayPtr>) -> Matrix4<f32> {
+  let display_data = match vr_display {
+    Some(d) => d.borrow().data(),
+    None => return Matrix4::<f32>::identity(),
+  };
+
+  if let Some(ref stage) = display_data.stage_parameters {
+    math::vec_to_matrix(&stage.sitting_to_standing_transform).inverse_transform().unwrap()
+  } else {
+    // Stage parameters not available yet or unsupported
+    // Assume 0.75m transform height
+    math::vec_to_translation(&[0.0, 0.75, 0.0]).inverse_transform().unwrap()
+  }
+}
+
+fn get_gamepad_pose(gamepad: &VRGamepadPtr) -> Matrix4<f32> {
+  let state = gamepad.borrow().state();
+  let rotation = match state.pose.orientation {
+    Some(o) => Matrix4::from(Quaternion::new(o[3], o[0], o[1], o[2])), // WebVR presents quaternions as (x, y, z, w)
+    None => Matrix4::<f32>::identity(),
+  };
+  let position = match state.pose.position {
+    Some(position) => Matrix4::from_translation(Vector3::from(position)),
+    None => Matrix4::<f32>::identity(),
+  };
+
+  position * rotation
+}
+
+// the projection of the camera over the whole window, which holds two eyes side by side
+fn get_mono_projection(render_dimensions: &(u32, u32)) -> Matrix4<f32> {
+  let aspect_ratio = render_dimensions.0 as f32 / render_dimensions.1 as f32;
+  cgmath::perspective(Deg(45.0), aspect_ratio * 2.0, 0.01f32, 1000.0)
+}
+
 fn draw_frame(
     target_lod: f32,
     vr_mode: bool,
@@ -154,9 +248,8 @@ fn draw_frame(
     window: &Window,
     render_params: &mut DrawParameters,
     world: &mut Vec<Object>,
-    num_objects: u32,
-    lights: &[Light; uniforms::MAX_NUM_LIGHTS],
-    num_lights: i32,
+    bvh: &Bvh,
+    lighting: &mut Lighting,
     empty: &mut Object,
     gamepads: &Vec<VRGamepadPtr>,
     gamepad_models: &mut Vec<Object>,
@@ -167,10 +260,13 @@ fn draw_frame(
     gui: &mut Gui,
     demo: &mut Option<Demo>,
     demo_record: bool,
-    show_bbox: bool) {
+    show_bbox: bool,
+    selection: Option<&[usize]>,
+    occlusion: &mut OcclusionCuller) {
 
   let aspect_ratio = render_dimensions.0 as f32 / render_dimensions.1 as f32;
-  let mono_projection = cgmath::perspective(Deg(45.0), aspect_ratio * 2.0, 0.01f32, 1000.0);
+  let pixel_error = Object::get_pixel_error(target_lod);
+  let mono_projection = get_mono_projection(render_dimensions);
   let stereo_projection = cgmath::perspective(Deg(45.0), aspect_ratio, 0.01f32, 1000.0);
 
   let (
@@ -183,15 +279,7 @@ fn draw_frame(
     vr_display.unwrap().borrow_mut().sync_poses();
     frame_performance.process_event("post_sync_poses");
 
-    let display_data = vr_display.unwrap().borrow().data();
-
-    let standing_transform = if let Some(ref stage) = display_data.stage_parameters {
-      math::vec_to_matrix(&stage.sitting_to_standing_transform).inverse_transform().unwrap()
-    } else {
-      // Stage parameters not available yet or unsupported
-      // Assume 0.75m transform height
-      math::vec_to_translation(&[0.0, 0.75, 0.0]).inverse_transform().unwrap()
-    };
+    let standing_transform = get_standing_transform(vr_display);
 
     frame_performance.process_event("pre_sync_frame_data");
     let frame_data = vr_display.unwrap().borrow().synced_frame_data(0.1, 1000.0);
@@ -222,6 +310,12 @@ fn draw_frame(
 
   let inverse_standing_transform = standing_transform.inverse_transform().unwrap();
 
+  // the gamepads are posed once per frame, for all eyes
+  for (i, ref gamepad) in gamepads.iter().enumerate() {
+    gamepad_models[i].transform = inverse_standing_transform * get_gamepad_pose(gamepad);
+    gamepad_models[i].update_bounds();
+  }
+
   frame_performance.process_event("pre_draw");
 
   // record demo entry
@@ -242,60 +336,90 @@ fn draw_frame(
   {
     let eyes = match stereo_mode {
       &StereoMode::StereoNone => vec![
-        (&canvas.viewport, &mono_projection, &left_view_matrix, (true, true, true, true)),
-      ],
-      &StereoMode::StereoCross => vec![
-        (&canvas.viewports[0], &left_projection_matrix, &left_view_matrix, (true, true, true, true)),
-        (&canvas.viewports[1], &right_projection_matrix, &right_view_matrix, (true, true, true, true)),
-      ],
-      &StereoMode::StereoAnaglyph => vec![
-        (&canvas.viewport, &mono_projection, &left_view_matrix, (true, false, false, true)),
-        (&canvas.viewport, &mono_projection, &right_view_matrix, (false, true, true, true)),
+        (canvas.viewport, canvas.get_jitter_matrix(&canvas.viewport) * mono_projection,
+            left_view_matrix, (true, true, true, true), 0),
       ],
+      &StereoMode::StereoCross => canvas.regions.iter().map(|region| {
+        let (projection, view) = if region.eye == 0 {
+          (left_projection_matrix, left_view_matrix)
+        } else {
+          (right_projection_matrix, right_view_matrix)
+        };
+
+        let jitter = canvas.get_jitter_matrix(&region.output);
+
+        (region.viewport, jitter * math::crop_projection(region.window) * projection, view,
+            (true, true, true, true), region.eye)
+      }).collect(),
+      &StereoMode::StereoAnaglyph => {
+        let projection = canvas.get_jitter_matrix(&canvas.viewport) * mono_projection;
+
+        vec![
+          (canvas.viewport, projection, left_view_matrix, (true, false, false, true), 0),
+          (canvas.viewport, projection, right_view_matrix, (false, true, true, true), 1),
+        ]
+      },
     };
 
     let is_anaglyph = if let &StereoMode::StereoAnaglyph = stereo_mode { true } else { false };
 
-    let mut framebuffer = canvas.get_framebuffer(display).unwrap();
-    framebuffer.clear_color(0.4, 0.4, 0.4, 1.0);
+    {
+      let mut framebuffer = canvas.get_framebuffer(display).unwrap();
+      framebuffer.clear_color(0.4, 0.4, 0.4, 1.0);
 
-    for (eye_i, eye) in eyes.iter().enumerate() {
-      framebuffer.clear_depth(1.0);
+      for (view_index, eye) in eyes.iter().enumerate() {
+        framebuffer.clear_depth(1.0);
 
-      let projection = math::matrix_to_uniform(*eye.1);
-      let view = math::matrix_to_uniform(eye.2 * standing_transform);
-      let viewport = *eye.0;
+        let projection = math::matrix_to_uniform(eye.1);
+        let view = math::matrix_to_uniform(eye.2 * standing_transform);
+        let viewport = eye.0;
+        let eye_i = eye.4;
 
-      render_params.color_mask = eye.3;
-      render_params.viewport = Some(viewport);
+        render_params.color_mask = eye.3;
+        render_params.viewport = Some(viewport);
 
-      let mut i = 0;
-      for object in world.iter_mut() {
-        if target_lod > (i as f32 / num_objects as f32) {
-          i = object.draw(target_lod, i, num_objects, &mut framebuffer, display, projection, view, &render_params, num_lights, lights, eye_i, is_anaglyph, show_bbox);
+        lighting.assign_clusters(eye.1, eye.2 * standing_transform);
+        frame_performance.add_counter("ClusterLightIndices", lighting.clusters.num_indices as f64);
+
+        let mut stats = DrawStats::default();
+
+        // these are opaque, so they go before the world, which draws its transparent objects last
+        for gamepad_model in gamepad_models.iter_mut().take(gamepads.len()) {
+          gamepad_model.draw(0.0, &mut framebuffer, display, projection, view, &render_params, lighting, eye_i, is_anaglyph, show_bbox, &mut stats);
         }
+
+        empty.draw(0.0, &mut framebuffer, display, projection, view, &render_params, lighting, eye_i, is_anaglyph, show_bbox, &mut stats);
+
+        Object::draw_world(world, bvh, pixel_error, &mut framebuffer, display, projection, view, &render_params, lighting, eye_i, is_anaglyph, show_bbox, selection, occlusion, view_index, &mut stats);
+
+        let eye_name = if eye_i == 0 { "Left" } else { "Right" };
+        frame_performance.add_counter(&format!("DrawnObjects{}", eye_name), stats.drawn_objects as f64);
+        frame_performance.add_counter(&format!("DrawnTriangles{}", eye_name), stats.drawn_triangles as f64);
+        frame_performance.add_counter(&format!("CulledObjects{}", eye_name), stats.culled_objects as f64);
+        frame_performance.add_counter(&format!("CulledTriangles{}", eye_name), stats.culled_triangles as f64);
+        frame_performance.add_counter(&format!("OccludedObjects{}", eye_name), stats.occluded_objects as f64);
+        frame_performance.add_counter(&format!("OccludedTriangles{}", eye_name), stats.occluded_triangles as f64);
+        frame_performance.add_counter(&format!("BlendedObjects{}", eye_name), stats.blended_objects as f64);
+        frame_performance.add_counter("OcclusionQueries", stats.occlusion_queries as f64);
+        // only the time to issue the queries; the GPU time is part of drawing
+        frame_performance.add_counter("OcclusionCpuTime", stats.occlusion_cpu_nanos as f64);
       }
+    }
 
-      for (i, ref gamepad) in gamepads.iter().enumerate() {
-        let state = gamepad.borrow().state();
-        let rotation = match state.pose.orientation {
-          Some(o) => Matrix4::from(Quaternion::new(o[3], o[0], o[1], o[2])), // WebVR presents quaternions as (x, y, z, w)
-          None => Matrix4::<f32>::identity(),
-        };
-        let position = match state.pose.position {
-          Some(position) => Matrix4::from_translation(Vector3::from(position)),
-          None => Matrix4::<f32>::identity(),
-        };
+    canvas.resolve(display);
 
-        gamepad_models[i].transform = inverse_standing_transform * position * rotation;
-        gamepad_models[i].draw(1.0, 0, 1, &mut framebuffer, display, projection, view, &render_params, num_lights, lights, eye_i, is_anaglyph, show_bbox);
-      }
+    let is_stereo = if let &StereoMode::StereoCross = stereo_mode { true } else { false };
 
-      empty.draw(1.0, 0, 1, &mut framebuffer, display, projection, view, &render_params, num_lights, lights, eye_i, is_anaglyph, show_bbox);
+    canvas.post_process(display, is_stereo);
 
-      canvas.resolve(display);
+    if is_stereo {
+      canvas.composite(display);
+    }
 
-      gui.draw(&mut canvas.get_resolved_framebuffer(display).unwrap(), *eye.0);
+    canvas.upscale(display, is_stereo);
+
+    for viewport in canvas.get_output_viewports(is_stereo) {
+      gui.draw(&mut canvas.get_output_framebuffer(display).unwrap(), viewport);
     }
 
     if vr_mode {
@@ -307,12 +431,7 @@ fn draw_frame(
 
     let target = display.draw();
 
-    let src_rect = Rect {
-      left: 0,
-      bottom: 0,
-      width: canvas.viewport.width,
-      height: canvas.viewport.height,
-    };
+    let src_rect = canvas.get_output_viewport();
 
     let (width, height) = window.get_inner_size().unwrap();
 
@@ -323,7 +442,7 @@ fn draw_frame(
       height: height as i32,
     };
 
-    let mut framebuffer = canvas.get_resolved_framebuffer(display).unwrap();
+    let framebuffer = canvas.get_output_framebuffer(display).unwrap();
 
     framebuffer.blit_color(&src_rect, &target, &blit_target, MagnifySamplerFilter::Linear);
 
@@ -341,6 +460,13 @@ fn draw_frame(
 }
 
 fn main() {
+  if let Err(e) = run() {
+    eprintln!("Error: {}", e);
+    process::exit(1);
+  }
+}
+
+fn run() -> Result<(), EngynError> {
   let mut open_filename = "".to_string();
   let mut save_filename = "".to_string();
   let mut perf_filename = "".to_string();
@@ -352,6 +478,21 @@ fn main() {
   let mut baseline = false;
   let mut visualize_perf = false;
   let mut num_samples = 10;
+  let mut controller_names = Vec::<String>::new();
+  let mut pid_parameters = PidParameters::default();
+  let mut pid_gains = Vec::<f32>::new();
+  let mut predictor_name = prediction::PREDICTOR_NAMES[0].to_owned();
+  let mut cost_model_filename = "".to_string();
+  let mut fit_cost_model_filename = "".to_string();
+  let mut cost_model_samples = Vec::<String>::new();
+  let mut eye_scales = Vec::<f32>::new();
+  let mut foveation = Vec::<f32>::new();
+  let mut occlusion_culling = false;
+  let mut shadows = false;
+  let mut mesh_data_budget = 256usize;
+  let mut lod_cache = false;
+  let mut validate = false;
+  let mut verbose = false;
 
   {
     let mut ap = ArgumentParser::new();
@@ -361,7 +502,8 @@ fn main() {
     ap.refer(&mut open_filename)
       .add_option(&["-o", "--open"], Store, "open scene from .yml file");
     ap.refer(&mut save_filename)
-      .add_option(&["-s", "--save"], Store, "save scene to .yml file");
+      .add_option(&["-s", "--save"], Store, "save scene to .yml file, upgrading the scene given \
+          with --open to the current version");
     ap.refer(&mut perf_filename)
       .add_option(&["-p", "--perf"], Store, "performance measurements");
     ap.refer(&mut baseline)
@@ -375,26 +517,221 @@ fn main() {
     ap.refer(&mut demo_record)
       .add_option(&["-r", "--record"], StoreTrue, "set this to record demo instead of playback");
     ap.refer(&mut weights)
-      .add_option(&["--weights"], List, "quality weights");
+      .add_option(&["--weights"], List, "quality weights, in the order of the knobs in the GUI; \
+          knobs without one keep their default");
     ap.refer(&mut enable_supersampling)
       .add_option(&["--no-supersampling"], StoreFalse, "limit maximum resolution to monitor \
           resolution");
     ap.refer(&mut num_samples)
       .add_option(&["--samples"], Store, "number of samples to record for each frame");
+    ap.refer(&mut controller_names)
+      .add_option(&["--controller"], List, &format!("quality controller(s) to use, benchmarks \
+          run each one in turn: {}", controller::CONTROLLER_NAMES.join(", ")));
+    ap.refer(&mut pid_gains)
+      .add_option(&["--pid-gains"], List, "gains of the pid controller (p, i, d)");
+    ap.refer(&mut pid_parameters.setpoint)
+      .add_option(&["--pid-setpoint"], Store, "fraction of the frame time the pid controller \
+          tries to keep unused");
+    ap.refer(&mut pid_parameters.derivative_filter)
+      .add_option(&["--pid-filter"], Store, "smoothing factor of the pid controller's \
+          derivative term (1.0 is unfiltered)");
+    ap.refer(&mut predictor_name)
+      .add_option(&["--predictor"], Store, &format!("frame time predictor to use: {}",
+          prediction::PREDICTOR_NAMES.join(", ")));
+    ap.refer(&mut cost_model_filename)
+      .add_option(&["--cost-model"], Store, "choose quality levels using a cost model from .yml \
+          file, instead of the quality weights");
+    ap.refer(&mut fit_cost_model_filename)
+      .add_option(&["--fit-cost-model"], Store, "fit a cost model to the --cost-samples and save \
+          it to .yml file");
+    ap.refer(&mut cost_model_samples)
+      .add_option(&["--cost-samples"], List, "benchmark .csv files to fit the cost model to");
+    ap.refer(&mut eye_scales)
+      .add_option(&["--eye-scales"], List, "resolution scale of each eye relative to the \
+          adaptive resolution (left, right)");
+    ap.refer(&mut foveation)
+      .add_option(&["--foveation"], List, "render the centre of each eye separately from the \
+          periphery (centre size, periphery scale)");
+    ap.refer(&mut occlusion_culling)
+      .add_option(&["--occlusion-culling"], StoreTrue, "skip objects that were hidden behind \
+          others in the previous frame (toggle with O)");
+    ap.refer(&mut shadows)
+      .add_option(&["--shadows"], StoreTrue, "cast shadows from the first lights of the scene");
+    ap.refer(&mut mesh_data_budget)
+      .add_option(&["--mesh-data-budget"], Store, "megabytes of mesh data to keep on the CPU for \
+          picking and statistics");
+    ap.refer(&mut lod_cache)
+      .add_option(&["--lod-cache"], StoreTrue, "keep the LOD levels of .obj files in a .obj.lod \
+          file next to them, so they load faster the next time");
+    ap.refer(&mut validate)
+      .add_option(&["--validate"], StoreTrue, "check the scene given with --open for problems, \
+          without opening a window");
+    ap.refer(&mut verbose)
+      .add_option(&["-v", "--verbose"], StoreTrue, "print statistics of the meshes that are \
+          loaded");
 
     ap.parse_args_or_exit();
   }
 
+  if !pid_gains.is_empty() {
+    if pid_gains.len() != 3 {
+      eprintln!("Invalid --pid-gains: expected 3 gains (p, i, d), got {}", pid_gains.len());
+      process::exit(1);
+    }
+
+    if pid_gains.iter().any(|gain| !(*gain >= 0.0) || !gain.is_finite()) {
+      eprintln!("Invalid --pid-gains: gains must be finite and not negative");
+      process::exit(1);
+    }
+
+    pid_parameters.gain_p = pid_gains[0];
+    pid_parameters.gain_i = pid_gains[1];
+    pid_parameters.gain_d = pid_gains[2];
+  }
+
+  let scale_options = [
+    ("--eye-scales", &eye_scales, "left, right"),
+    ("--foveation", &foveation, "centre size, periphery scale"),
+  ];
+
+  for &(option, values, names) in &scale_options {
+    if values.is_empty() {
+      continue;
+    }
+
+    if values.len() != 2 {
+      eprintln!("Invalid {}: expected 2 values ({}), got {}", option, names, values.len());
+      process::exit(1);
+    }
+
+    // also rejects NaN and infinity
+    if values.iter().any(|value| !(*value > 0.0 && *value <= 1.0)) {
+      eprintln!("Invalid {}: values must be greater than 0 and at most 1", option);
+      process::exit(1);
+    }
+  }
+
+  for name in &controller_names {
+    if controller::from_name(name, &pid_parameters).is_none() {
+      eprintln!("Unknown quality controller: {} (available: {})", name,
+          controller::CONTROLLER_NAMES.join(", "));
+      process::exit(1);
+    }
+  }
+
+  if !weights.is_empty() {
+    let controller = controller::from_name(controller::CONTROLLER_NAMES[0], &pid_parameters);
+    let quality = create_quality(controller.unwrap(), &Vec::new(), &None);
+
+    match quality.complete_weights(&weights) {
+      Ok(completed) => {
+        if weights.len() < completed.len() {
+          eprintln!("Warning: {} weights given for {} quality knobs, so {} keep their default \
+              weights", weights.len(), completed.len(),
+              quality.get_knob_names()[weights.len()..].join(", "));
+        }
+
+        weights = completed;
+      },
+      Err(e) => {
+        eprintln!("Invalid --weights: {}", e);
+        process::exit(1);
+      },
+    }
+  }
+
+  let scene = if open_filename != "" {
+    Some(Scene::from_yaml(&open_filename)?)
+  } else {
+    None
+  };
+
+  if validate {
+    let problems = match scene {
+      Some(ref scene) => scene.validate(),
+      None => {
+        eprintln!("Nothing to validate, use --open to give a scene file");
+        process::exit(1);
+      },
+    };
+
+    for problem in &problems {
+      eprintln!("{}: {}", open_filename, problem);
+    }
+
+    if !problems.is_empty() {
+      eprintln!("{}: {} problem{} found", open_filename, problems.len(),
+          if problems.len() == 1 { "" } else { "s" });
+      process::exit(1);
+    }
+
+    println!("{}: no problems found", open_filename);
+    return Ok(());
+  }
+
+  if fit_cost_model_filename != "" {
+    let paths: Vec<&Path> = cost_model_samples.iter().map(|s| Path::new(s)).collect();
+
+    let controller = controller::from_name(controller::CONTROLLER_NAMES[0], &pid_parameters);
+    let knob_names = create_quality(controller.unwrap(), &weights, &None).get_knob_names();
+    let knob_names: Vec<&str> = knob_names.iter().map(|n| &n[..]).collect();
+
+    match CostModel::fit(&paths, &knob_names) {
+      Ok(model) => {
+        println!("Fitted cost model to {} samples, RMS error {} ns", model.num_samples,
+            model.rms_error);
+        model.to_yaml(&fit_cost_model_filename)?;
+        return Ok(());
+      },
+      Err(e) => {
+        eprintln!("Could not fit cost model: {}", e);
+        process::exit(1);
+      },
+    }
+  }
+
+  let cost_model = if cost_model_filename != "" {
+    let cost_model = CostModel::from_yaml(&cost_model_filename)?;
+    let controller = controller::from_name(controller::CONTROLLER_NAMES[0], &pid_parameters);
+    let knob_names = create_quality(controller.unwrap(), &Vec::new(), &None).get_knob_names();
+
+    // a knob that was renamed would otherwise silently fall back to its cost hint
+    for knob in cost_model.knobs.iter().filter(|k| !knob_names.contains(k)) {
+      eprintln!("Warning: {}: unknown quality knob {} is ignored", cost_model_filename, knob);
+    }
+
+    for knob in knob_names.iter().filter(|k| !cost_model.knobs.contains(k)) {
+      eprintln!("Warning: {}: quality knob {} is missing, its cost hint is used instead",
+          cost_model_filename, knob);
+    }
+
+    Some(Rc::new(cost_model))
+  } else {
+    None
+  };
+
+  let predictor = match prediction::from_name(&predictor_name) {
+    Some(predictor) => predictor,
+    None => {
+      eprintln!("Unknown frame time predictor: {} (available: {})", predictor_name,
+          prediction::PREDICTOR_NAMES.join(", "));
+      process::exit(1);
+    },
+  };
+
+  // an opened scene is saved in the current version of the format
   if save_filename != "" {
-    let scene = Scene::new();
-    scene.to_yaml(&save_filename).unwrap();
+    match scene {
+      Some(ref scene) => scene.to_yaml(&save_filename)?,
+      None => Scene::new().to_yaml(&save_filename)?,
+    }
   }
 
   let mut demo = if demo_record {
     println!("Recording demo {}", demo_filename);
     Some(Demo::new())
   } else if demo_filename != "" {
-    let demo = Demo::from_bincode(&demo_filename).unwrap();
+    let demo = Demo::from_bincode(&demo_filename)?;
     println!("Playing back demo {} ({} frames)", demo_filename, demo.entries.len());
     Some(demo)
   } else {
@@ -435,7 +772,10 @@ fn main() {
     },
   };
 
-  let resource_manager = ResourceManager::new(&display);
+  let mut resource_manager = ResourceManager::new(&display);
+  resource_manager.mesh_data_budget = mesh_data_budget * 1024 * 1024;
+  resource_manager.verbose = verbose;
+  resource_manager.use_lod_cache = lod_cache;
 
   if !vr_mode {
     let (width, height) = window.get_inner_size().unwrap();
@@ -447,13 +787,21 @@ fn main() {
   }
 
   let marble_material = Rc::new(RefCell::new(Material {
-    albedo_map: resource_manager.get_texture(&Path::new("data/marble.jpg")).unwrap(),
+    name: "marble".to_owned(),
+    albedo_map: resource_manager.get_texture(&Path::new("data/marble.jpg"))?,
     ambient_color: [0.0, 0.0, 0.0],
     diffuse_color: [0.0, 0.0, 0.0],
     specular_color: [1.0, 1.0, 1.0],
     shininess: 1.0,
     metalness: 0.0,
     reflectivity: 0.0,
+    roughness: 1.0,
+    shading: Shading::BlinnPhong,
+    emissive_color: [0.0, 0.0, 0.0],
+    dissolve: 1.0,
+    alpha_mode: AlphaMode::Opaque,
+    alpha_cutoff: 0.5,
+    maps: MaterialMaps::default(),
   }));
 
   let canvas_dimensions = if enable_supersampling {
@@ -464,31 +812,47 @@ fn main() {
 
   let mut canvas = AdaptiveCanvas::new(&display, canvas_dimensions.0, canvas_dimensions.1, 3);
 
+  if !eye_scales.is_empty() {
+    canvas.set_eye_scales([eye_scales[0], eye_scales[1]]);
+  }
+
+  if !foveation.is_empty() {
+    canvas.set_foveation(Some(Foveation {
+      center_size: foveation[0],
+      periphery_scale: foveation[1],
+    }));
+  }
+
   let mut world = Vec::new();
-  let mut num_lights = 0;
-  let mut lights: [Light; uniforms::MAX_NUM_LIGHTS] = Default::default();
+  let mut lighting = Lighting::new(&display, &resource_manager)?;
+  lighting.shadow_maps.enabled = shadows;
 
   if visualize_perf && perf_filename != "" {
-    world.push(Benchmark::from_file(&display, &Path::new(&perf_filename)).as_object());
-  } else if open_filename != "" {
-    let scene = Scene::from_yaml(&open_filename).unwrap();
-    world.push(scene.as_object(&display, &resource_manager));
-
-    for (i, light) in scene.lights.iter().enumerate() {
-      if i < uniforms::MAX_NUM_LIGHTS {
-        lights[i] = *light;
+    world.push(Benchmark::from_file(&display, &Path::new(&perf_filename))?.as_object());
+  } else if let Some(ref scene) = scene {
+    world.push(scene.as_object(&display, &resource_manager)?);
+
+    lighting.set_lights(&scene.lights);
+
+    // the command line takes precedence over the scene file
+    if controller_names.is_empty() {
+      if let Some(ref name) = scene.quality_controller {
+        if controller::from_name(name, &pid_parameters).is_none() {
+          eprintln!("Unknown quality controller in scene: {}", name);
+          process::exit(1);
+        }
+
+        controller_names.push(name.to_owned());
       }
     }
-
-    num_lights = usize::min(scene.lights.len(), uniforms::MAX_NUM_LIGHTS) as i32;
   } else {
     // a triangle
     world.push(Object::new_triangle(&display, &resource_manager, Rc::clone(&marble_material),
-        [1.0, 1.0], [0.0, 0.0, 0.0], [0.0, 0.0, 0.0], [1.0, 1.0, 1.0]));
+        [1.0, 1.0], [0.0, 0.0, 0.0], [0.0, 0.0, 0.0], [1.0, 1.0, 1.0])?);
 
     // a terrain mesh
     let mut terrain = Object::from_file(&display, &resource_manager,
-        &Path::new("data/terrain.obj"));
+        &Path::new("data/terrain.obj"))?;
     terrain.transform = Matrix4::identity();
     world.push(terrain);
 
@@ -528,22 +892,26 @@ fn main() {
           &display,
           Rc::new(RefCell::new(Geometry {
             bounding_box: my_teapot_bounding_box,
+            mesh_data: None,
             indices: Some(IndexBuffer::new(
                 &display,
                 PrimitiveType::TrianglesList,
                 &teapot::INDICES).unwrap()),
             normals: VertexBuffer::new(&display, &teapot::NORMALS).unwrap(),
+            tangents: VertexBuffer::new(&display, &[Tangent { tangent: (1.0, 0.0, 0.0, 1.0) }; 531])
+                .unwrap(),
             vertices: VertexBuffer::new(&display, &teapot::VERTICES).unwrap(),
             texcoords: VertexBuffer::new(&display, &my_teapot_texcoords).unwrap(),
           })),
           Rc::clone(&marble_material),
-          &resource_manager))),
+          &resource_manager)?)),
       transform: Matrix4::new(
           0.005, 0.0, 0.0, 0.0,
           0.0, 0.005, 0.0, 0.0,
           0.0, 0.0, 0.005, 0.0,
           0.0, 1.0, 0.0, 1.0),
       size: (0..3).map(|i| (my_teapot_bounding_box.1[i] - my_teapot_bounding_box.0[i]).powi(2)).sum(),
+      bounds: None,
     };
 
     world.push(my_teapot);
@@ -557,6 +925,7 @@ fn main() {
             0.0, 0.0, 1.0, 0.0,
             0.0, 1.0, -1.0, 1.0),
         size: 0.0,
+        bounds: None,
     };
 
     world.push(my_conic);
@@ -570,32 +939,60 @@ fn main() {
             0.0, 0.0, 1.0, 0.0,
             0.0, 1.0, 1.0, 1.0),
         size: 0.0,
+        bounds: None,
     };
 
     world.push(my_network);
 
     // add a light
 
-    num_lights = 1;
-    lights[0] = Light { color: [1.0, 0.9, 0.9], position: [10.0, 10.0, 10.0] };
-    // lights[1] = Light { color: [0.9, 1.0, 0.9], position: [10.0, 10.0, -10.0] };
-    // lights[2] = Light { color: [0.9, 0.9, 1.0], position: [-10.0, 10.0, -10.0] };
-    // lights[3] = Light { color: [1.0, 1.0, 1.0], position: [-10.0, 10.0, 10.0] };
+    lighting.set_lights(&[
+      Light {
+        color: [1.0, 0.9, 0.9],
+        intensity: 300.0,
+        position: [10.0, 10.0, 10.0],
+        .. Default::default()
+      },
+      // Light { color: [0.9, 1.0, 0.9], position: [10.0, 10.0, -10.0], .. Default::default() },
+      // Light { color: [0.9, 0.9, 1.0], position: [-10.0, 10.0, -10.0], .. Default::default() },
+      // Light { color: [1.0, 1.0, 1.0], position: [-10.0, 10.0, 10.0], .. Default::default() },
+    ]);
+  }
+
+  for object in world.iter_mut() {
+    object.update_bounds();
+  }
+
+  let mut bvh = Bvh::build(Object::collect_bvh_items(&world));
+
+  if verbose {
+    println!("Kept {} of {} bytes of mesh data on the CPU", resource_manager.get_mesh_data_size(),
+        resource_manager.mesh_data_budget);
   }
 
-  let num_objects = calculate_num_objects(&world);
+  if controller_names.is_empty() {
+    controller_names.push(controller::CONTROLLER_NAMES[0].to_owned());
+  }
 
   // empty texture to force glutin clean
   let mut empty = Object::new_plane(&display, &resource_manager, Rc::new(RefCell::new(Material {
-        albedo_map: resource_manager.get_texture(&Path::new("data/empty.bmp")).unwrap(),
+        name: String::new(),
+        albedo_map: resource_manager.get_texture(&Path::new("data/empty.bmp"))?,
         ambient_color: [0.0, 0.0, 0.0],
         diffuse_color: [0.0, 0.0, 0.0],
         specular_color: [0.0, 0.0, 0.0],
         shininess: 0.0,
         metalness: 0.0,
         reflectivity: 0.0,
+        roughness: 1.0,
+        shading: Shading::BlinnPhong,
+        emissive_color: [0.0, 0.0, 0.0],
+        dissolve: 1.0,
+        alpha_mode: AlphaMode::Opaque,
+        alpha_cutoff: 0.5,
+        maps: MaterialMaps::default(),
       })),
-      [0.0001,0.0001], [-0.1, 0.1, 0.0], [0.0, 0.0, 0.0], [-1.0,1.0,1.0]);
+      [0.0001,0.0001], [-0.1, 0.1, 0.0], [0.0, 0.0, 0.0], [-1.0,1.0,1.0])?;
 
   let mut render_params = DrawParameters {
     depth: Depth { test: DepthTest::IfLess, write: true, .. Default::default() },
@@ -615,24 +1012,25 @@ fn main() {
   for _ in &gamepads {
     println!("We've found a gamepad!");
     let gamepad_model_path = Path::new("data/vive-controller.obj");
-    let gamepad_model = Object::from_file(&display, &resource_manager, &gamepad_model_path);
+    let gamepad_model = Object::from_file(&display, &resource_manager, &gamepad_model_path)?;
     gamepad_models.push(gamepad_model);
   }
 
   let mut input_handler = InputHandler::new(gamepads.len());
-  let mut quality = Quality::new(if weights.len() >= 3 {
-    (weights[0], weights[1], weights[2])
-  } else {
-    (0.5, 0.1, 1.0)
-  });
-  let mut gui = Gui::new(&display, Rc::clone(&quality.weight_resolution),
-      Rc::clone(&quality.weight_msaa), Rc::clone(&quality.weight_lod));
-  let mut frame_performance = FramePerformance::new(vr_mode);
+  let mut quality = create_quality(
+      controller::from_name(&controller_names[0], &pid_parameters).unwrap(), &weights,
+      &cost_model);
+  let mut gui = Gui::new(&display, &quality.knobs);
+  let mut frame_performance = FramePerformance::new(vr_mode, predictor);
 
   let num_configurations = 50;
 
   let mut stereo_mode = StereoMode::StereoCross;
   let mut show_bbox = false;
+  // the path of the object that was picked last, which is drawn with its bounding box
+  let mut selection: Option<Vec<usize>> = None;
+  let mut occlusion = OcclusionCuller::new(&display);
+  occlusion.enabled = occlusion_culling;
 
   if let Some(d) = vr_display {
     d.borrow_mut().start_present(Some(VRFramebufferAttributes {
@@ -642,8 +1040,10 @@ fn main() {
     }));
   }
 
-  let configurations = if baseline && weights.len() == 3 {
-    vec![("none", (weights[0], weights[1], weights[2]))]
+  let num_knobs = quality.knobs.len();
+
+  let configurations = if baseline && weights.len() == num_knobs {
+    vec![("none".to_owned(), weights.clone())]
   } else if benchmarking {
     let mut c = Vec::new();
     let seed = [
@@ -657,137 +1057,193 @@ fn main() {
     let mut rng = Hc128Rng::from_seed(seed);
 
     for _ in 0..num_configurations {
-      if weights.len() == 3 {
+      if weights.len() == num_knobs {
         // if we set some fixed weights on the command line, we want to benchmark using those
-        let mut configuration = rng.gen::<(f32, f32, f32)>();
-        if weights[0] >= 0.0 && weights[0] <= 1.0 {
-          configuration.0 = weights[0];
-        }
+        let mut configuration: Vec<f32> = (0..num_knobs).map(|_| rng.gen::<f32>()).collect();
 
-        if weights[1] >= 0.0 && weights[1] <= 1.0 {
-          configuration.1 = weights[1];
-        }
-
-        if weights[2] >= 0.0 && weights[2] <= 1.0 {
-          configuration.2 = weights[2];
+        for i in 0..num_knobs {
+          if weights[i] >= 0.0 && weights[i] <= 1.0 {
+            configuration[i] = weights[i];
+          }
         }
 
-        c.push(("custom", configuration));
+        c.push(("custom".to_owned(), configuration));
       } else {
-        // we use a random weight 3 times, once for each feature
+        // we use a random weight once for each knob, dividing the rest over the other knobs
         let random_weight = rng.gen::<f32>();
-        let other_weight = (1.0 - random_weight) / 2.0;
-        let mut configurations = vec![
-          ("resolution", (random_weight, other_weight, other_weight)),
-          ("msaa", (other_weight, random_weight, other_weight)),
-          ("lod", (other_weight, other_weight, random_weight)),
-        ];
-        c.append(&mut configurations);
+        let other_weight = (1.0 - random_weight) / (num_knobs - 1) as f32;
+
+        for (i, knob) in quality.knobs.iter().enumerate() {
+          let mut configuration = vec![other_weight; num_knobs];
+          configuration[i] = random_weight;
+          c.push((knob.name.to_lowercase(), configuration));
+        }
       }
     }
 
     c
   } else {
-    vec![("none", (0.0, 0.0, 0.0))]
+    vec![("none".to_owned(), vec![0.0; num_knobs])]
   };
 
-  println!("Configurations:");
-  for c in &configurations {
-    println!("{} {} {} {}", c.0, (c.1).0, (c.1).1, (c.1).2);
+  // only benchmarks compare controllers, an interactive session uses the first one
+  if !benchmarking {
+    controller_names.truncate(1);
   }
 
+  println!("Configurations:");
   for c in &configurations {
-    for sample_number in 0..if benchmarking { num_samples } else { 1 } {
-      println!("Running sample {}", sample_number);
-      frame_performance.reset_frame_count();
-
-      if benchmarking {
-        quality = Quality::new((*c).1);
-      }
-
-      'main: loop {
-        let (target_resolution, target_msaa, target_lod) = if baseline {
-          if benchmarking {
-            ((c.1).0, (c.1).1, (c.1).2)
-          } else if weights.len() >= 3 {
-            (weights[0], weights[1], weights[2])
-          } else {
-            (
-                *quality.weight_resolution.borrow(),
-                *quality.weight_msaa.borrow(),
-                *quality.weight_lod.borrow()
-            )
-          }
-        } else {
-          quality.set_level(&frame_performance, vr_display);
-          let targets = quality.get_target_levels();
-          (targets.0, targets.1, targets.2)
-        };
-
-        canvas.set_resolution_scale(target_resolution);
-        canvas.set_msaa_scale(target_msaa);
+    println!("{} {}", c.0, c.1.iter().join(" "));
+  }
 
-        frame_performance.start_frame(&quality);
-        frame_performance.process_event("frame_start");
-        frame_performance.process_event("pre_input");
+  for controller_name in &controller_names {
+    println!("Using quality controller {}", controller_name);
 
-        // prepare GUI and handle its actions
-        let gui_action = gui.prepare(*quality.level.borrow());
+    for c in &configurations {
+      for sample_number in 0..if benchmarking { num_samples } else { 1 } {
+        println!("Running sample {}", sample_number);
+        frame_performance.reset_frame_count();
 
-        // get input and handle its actions
-        let input_actions = input_handler.process(&gui_action, &gamepads, &mut vr, &display, &window,
-            vr_mode, &mut events_loop, &mut gui);
+        if benchmarking {
+          quality = create_quality(
+              controller::from_name(controller_name, &pid_parameters).unwrap(), &c.1,
+              &cost_model);
+        }
 
-        for action in &input_actions {
-          match action {
-            &Action::Quit => break 'main,
-            &Action::StereoNone => stereo_mode = StereoMode::StereoNone,
-            &Action::StereoCross => stereo_mode = StereoMode::StereoCross,
-            &Action::StereoAnaglyph => stereo_mode = StereoMode::StereoAnaglyph,
-            &Action::ToggleBoundingBox => show_bbox = !show_bbox,
-            _ => (),
+        'main: loop {
+          let targets = if baseline {
+            if benchmarking {
+              c.1.clone()
+            } else if weights.len() >= num_knobs {
+              weights.clone()
+            } else {
+              quality.get_weights()
+            }
+          } else {
+            quality.set_level(&mut frame_performance, vr_display);
+            quality.get_target_levels()
+          };
+
+          canvas.set_resolution_scale(quality.get_knob_value(&targets, "Resolution"));
+          canvas.set_aa_scale(quality.get_knob_value(&targets, "MSAA"));
+          canvas.set_upscaling_scale(quality.get_knob_value(&targets, "Upscaling"));
+          let target_lod = quality.get_knob_value(&targets, "LOD");
+          lighting.set_shading_scale(quality.get_knob_value(&targets, "Shading"));
+          lighting.shadow_maps.set_resolution_scale(&display,
+              quality.get_knob_value(&targets, "ShadowResolution"))?;
+          lighting.shadow_maps.set_filter_scale(quality.get_knob_value(&targets, "ShadowFilter"));
+          lighting.set_light_scale(quality.get_knob_value(&targets, "Lights"));
+          occlusion.set_quality_scale(quality.get_knob_value(&targets, "Occlusion"));
+
+          frame_performance.start_frame(&quality, &targets);
+          record_canvas_counters(&canvas, &mut frame_performance);
+          frame_performance.set_counter("LodPixelError", Object::get_pixel_error(target_lod) as f64);
+          frame_performance.set_counter("OcclusionCulling", if occlusion.is_active() { 1.0 } else { 0.0 });
+          frame_performance.set_counter("Pbr", if lighting.is_pbr_enabled { 1.0 } else { 0.0 });
+          frame_performance.set_counter("Shadows", if lighting.shadow_maps.enabled { 1.0 } else { 0.0 });
+          frame_performance.set_counter("ShadowResolution", lighting.shadow_maps.tile_resolution as f64);
+          frame_performance.set_counter("ShadowFilterRadius", lighting.shadow_maps.filter_radius as f64);
+          frame_performance.process_event("frame_start");
+          frame_performance.process_event("pre_input");
+
+          // prepare GUI and handle its actions
+          let gui_action = gui.prepare(*quality.level.borrow());
+
+          // get input and handle its actions
+          let input_actions = input_handler.process(&gui_action, &gamepads, &mut vr, &display, &window,
+              vr_mode, &mut events_loop, &mut gui);
+
+          for action in &input_actions {
+            match action {
+              &Action::Quit => break 'main,
+              &Action::StereoNone => {
+                stereo_mode = StereoMode::StereoNone;
+                occlusion.reset();
+              },
+              &Action::StereoCross => {
+                stereo_mode = StereoMode::StereoCross;
+                occlusion.reset();
+              },
+              &Action::StereoAnaglyph => {
+                stereo_mode = StereoMode::StereoAnaglyph;
+                occlusion.reset();
+              },
+              &Action::ToggleBoundingBox => show_bbox = !show_bbox,
+              &Action::ToggleOcclusionCulling => {
+                occlusion.enabled = !occlusion.enabled;
+                occlusion.reset();
+              },
+              _ => (),
+            }
+
+            if let &Action::Quit = action {
+              break 'main
+            }
           }
 
-          if let &Action::Quit = action {
-            break 'main
+          frame_performance.process_event("post_input");
+
+          frame_performance.process_event("pre_update_camera");
+          update_camera(&mut fps_camera, &input_actions);
+          frame_performance.process_event("post_update_camera");
+
+          frame_performance.process_event("pre_update_world");
+          update_world(&display, &mut world, &mut gui, &input_actions);
+          Object::update_bvh(&mut world, &mut bvh);
+          frame_performance.process_event("post_update_world");
+
+          for action in &input_actions {
+            let ray = match action {
+              &Action::PickCamera { x, y } => Ray::from_screen(&fps_camera.get_transform(),
+                  &get_mono_projection(&render_dimensions), x, y),
+              &Action::PickGamepad(i) => {
+                let inverse_standing_transform = get_standing_transform(vr_display)
+                    .inverse_transform().unwrap();
+                Ray::from_transform(&(inverse_standing_transform * get_gamepad_pose(&gamepads[i])))
+              },
+              _ => continue,
+            };
+
+            // picking nothing clears the selection
+            selection = picking::pick(&world, &bvh, &ray).map(|hit| hit.path);
           }
-        }
-
-        frame_performance.process_event("post_input");
-
-        frame_performance.process_event("pre_update_camera");
-        update_camera(&mut fps_camera, &input_actions);
-        frame_performance.process_event("post_update_camera");
-
-        frame_performance.process_event("pre_update_world");
-        update_world(&display, &mut world, &mut gui, &input_actions);
-        frame_performance.process_event("post_update_world");
 
-        draw_frame(target_lod, vr_mode, &stereo_mode, vr_display, &display, &window,
-            &mut render_params, &mut world, num_objects, &lights, num_lights, &mut empty,
-            &gamepads, &mut gamepad_models, &mut canvas, &mut frame_performance,
-            &mut render_dimensions, &mut fps_camera, &mut gui, &mut demo, demo_record, show_bbox);
-
-        frame_performance.process_event("frame_end");
-        frame_performance.record_frame_log(sample_number, c.0);
-
-        // quit when demo is done
-        if let Some(d) = demo.as_mut() {
-          if !demo_record && frame_performance.get_frame_number() as usize >= d.entries.len() {
-            break 'main;
+          frame_performance.process_event("pre_shadows");
+          lighting.render_shadows(&display, &world, &bvh);
+          frame_performance.process_event("post_shadows");
+          lighting.upload_lights();
+          frame_performance.set_counter("ShadowCasters", lighting.shadow_maps.num_casters as f64);
+
+          draw_frame(target_lod, vr_mode, &stereo_mode, vr_display, &display, &window,
+              &mut render_params, &mut world, &bvh, &mut lighting, &mut empty,
+              &gamepads, &mut gamepad_models, &mut canvas, &mut frame_performance,
+              &mut render_dimensions, &mut fps_camera, &mut gui, &mut demo, demo_record, show_bbox,
+              selection.as_ref().map(|path| &path[..]), &mut occlusion);
+
+          frame_performance.set_counter("ConsideredLights", lighting.num_considered_lights as f64);
+          frame_performance.process_event("frame_end");
+          frame_performance.record_frame_log(sample_number, &c.0);
+
+          // quit when demo is done
+          if let Some(d) = demo.as_mut() {
+            if !demo_record && frame_performance.get_frame_number() as usize >= d.entries.len() {
+              break 'main;
+            }
           }
-        }
-      } // main loop
-    } // samples
-  } // configurations
+        } // main loop
+      } // samples
+    } // configurations
+  } // controllers
 
   let now = Utc::now().format("%Y-%m-%d-%H-%M-%S");
 
   if !visualize_perf && (benchmarking || perf_filename != "") {
     // write benchmark csv
     let csv = frame_performance.to_csv();
-    let mut file = File::create(format!("{}-{}.csv", perf_filename, now)).unwrap();
-    file.write_all(csv.as_bytes()).unwrap();
+    let csv_filename = format!("{}-{}.csv", perf_filename, now);
+    let csv_path = Path::new(&csv_filename);
+    let mut file = File::create(csv_path).map_err(|e| EngynError::io(csv_path, e))?;
+    file.write_all(csv.as_bytes()).map_err(|e| EngynError::io(csv_path, e))?;
   }
 
   if demo_record || demo_length > 0 {
@@ -799,7 +1255,7 @@ fn main() {
       };
 
       if demo_length <= 0 {
-        d.to_bincode(&filename).unwrap();
+        d.to_bincode(&filename)?;
       } else {
         let mut new_demo = Demo::new();
         let step_size = d.entries.len() / demo_length as usize;
@@ -812,8 +1268,10 @@ fn main() {
           }
         }
 
-        new_demo.to_bincode(&filename).unwrap();
+        new_demo.to_bincode(&filename)?;
       }
     }
   }
+
+  Ok(())
 }
@@ -17,10 +17,13 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use glium::texture::SrgbTexture2d;
+use glium::texture::Texture2d;
 use std::cell::RefCell;
 use std::rc::Rc;
 
 pub struct Material {
+  // the name in the MTL file, which scene files use to override the material
+  pub name: String,
   pub albedo_map: Rc<RefCell<SrgbTexture2d>>,
   pub ambient_color: [f32; 3],
   pub diffuse_color: [f32; 3],
@@ -28,4 +31,74 @@ pub struct Material {
   pub shininess: f32,
   pub metalness: f32,
   pub reflectivity: f32,
+  pub roughness: f32,
+  pub shading: Shading,
+  pub emissive_color: [f32; 3],
+  pub dissolve: f32,
+  pub alpha_mode: AlphaMode,
+  // fragments that are less opaque than this are discarded when alpha testing
+  pub alpha_cutoff: f32,
+  pub maps: MaterialMaps,
+}
+
+// how the opacity of a material is used; blended materials are drawn after the opaque ones
+#[derive(Serialize, Deserialize, PartialEq, Debug, Copy, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum AlphaMode {
+  Opaque,
+  Mask,
+  Blend,
+}
+
+impl Default for AlphaMode {
+  fn default() -> AlphaMode {
+    AlphaMode::Opaque
+  }
+}
+
+// the optional texture maps of a material; colors are in sRGB, the other maps are linear
+#[derive(Default)]
+pub struct MaterialMaps {
+  pub normal: Option<Rc<RefCell<Texture2d>>>,
+  pub specular: Option<Rc<RefCell<SrgbTexture2d>>>,
+  pub shininess: Option<Rc<RefCell<Texture2d>>>,
+  pub roughness: Option<Rc<RefCell<Texture2d>>>,
+  pub emissive: Option<Rc<RefCell<SrgbTexture2d>>>,
+  pub opacity: Option<Rc<RefCell<Texture2d>>>,
+}
+
+impl MaterialMaps {
+  // the names of the maps that are present, which select a permutation of the shader
+  pub fn get_names(&self) -> Vec<&'static str> {
+    let mut names = Vec::new();
+
+    if self.normal.is_some() { names.push("normal") }
+    if self.specular.is_some() { names.push("specular") }
+    if self.shininess.is_some() { names.push("shininess") }
+    if self.roughness.is_some() { names.push("roughness") }
+    if self.emissive.is_some() { names.push("emissive") }
+    if self.opacity.is_some() { names.push("opacity") }
+
+    names
+  }
+}
+
+// the lighting model of a material; physically based shading uses metalness, reflectivity and
+// roughness instead of the specular color and shininess
+#[derive(Serialize, Deserialize, PartialEq, Debug, Copy, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum Shading {
+  BlinnPhong,
+  Pbr,
+}
+
+impl Default for Shading {
+  fn default() -> Shading {
+    Shading::BlinnPhong
+  }
+}
+
+// the perceptual roughness that gives a highlight of about the same size as a Blinn-Phong exponent
+pub fn roughness_from_shininess(shininess: f32) -> f32 {
+  f32::powf(2.0 / (f32::max(shininess, 0.0) + 2.0), 0.25)
 }
@@ -18,6 +18,10 @@
 
 use cgmath::Matrix4;
 use cgmath::Vector3;
+use cgmath::Vector4;
+use std::f32;
+
+use geometry::BoundingBox;
 
 pub fn vec_to_matrix(m: &[f32; 16]) -> Matrix4<f32> {
   Matrix4::new(
@@ -43,3 +47,58 @@ pub fn matrix_to_uniform(m: Matrix4<f32>) -> [[f32; 4]; 4] {
 pub fn vec_to_translation(t: &[f32; 3]) -> Matrix4<f32> {
     Matrix4::from_translation(Vector3::new(t[0], t[1], t[2]))
 }
+
+// narrows a projection to a window of the view, given as [left, bottom, width, height] in the
+// range 0..1, so that the window fills the whole viewport
+pub fn crop_projection(window: [f32; 4]) -> Matrix4<f32> {
+  let center_x = 2.0 * window[0] - 1.0 + window[2];
+  let center_y = 2.0 * window[1] - 1.0 + window[3];
+
+  Matrix4::new(
+      1.0 / window[2], 0.0, 0.0, 0.0,
+      0.0, 1.0 / window[3], 0.0, 0.0,
+      0.0, 0.0, 1.0, 0.0,
+      -center_x / window[2], -center_y / window[3], 0.0, 1.0)
+}
+
+pub fn empty_bounding_box() -> BoundingBox {
+  ([f32::INFINITY; 3], [f32::NEG_INFINITY; 3])
+}
+
+pub fn is_empty_bounding_box(bounding_box: &BoundingBox) -> bool {
+  (0..3).any(|i| bounding_box.0[i] > bounding_box.1[i])
+}
+
+pub fn merge_bounding_boxes(a: &BoundingBox, b: &BoundingBox) -> BoundingBox {
+  let mut result = *a;
+
+  for i in 0..3 {
+    result.0[i] = result.0[i].min(b.0[i]);
+    result.1[i] = result.1[i].max(b.1[i]);
+  }
+
+  result
+}
+
+// the axis-aligned box around the transformed corners of a bounding box
+pub fn transform_bounding_box(bounding_box: &BoundingBox, m: &Matrix4<f32>) -> BoundingBox {
+  if is_empty_bounding_box(bounding_box) {
+    return *bounding_box;
+  }
+
+  let mut result = empty_bounding_box();
+
+  for i in 0..8 {
+    let corner = Vector4::new(
+        if i & 1 == 0 { bounding_box.0[0] } else { bounding_box.1[0] },
+        if i & 2 == 0 { bounding_box.0[1] } else { bounding_box.1[1] },
+        if i & 4 == 0 { bounding_box.0[2] } else { bounding_box.1[2] },
+        1.0);
+    let transformed = m * corner;
+    let point = [transformed.x, transformed.y, transformed.z];
+
+    result = merge_bounding_boxes(&result, &(point, point));
+  }
+
+  result
+}
@@ -17,9 +17,11 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use cgmath::Matrix4;
+use cgmath::SquareMatrix;
 use glium::DrawParameters;
 use glium::PolygonMode;
 use glium::Program;
+use glium::ProgramCreationError;
 use glium::backend::Facade;
 use glium::framebuffer::SimpleFrameBuffer;
 use glium::IndexBuffer;
@@ -31,19 +33,33 @@ use std::cell::RefCell;
 use std::path::Path;
 use std::rc::Rc;
 
+use cluster;
 use drawable::Drawable;
+use error::EngynError;
 use geometry::Geometry;
+use geometry::MeshData;
 use geometry::Vertex;
 use gui::Action;
-use light::Light;
+use light::Lighting;
+use material::AlphaMode;
 use material::Material;
+use material::MaterialMaps;
+use material::Shading;
 use math;
 use resources::ResourceManager;
+use shadow;
 use uniforms;
 use uniforms::ObjectUniforms;
 
+// a simplified version of a mesh, with the largest distance it deviates from the original
+pub struct MeshLod {
+  pub geometry: Rc<RefCell<Geometry>>,
+  pub error: f32,
+}
+
 pub struct Mesh {
   pub geometry: Rc<RefCell<Geometry>>,
+  pub lods: Vec<MeshLod>,
   pub material: Rc<RefCell<Material>>,
   pub program: Rc<RefCell<Program>>,
   pub bbox_program: Rc<RefCell<Program>>,
@@ -51,31 +67,44 @@ pub struct Mesh {
 
 impl Mesh {
   pub fn new<F>(display: &F, geometry: Rc<RefCell<Geometry>>, material: Rc<RefCell<Material>>,
-      resource_manager: &ResourceManager) -> Mesh
+      resource_manager: &ResourceManager) -> Result<Mesh, EngynError>
       where F: Facade {
-    let program = resource_manager.get_program(&Path::new("programs/mesh_program"), &|| {
-      construct_program(display)
-    }).unwrap();
+    let program = get_program(display, resource_manager, &material.borrow().maps)?;
 
     let bbox_program = resource_manager.get_program(&Path::new("programs/mesh_bbox_program"), &|| {
       construct_bbox_program(display)
-    }).unwrap();
+    })?;
 
-    Mesh {
+    Ok(Mesh {
       geometry: geometry,
+      lods: Vec::new(),
       material: material,
       program: Rc::clone(&program),
       bbox_program: Rc::clone(&bbox_program),
-    }
+    })
+  }
+
+  // picks the coarsest level that deviates at most `max_error` from the full geometry
+  pub fn get_lod_geometry(&self, max_error: f32) -> &Rc<RefCell<Geometry>> {
+    self.lods.iter().rev()
+        .find(|lod| lod.error <= max_error)
+        .map_or(&self.geometry, |lod| &lod.geometry)
   }
 }
 
 impl Drawable for Mesh {
   fn draw(&mut self, target: &mut SimpleFrameBuffer, context: &Facade,
-      projection: [[f32; 4]; 4], view: [[f32; 4]; 4], model_transform: Matrix4<f32>,
-      render_params: &DrawParameters, num_lights: i32, lights: &[Light; 32], eye_i: usize,
+      projection: [[f32; 4]; 4], view: [[f32; 4]; 4], model_transform: Matrix4<f32>, max_error: f32,
+      render_params: &DrawParameters, lighting: &Lighting, eye_i: usize,
       is_anaglyph: bool, show_bbox: bool) {
     let material_ref = self.material.borrow();
+    let normal_map = material_ref.maps.normal.as_ref().map(|m| m.borrow());
+    let specular_map = material_ref.maps.specular.as_ref().map(|m| m.borrow());
+    let shininess_map = material_ref.maps.shininess.as_ref().map(|m| m.borrow());
+    let roughness_map = material_ref.maps.roughness.as_ref().map(|m| m.borrow());
+    let emissive_map = material_ref.maps.emissive.as_ref().map(|m| m.borrow());
+    let opacity_map = material_ref.maps.opacity.as_ref().map(|m| m.borrow());
+    let camera_position = Matrix4::from(view).invert().map_or([0.0; 3], |v| v.w.truncate().into());
     let uniforms = ObjectUniforms {
       projection: projection,
       view: view,
@@ -87,23 +116,44 @@ impl Drawable for Mesh {
       shininess: material_ref.shininess,
       metalness: material_ref.metalness,
       reflectivity: material_ref.reflectivity,
-      num_lights: num_lights,
-      lights: *lights,
+      roughness: material_ref.roughness,
+      emissive_color: material_ref.emissive_color,
+      dissolve: material_ref.dissolve,
+      alpha_mode: material_ref.alpha_mode,
+      alpha_cutoff: material_ref.alpha_cutoff,
+      normal_map: normal_map.as_ref().map(|m| &**m),
+      specular_map: specular_map.as_ref().map(|m| &**m),
+      shininess_map: shininess_map.as_ref().map(|m| &**m),
+      roughness_map: roughness_map.as_ref().map(|m| &**m),
+      emissive_map: emissive_map.as_ref().map(|m| &**m),
+      opacity_map: opacity_map.as_ref().map(|m| &**m),
+      use_pbr: material_ref.shading == Shading::Pbr && lighting.is_pbr_enabled,
+      camera_position: camera_position,
+      light_buffer: lighting.get_light_buffer(),
+      cluster_grid: lighting.clusters.get_grid(),
+      cluster_indices: lighting.clusters.get_indices(),
+      cluster_matrix: lighting.clusters.matrix,
+      cluster_depth_range: lighting.clusters.depth_range,
+      num_global_lights: lighting.clusters.num_global_lights as i32,
+      shadow_map: lighting.shadow_maps.get_atlas(),
+      num_shadows: lighting.shadow_maps.num_shadows,
+      shadow_matrices: lighting.shadow_maps.matrices,
+      shadow_filter_radius: lighting.shadow_maps.filter_radius,
       eye_i: eye_i,
       is_anaglyph: is_anaglyph,
     };
 
-    let geometry = self.geometry.borrow();
+    let geometry = self.get_lod_geometry(max_error).borrow();
 
     match geometry.indices {
       Some(ref indices) => target.draw(
-        (&geometry.vertices, &geometry.normals, &geometry.texcoords),
+        (&geometry.vertices, &geometry.normals, &geometry.texcoords, &geometry.tangents),
         indices,
         &self.program.borrow(),
         &uniforms,
         render_params).unwrap(),
       None => target.draw(
-        (&geometry.vertices, &geometry.normals, &geometry.texcoords),
+        (&geometry.vertices, &geometry.normals, &geometry.texcoords, &geometry.tangents),
         NoIndices(PrimitiveType::TrianglesList),
         &self.program.borrow(),
         &uniforms,
@@ -147,10 +197,37 @@ impl Drawable for Mesh {
     }
   }
 
+  fn get_geometry(&self) -> Option<Rc<RefCell<Geometry>>> {
+    Some(Rc::clone(&self.geometry))
+  }
+
+  fn is_blended(&self) -> bool {
+    self.material.borrow().alpha_mode == AlphaMode::Blend
+  }
+
+  fn set_material(&mut self, context: &Facade, resource_manager: &ResourceManager,
+      material: &Rc<RefCell<Material>>) -> Result<(), EngynError> {
+    self.program = get_program(context, resource_manager, &material.borrow().maps)?;
+    self.material = Rc::clone(material);
+    Ok(())
+  }
+
+  fn get_material(&self) -> Option<Rc<RefCell<Material>>> {
+    Some(Rc::clone(&self.material))
+  }
+
+  fn get_mesh_data(&self) -> Option<Rc<MeshData>> {
+    self.geometry.borrow().mesh_data.as_ref().map(Rc::clone)
+  }
+
+  fn get_num_triangles(&self, max_error: f32) -> usize {
+    self.get_lod_geometry(max_error).borrow().get_num_triangles()
+  }
+
   fn update(&mut self, _: &Facade, _: Matrix4<f32>, _: &Vec<Action>) {}
 }
 
-fn construct_bbox_program<F>(display: &F) -> Program
+fn construct_bbox_program<F>(display: &F) -> Result<Program, ProgramCreationError>
     where F: Facade {
       Program::from_source(
           display,
@@ -200,11 +277,23 @@ fn construct_bbox_program<F>(display: &F) -> Program
               color = vec4(make_anaglyph(black, eye_i, is_anaglyph), 1.0);
             }
           "#, "MAX_NUM_LIGHTS", &format!("{}", uniforms::MAX_NUM_LIGHTS)),
-          None).unwrap()
+          None)
 }
 
-fn construct_program<F>(display: &F) -> Program
-    where F: Facade {
+// the permutation of the mesh program that samples the maps that are present
+fn get_program(context: &Facade, resource_manager: &ResourceManager, maps: &MaterialMaps)
+    -> Result<Rc<RefCell<Program>>, EngynError> {
+  let names = maps.get_names();
+  let path = names.iter().fold("programs/mesh_program".to_owned(), |path, name| path + "_" + name);
+
+  resource_manager.get_program(&Path::new(&path), &|| construct_program(context, &names))
+}
+
+fn construct_program(display: &Facade, maps: &[&str]) -> Result<Program, ProgramCreationError> {
+  let defines: String = maps.iter()
+      .map(|name| format!("#define HAS_{}_MAP\n", name.to_uppercase()))
+      .collect();
+
   Program::from_source(
       display,
       &r#"
@@ -217,10 +306,12 @@ fn construct_program<F>(display: &F) -> Program
         in vec3 position;
         in vec3 normal;
         in vec2 texcoord;
+        in vec4 tangent;
 
         out vec3 v_normal;
         out vec2 v_texcoord;
         out vec3 v_vertex_position;
+        out vec4 v_tangent;
 
         void main() {
           mat4 normal_matrix = transpose(inverse(model)); // TODO: put this in host code
@@ -229,67 +320,270 @@ fn construct_program<F>(display: &F) -> Program
 
           v_texcoord = texcoord;
           v_normal = vec3(normal_matrix * vec4(normal, 1.0));
+          v_tangent = vec4(mat3(model) * tangent.xyz, tangent.w);
           v_vertex_position = vec3(position_global);
           gl_Position = projection * position_eye;
         }
       "#,
-      &str::replace(r#"
+      &r#"
         #version 330
+        MAP_DEFINES
         layout(std140) uniform;
 
         const float SCREEN_GAMMA = 2.2;
         const float INTENSITY = 1.0;
+        const float PI = 3.14159265359;
 
+        // kind 0 is directional, 1 is point and 2 is spot; the color includes the intensity
         struct Light {
+          int kind;
           vec3 color;
           vec3 position;
+          vec3 direction;
+          float range;
+          float cos_inner;
+          float cos_outer;
+          int shadow_tile;
+        };
+
+        // the lights, packed as described by LightBlock in uniforms.rs
+        uniform LightBlock {
+          vec4 light_data[MAX_NUM_LIGHTS * LIGHT_BLOCK_STRIDE];
         };
 
         uniform vec3 ambient_color;
         uniform vec3 diffuse_color;
         uniform vec3 specular_color;
         uniform float shininess;
+        uniform float metalness;
+        uniform float reflectivity;
+        uniform float roughness;
+        uniform bool use_pbr;
+        uniform vec3 camera_position;
+        uniform vec3 emissive_color;
         uniform float dissolve;
+        uniform int alpha_mode;
+        uniform float alpha_cutoff;
         uniform sampler2D albedo_map;
-        uniform int num_lights;
-        uniform Light lights[MAX_NUM_LIGHTS];
+
+        #ifdef HAS_NORMAL_MAP
+        uniform sampler2D normal_map;
+        #endif
+        #ifdef HAS_SPECULAR_MAP
+        uniform sampler2D specular_map;
+        #endif
+        #ifdef HAS_SHININESS_MAP
+        uniform sampler2D shininess_map;
+        #endif
+        #ifdef HAS_ROUGHNESS_MAP
+        uniform sampler2D roughness_map;
+        #endif
+        #ifdef HAS_EMISSIVE_MAP
+        uniform sampler2D emissive_map;
+        #endif
+        #ifdef HAS_OPACITY_MAP
+        uniform sampler2D opacity_map;
+        #endif
+
+        uniform usampler2D cluster_grid;
+        uniform usampler2D cluster_indices;
+        uniform mat4 cluster_matrix;
+        uniform vec2 cluster_depth_range;
+        uniform int num_global_lights;
+        uniform sampler2DShadow shadow_map;
+        uniform int num_shadows;
+        uniform mat4 shadow_matrices[MAX_NUM_SHADOWS];
+        uniform int shadow_filter_radius;
         uniform uint eye_i;
         uniform bool is_anaglyph;
 
         in vec3 v_normal;
         in vec2 v_texcoord;
         in vec3 v_vertex_position;
+        in vec4 v_tangent;
 
         out vec4 color;
 
+        Light get_light(int i) {
+          vec4 position_kind = light_data[i * LIGHT_BLOCK_STRIDE];
+          vec4 color_range = light_data[i * LIGHT_BLOCK_STRIDE + 1];
+          vec4 direction_tile = light_data[i * LIGHT_BLOCK_STRIDE + 2];
+          vec4 cone = light_data[i * LIGHT_BLOCK_STRIDE + 3];
+
+          return Light(int(position_kind.w), color_range.rgb, position_kind.xyz,
+              direction_tile.xyz, color_range.w, cone.x, cone.y, int(direction_tile.w));
+        }
+
+        // the offset and number of the light indices of the cluster of this fragment
+        uvec2 get_cluster() {
+          vec4 clip = cluster_matrix * vec4(v_vertex_position, 1.0);
+          vec2 tile = (clip.xy / clip.w * 0.5 + 0.5) * vec2(CLUSTERS_X, CLUSTERS_Y);
+          ivec2 xy = clamp(ivec2(tile), ivec2(0), ivec2(CLUSTERS_X - 1, CLUSTERS_Y - 1));
+
+          // the slices grow exponentially with the depth, like in cluster.rs
+          float near = cluster_depth_range.x;
+          float far = cluster_depth_range.y;
+          float slice = log(max(clip.w, near) / near) / log(far / near) * float(CLUSTERS_Z);
+          int z = int(clamp(slice, 0.0, float(CLUSTERS_Z - 1)));
+
+          return texelFetch(cluster_grid, ivec2(xy.x + xy.y * CLUSTERS_X, z), 0).xy;
+        }
+
+        int get_cluster_light(int index) {
+          ivec2 coords = ivec2(index % CLUSTER_INDICES_WIDTH, index / CLUSTER_INDICES_WIDTH);
+          return int(texelFetch(cluster_indices, coords, 0).r);
+        }
+
+        // the normalized direction from this fragment towards the light
+        vec3 get_light_direction(Light light) {
+          if(light.kind == 0) {
+            return -normalize(light.direction);
+          } else {
+            return normalize(light.position - v_vertex_position);
+          }
+        }
+
+        // the light that arrives at this fragment, after distance attenuation and the spot cone
+        vec3 get_radiance(Light light) {
+          if(light.kind == 0) {
+            return light.color * INTENSITY;
+          }
+
+          vec3 to_light = light.position - v_vertex_position;
+          float distance_squared = max(dot(to_light, to_light), 0.0001);
+          float attenuation = 1.0 / distance_squared;
+
+          // fade out smoothly so the light has no effect beyond its range
+          if(light.range > 0.0) {
+            float ratio = distance_squared / (light.range * light.range);
+            float window = clamp(1.0 - ratio * ratio, 0.0, 1.0);
+            attenuation *= window * window;
+          }
+
+          if(light.kind == 2) {
+            float cos_angle = dot(normalize(-to_light), normalize(light.direction));
+            float cos_inner = max(light.cos_inner, light.cos_outer + 0.0001);
+            attenuation *= smoothstep(light.cos_outer, cos_inner, cos_angle);
+          }
+
+          return light.color * INTENSITY * attenuation;
+        }
+
         vec3 calculate_lighting(
-            vec3 light_position,
-            vec3 light_color,
+            vec3 light_direction,
+            vec3 radiance,
             vec3 normal,
             vec3 ambient_color,
             vec3 diffuse_color,
             vec3 specular_color,
             float shininess) {
-          vec3 light_direction = normalize(light_position - v_vertex_position);
           float diffuse_fraction = max(dot(light_direction, normal), 0.0);
-          float distance = length(light_direction);
-          distance = distance * distance;
 
           vec3 ambient = ambient_color;
-          vec3 diffuse = diffuse_color * diffuse_fraction * light_color * INTENSITY / distance;
+          vec3 diffuse = diffuse_color * diffuse_fraction * radiance;
           vec3 specular = vec3(0.0);
 
           if(diffuse_fraction > 0.0) {
-            vec3 view_direction = normalize(-v_vertex_position);
+            vec3 view_direction = normalize(camera_position - v_vertex_position);
             vec3 reflection_direction = reflect(-light_direction, normal);
             float specular_angle = max(dot(reflection_direction, view_direction), 0.0);
             float specular_fraction = pow(specular_angle, shininess * 0.25);
-            specular = specular_color * specular_fraction * light_color * INTENSITY / distance;
+            specular = specular_color * specular_fraction * radiance;
           }
 
           return ambient * 0.01 + diffuse + specular;
         }
 
+        // GGX / Trowbridge-Reitz normal distribution
+        float distribution_ggx(float n_dot_h, float alpha) {
+          float alpha2 = alpha * alpha;
+          float denominator = n_dot_h * n_dot_h * (alpha2 - 1.0) + 1.0;
+          return alpha2 / (PI * denominator * denominator);
+        }
+
+        // Smith masking-shadowing with the Schlick-GGX approximation for direct lighting
+        float geometry_smith(float n_dot_v, float n_dot_l, float roughness) {
+          float k = (roughness + 1.0) * (roughness + 1.0) / 8.0;
+          float g_v = n_dot_v / (n_dot_v * (1.0 - k) + k);
+          float g_l = n_dot_l / (n_dot_l * (1.0 - k) + k);
+          return g_v * g_l;
+        }
+
+        vec3 fresnel_schlick(float cos_theta, vec3 f0) {
+          return f0 + (1.0 - f0) * pow(1.0 - cos_theta, 5.0);
+        }
+
+        // metallic-roughness shading; reflectivity is the specular level of dielectrics
+        vec3 calculate_lighting_pbr(
+            vec3 light_direction,
+            vec3 radiance,
+            vec3 normal,
+            vec3 ambient_color,
+            vec3 base_color,
+            float roughness,
+            float reflectivity) {
+          vec3 view_direction = normalize(camera_position - v_vertex_position);
+          vec3 half_direction = normalize(light_direction + view_direction);
+
+          float n_dot_l = max(dot(normal, light_direction), 0.0);
+          float n_dot_v = max(dot(normal, view_direction), 0.0001);
+          float n_dot_h = max(dot(normal, half_direction), 0.0);
+          float v_dot_h = max(dot(view_direction, half_direction), 0.0);
+
+          float clamped_roughness = clamp(roughness, 0.045, 1.0);
+          vec3 f0 = mix(vec3(0.16 * reflectivity * reflectivity), base_color, metalness);
+          vec3 fresnel = fresnel_schlick(v_dot_h, f0);
+
+          float d = distribution_ggx(n_dot_h, clamped_roughness * clamped_roughness);
+          float g = geometry_smith(n_dot_v, n_dot_l, clamped_roughness);
+          vec3 specular = d * g * fresnel / (4.0 * n_dot_v * max(n_dot_l, 0.0001));
+
+          // light that is reflected specularly or absorbed by metals does not reach the diffuse lobe
+          vec3 diffuse = (1.0 - fresnel) * (1.0 - metalness) * base_color / PI;
+
+          // scaled like the Blinn-Phong path, whose diffuse term is not divided by pi
+          return ambient_color * base_color * 0.01 + (diffuse + specular) * radiance * PI * n_dot_l;
+        }
+
+        // the fraction of the light that reaches this fragment, with a PCF kernel over the tile of
+        // the light in the shadow atlas
+        float calculate_shadow(int tile, vec3 normal, vec3 light_direction) {
+          if(tile < 0 || tile >= num_shadows) {
+            return 1.0;
+          }
+
+          vec4 shadow_position = shadow_matrices[tile] * vec4(v_vertex_position, 1.0);
+          vec3 coords = shadow_position.xyz / shadow_position.w;
+
+          float tile_size = 1.0 / float(SHADOW_ATLAS_TILES);
+          vec2 tile_min = vec2(tile % SHADOW_ATLAS_TILES, tile / SHADOW_ATLAS_TILES) * tile_size;
+          vec2 tile_max = tile_min + vec2(tile_size);
+
+          if(any(lessThan(coords.xy, tile_min)) || any(greaterThan(coords.xy, tile_max))
+              || coords.z > 1.0) {
+            return 1.0;
+          }
+
+          // surfaces at a grazing angle to the light need more bias to avoid acne
+          float bias = mix(0.0005, 0.005, 1.0 - max(dot(normal, light_direction), 0.0));
+
+          vec2 texel_size = 1.0 / vec2(textureSize(shadow_map, 0));
+          float lit = 0.0;
+          int num_samples = 0;
+
+          for(int x = -shadow_filter_radius; x <= shadow_filter_radius; x++) {
+            for(int y = -shadow_filter_radius; y <= shadow_filter_radius; y++) {
+              vec2 offset = vec2(x, y) * texel_size;
+              vec2 sample_coords = clamp(coords.xy + offset, tile_min + texel_size * 0.5,
+                  tile_max - texel_size * 0.5);
+              lit += texture(shadow_map, vec3(sample_coords, coords.z - bias));
+              num_samples++;
+            }
+          }
+
+          return lit / float(num_samples);
+        }
+
         vec3 make_anaglyph(vec3 color, uint eye_i, bool is_anaglyph) {
           if(is_anaglyph) {
             if(eye_i == 0u) {
@@ -308,22 +602,100 @@ fn construct_program<F>(display: &F) -> Program
           vec3 diffuse_texture = vec3(texture(albedo_map, v_texcoord));
           vec3 color_linear = vec3(0.0);
 
-          for(int i = 0; i < num_lights; i++) {
-            vec3 color_one_light = calculate_lighting(
-                lights[i].position,
-                lights[i].color,
-                normal,
-                ambient_color,
-                diffuse_texture + diffuse_color,
-                specular_color,
-                20.0); //shininess);
-            color_linear += color_one_light;
+          #ifdef HAS_NORMAL_MAP
+          vec3 tangent = normalize(v_tangent.xyz - normal * dot(normal, v_tangent.xyz));
+          vec3 bitangent = cross(normal, tangent) * v_tangent.w;
+          vec3 normal_texel = texture(normal_map, v_texcoord).xyz * 2.0 - 1.0;
+          normal = normalize(mat3(tangent, bitangent, normal) * normal_texel);
+          #endif
+
+          vec3 specular_value = specular_color;
+          float reflectivity_value = reflectivity;
+          #ifdef HAS_SPECULAR_MAP
+          vec3 specular_texel = texture(specular_map, v_texcoord).rgb;
+          specular_value *= specular_texel;
+          reflectivity_value *= max(specular_texel.r, max(specular_texel.g, specular_texel.b));
+          #endif
+
+          // a shininess map gives the roughness for physically based shading
+          float roughness_value = roughness;
+          #ifdef HAS_SHININESS_MAP
+          float shininess_value = shininess * texture(shininess_map, v_texcoord).r;
+          roughness_value = pow(2.0 / (shininess_value + 2.0), 0.25);
+          #endif
+          #ifdef HAS_ROUGHNESS_MAP
+          roughness_value *= texture(roughness_map, v_texcoord).r;
+          #endif
+
+          vec3 emissive = emissive_color;
+          #ifdef HAS_EMISSIVE_MAP
+          emissive *= texture(emissive_map, v_texcoord).rgb;
+          #endif
+
+          float opacity = dissolve;
+          #ifdef HAS_OPACITY_MAP
+          opacity *= texture(opacity_map, v_texcoord).r;
+          #endif
+
+          // 0: opaque, 1: alpha test, 2: blended
+          if(alpha_mode == 0) {
+            opacity = 1.0;
+          } else if(alpha_mode == 1) {
+            if(opacity < alpha_cutoff) {
+              discard;
+            }
+
+            opacity = 1.0;
+          }
+
+          // the global lights come first in the indices, then those of the cluster
+          uvec2 cluster = get_cluster();
+
+          for(int j = 0; j < num_global_lights + int(cluster.y); j++) {
+            int index = j < num_global_lights ? j : int(cluster.x) + j - num_global_lights;
+            Light light = get_light(get_cluster_light(index));
+            vec3 light_direction = get_light_direction(light);
+            vec3 radiance = get_radiance(light);
+            vec3 color_one_light;
+
+            if(use_pbr) {
+              color_one_light = calculate_lighting_pbr(
+                  light_direction,
+                  radiance,
+                  normal,
+                  ambient_color,
+                  diffuse_texture + diffuse_color,
+                  roughness_value,
+                  reflectivity_value);
+            } else {
+              color_one_light = calculate_lighting(
+                  light_direction,
+                  radiance,
+                  normal,
+                  ambient_color,
+                  diffuse_texture + diffuse_color,
+                  specular_value,
+                  20.0); //shininess);
+            }
+
+            color_linear += color_one_light * calculate_shadow(light.shadow_tile, normal,
+                light_direction);
           }
 
+          color_linear += emissive;
+
           vec3 color_gamma_corrected = color_linear;
           //vec3 color_gamma_corrected = pow(color_linear, vec3(1.0 / SCREEN_GAMMA)); // assumes textures are linearized (i.e. not sRGB))
-          color = vec4(make_anaglyph(color_gamma_corrected, eye_i, is_anaglyph), dissolve);
+          color = vec4(make_anaglyph(color_gamma_corrected, eye_i, is_anaglyph), opacity);
         }
-      "#, "MAX_NUM_LIGHTS", &format!("{}", uniforms::MAX_NUM_LIGHTS)),
-      None).unwrap()
+      "#.replace("MAP_DEFINES", &defines)
+          .replace("MAX_NUM_LIGHTS", &format!("{}", uniforms::MAX_NUM_LIGHTS))
+          .replace("LIGHT_BLOCK_STRIDE", &format!("{}", uniforms::LIGHT_BLOCK_STRIDE))
+          .replace("CLUSTERS_X", &format!("{}", cluster::CLUSTERS_X))
+          .replace("CLUSTERS_Y", &format!("{}", cluster::CLUSTERS_Y))
+          .replace("CLUSTERS_Z", &format!("{}", cluster::CLUSTERS_Z))
+          .replace("CLUSTER_INDICES_WIDTH", &format!("{}", cluster::CLUSTER_INDICES_WIDTH))
+          .replace("MAX_NUM_SHADOWS", &format!("{}", shadow::MAX_NUM_SHADOWS))
+          .replace("SHADOW_ATLAS_TILES", &format!("{}", shadow::SHADOW_ATLAS_TILES)),
+      None)
 }
@@ -34,7 +34,7 @@ use std::f32;
 use drawable::Drawable;
 use geometry::Vertex;
 use gui::Action;
-use light::Light;
+use light::Lighting;
 use math;
 use object::Object;
 
@@ -142,6 +142,7 @@ impl Network {
       drawable: Some(Box::new(self)),
       transform: Matrix4::identity(),
       size: 0.0,
+      bounds: None,
     }
   }
 
@@ -214,8 +215,8 @@ impl Network {
 
 impl Drawable for Network {
   fn draw(&mut self, target: &mut SimpleFrameBuffer, _: &Facade, projection: [[f32; 4]; 4],
-      view: [[f32; 4]; 4], model_transform: Matrix4<f32>, render_params: &DrawParameters, _: i32,
-      _: &[Light; 32], _: usize, _: bool, _: bool) {
+      view: [[f32; 4]; 4], model_transform: Matrix4<f32>, _: f32, render_params: &DrawParameters,
+      _: &Lighting, _: usize, _: bool, _: bool) {
     let uniforms = uniform! {
       projection: projection,
       view: view,
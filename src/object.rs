@@ -18,63 +18,163 @@
 
 use cgmath::Euler;
 use cgmath::Rad;
+use cgmath::InnerSpace;
 use cgmath::Matrix4;
 use cgmath::SquareMatrix;
 use cgmath::Vector3;
+use glium::Blend;
 use glium::Display;
 use glium::DrawParameters;
 use glium::backend::Facade;
 use glium::framebuffer::SimpleFrameBuffer;
-use glium::index::PrimitiveType;
-use glium::IndexBuffer;
-use glium::VertexBuffer;
 use std::cell::RefCell;
 use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::f32;
 use std::path::Path;
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::time::Instant;
 use tobj;
 
+use bvh::Bvh;
+use bvh::BvhItem;
 use drawable::Drawable;
+use error::EngynError;
+use frustum::Frustum;
+use frustum::Intersection;
+use geometry::BoundingBox;
 use geometry::Geometry;
-use geometry::Normal;
-use geometry::Vertex;
-use geometry::Texcoord;
-use light::Light;
+use geometry::MeshData;
+use light::Lighting;
+use material;
+use material::AlphaMode;
 use material::Material;
+use material::MaterialMaps;
+use material::Shading;
+use math;
 use mesh::Mesh;
+use mesh::MeshLod;
+use occlusion::OcclusionCuller;
+use quality::Quality;
 use resources::ResourceManager;
+use simplify;
+
+// simplified levels per mesh, as fractions of the original number of triangles
+const LOD_RATIOS: [f32; 4] = [0.5, 0.25, 0.125, 0.0625];
+
+// meshes with fewer triangles are not worth simplifying
+const MIN_LOD_TRIANGLES: usize = 64;
+
+// the screen-space error in pixels at the highest and lowest LOD quality level
+const MIN_PIXEL_ERROR: f32 = 0.5;
+const MAX_PIXEL_ERROR: f32 = 32.0;
+
+// objects closer than this are drawn as if they were at this distance
+const NEAR_DISTANCE: f32 = 0.01;
+
+// what was drawn and what was culled, over one or more calls to `Object::draw`
+#[derive(Default)]
+pub struct DrawStats {
+  pub drawn_objects: u32,
+  pub drawn_triangles: usize,
+  pub culled_objects: u32,
+  pub culled_triangles: usize,
+  pub occluded_objects: u32,
+  pub occluded_triangles: usize,
+  pub occlusion_queries: u32,
+  pub occlusion_cpu_nanos: u32,
+  pub blended_objects: u32,
+}
 
 pub struct Object {
   pub children: Vec<Object>,
   pub drawable: Option<Box<Drawable>>,
   pub transform: Matrix4<f32>,
   pub size: f32,
+  pub bounds: Option<BoundingBox>,
 }
 
 impl Object {
-  pub fn from_file<F>(context: &F, resource_manager: &ResourceManager, path: &Path) -> Object
-      where F: Facade {
+  pub fn register_quality_knobs(quality: &mut Quality) {
+    quality.register_knob("LOD", "Level-of-detail weight", (0.0, 1.0), 0.4, 1.0);
+  }
+
+  pub fn from_file<F>(context: &F, resource_manager: &ResourceManager, path: &Path)
+      -> Result<Object, EngynError> where F: Facade {
     let mut objects = Vec::new();
     let mut materials = Vec::new();
 
-    let obj_dir = path.parent().unwrap();
+    let obj_dir = path.parent().unwrap_or(Path::new(""));
 
-    let (objs, mtls) = tobj::load_obj(path).unwrap(); // TODO: propagate error
+    let (objs, mtls) = tobj::load_obj(path).map_err(|e| EngynError::obj(path, e))?;
 
     for mtl in mtls {
-      let texture_filename = mtl.diffuse_texture;
-      let texture_file = obj_dir.join(&texture_filename);
-      let albedo_map = resource_manager.get_texture(&texture_file).unwrap();
+      let texture_file = obj_dir.join(&mtl.diffuse_texture);
+      let albedo_map = resource_manager.get_texture(&texture_file)?;
+
+      let color_map = |texture: &str, keys: &[&str]| {
+        match get_map_path(obj_dir, &mtl, texture, keys) {
+          Some(path) => resource_manager.get_texture(&path).map(Some),
+          None => Ok(None),
+        }
+      };
+      let linear_map = |texture: &str, keys: &[&str]| {
+        match get_map_path(obj_dir, &mtl, texture, keys) {
+          Some(path) => resource_manager.get_linear_texture(&path).map(Some),
+          None => Ok(None),
+        }
+      };
+
+      let maps = MaterialMaps {
+        normal: linear_map(&mtl.normal_texture, &["norm", "map_Bump", "map_bump", "bump"])?,
+        specular: color_map(&mtl.specular_texture, &[])?,
+        shininess: linear_map(&mtl.shininess_texture, &[])?,
+        roughness: linear_map("", &["map_Pr"])?,
+        emissive: color_map("", &["map_Ke"])?,
+        opacity: linear_map(&mtl.dissolve_texture, &[])?,
+      };
+
+      // an emissive map without a color is taken as is
+      let emissive_color = mtl.unknown_param.get("Ke")
+          .and_then(|value| parse_color(value))
+          .unwrap_or(if maps.emissive.is_some() { [1.0, 1.0, 1.0] } else { [0.0, 0.0, 0.0] });
+
+      // the PBR extension of MTL gives roughness and metalness directly
+      let roughness = mtl.unknown_param.get("Pr").and_then(|value| value.trim().parse().ok());
+      let metalness = mtl.unknown_param.get("Pm").and_then(|value| value.trim().parse().ok());
+      let is_pbr = roughness.is_some() || metalness.is_some() || maps.roughness.is_some();
+
+      // opacity maps are mostly cut-outs like leaves, which don't need sorting
+      let alpha_mode = if maps.opacity.is_some() {
+        AlphaMode::Mask
+      } else if mtl.dissolve < 1.0 {
+        AlphaMode::Blend
+      } else {
+        AlphaMode::Opaque
+      };
 
       materials.push(Rc::new(RefCell::new(Material {
+        name: mtl.name.clone(),
         albedo_map: Rc::clone(&albedo_map),
         ambient_color: mtl.ambient,
         diffuse_color: mtl.diffuse,
         specular_color: mtl.specular,
         shininess: mtl.shininess,
-        metalness: 0.0,
-        reflectivity: 0.0,
+        metalness: metalness.unwrap_or(0.0),
+        reflectivity: if is_pbr { 0.5 } else { 0.0 },
+        roughness: roughness.unwrap_or(if maps.roughness.is_some() {
+          1.0
+        } else {
+          material::roughness_from_shininess(mtl.shininess)
+        }),
+        shading: if is_pbr { Shading::Pbr } else { Shading::BlinnPhong },
+        emissive_color: emissive_color,
+        dissolve: mtl.dissolve,
+        alpha_mode: alpha_mode,
+        alpha_cutoff: 0.5,
+        maps: maps,
       })));
     }
 
@@ -83,104 +183,101 @@ impl Object {
       [f32::NEG_INFINITY; 3],
     );
 
-    for obj in objs {
-      let mut bounding_box = (
-        [f32::INFINITY; 3],
-        [f32::NEG_INFINITY; 3],
-      );
-
-      for idx in &obj.mesh.indices {
-        let i = *idx as usize;
-        let pos = [
-            obj.mesh.positions[3 * i],
-            obj.mesh.positions[3 * i + 1],
-            obj.mesh.positions[3 * i + 2]
-        ];
-
-        for i in 0..pos.len() {
-          bounding_box.0[i] = bounding_box.0[i].min(pos[i]);
-          bounding_box.1[i] = bounding_box.1[i].max(pos[i]);
-        }
+    // positions that are shared between objects, e.g. where two materials meet, must stay in
+    // place when simplifying
+    let mut position_counts = HashMap::new();
+    for obj in &objs {
+      let keys: HashSet<[u32; 3]> = obj.mesh.positions.chunks(3)
+          .map(|p| simplify::position_key(&[p[0], p[1], p[2]]))
+          .collect();
 
+      for key in keys {
+        *position_counts.entry(key).or_insert(0) += 1;
       }
+    }
+    let locked_positions: HashSet<[u32; 3]> = position_counts.into_iter()
+        .filter(|&(_, count)| count > 1)
+        .map(|(key, _)| key)
+        .collect();
 
-      let size = (0..3).map(|i| (bounding_box.1[i] - bounding_box.0[i]).powi(2)).sum();
+    let mesh_datas: Vec<MeshData> = objs.iter().map(|obj| MeshData::from_obj(&obj.mesh)).collect();
 
-      for i in 0..bounding_box.0.len() {
-        global_bounding_box.0[i] = global_bounding_box.0[i].min(bounding_box.0[i]);
-        global_bounding_box.1[i] = global_bounding_box.1[i].max(bounding_box.1[i]);
+    let build_lod_chain = |i: usize| {
+      if mesh_datas[i].get_num_triangles() >= MIN_LOD_TRIANGLES {
+        simplify::build_lod_chain(&mesh_datas[i], &LOD_RATIOS, &locked_positions)
+      } else {
+        Vec::new()
       }
+    };
 
-      let indices = if obj.mesh.indices.len() > 0 {
-        Some(IndexBuffer::new(context, PrimitiveType::TrianglesList, &obj.mesh.indices).unwrap())
-      } else {
-        None
-      };
+    // simplifying takes a while, so the levels can be kept in a cache file next to the OBJ file
+    let lod_chains = if resource_manager.use_lod_cache {
+      simplify::get_cached_lod_chains(path, mesh_datas.len(), &LOD_RATIOS, build_lod_chain)
+    } else {
+      (0..mesh_datas.len()).map(build_lod_chain).collect()
+    };
 
-      let mut normals = VertexBuffer::empty(context, obj.mesh.normals.len()).unwrap();
-      {
-        let mut mapped = normals.map();
-        for i in 0..obj.mesh.normals.len() / 3 {
-          mapped[i] = Normal { normal: (
-            obj.mesh.normals[i * 3 + 0],
-            obj.mesh.normals[i * 3 + 1],
-            obj.mesh.normals[i * 3 + 2],
-          )};
-        }
-      }
+    let mut num_vertices = 0;
+    let mut num_triangles = 0;
+    let mut area = 0.0;
 
-      let mut vertices = VertexBuffer::empty(context, obj.mesh.positions.len()).unwrap();
-      {
-        let mut mapped = vertices.map();
-        for i in 0..obj.mesh.positions.len() / 3 {
-          mapped[i] = Vertex { position: (
-            obj.mesh.positions[i * 3 + 0],
-            obj.mesh.positions[i * 3 + 1],
-            obj.mesh.positions[i * 3 + 2],
-          )};
-        }
-      }
+    for ((obj, mesh_data), lod_chain) in objs.iter().zip(mesh_datas).zip(lod_chains) {
+      let bounding_box = mesh_data.get_bounding_box();
 
-      let mut texcoords = VertexBuffer::empty(context, obj.mesh.texcoords.len()).unwrap();
-      {
-        let mut mapped = texcoords.map();
-        for i in 0..obj.mesh.texcoords.len() / 2 {
-          mapped[i] = Texcoord { texcoord: (
-            obj.mesh.texcoords[i * 2 + 0],
-            obj.mesh.texcoords[i * 2 + 1],
-          )};
-        }
+      let size = (0..3).map(|i| (bounding_box.1[i] - bounding_box.0[i]).powi(2)).sum();
+
+      for i in 0..bounding_box.0.len() {
+        global_bounding_box.0[i] = global_bounding_box.0[i].min(bounding_box.0[i]);
+        global_bounding_box.1[i] = global_bounding_box.1[i].max(bounding_box.1[i]);
       }
 
+      let lods = lod_chain.iter()
+          .map(|level| MeshLod {
+            geometry: Rc::new(RefCell::new(Geometry::from_mesh_data(context, &level.mesh))),
+            error: level.error,
+          })
+          .collect();
+
       let material = if let Some(material_id) = obj.mesh.material_id {
         Rc::clone(&materials[material_id])
       } else {
         Rc::new(RefCell::new(Material {
-          albedo_map: resource_manager.get_texture(&Path::new("data/empty.bmp")).unwrap(),
+          name: String::new(),
+          albedo_map: resource_manager.get_texture(&Path::new("data/empty.bmp"))?,
           ambient_color: [0.0, 0.0, 0.0],
           diffuse_color: [0.0, 0.0, 0.0],
           specular_color: [0.0, 0.0, 0.0],
           shininess: 0.0,
           metalness: 0.0,
           reflectivity: 0.0,
+          roughness: 1.0,
+          shading: Shading::BlinnPhong,
+          emissive_color: [0.0, 0.0, 0.0],
+          dissolve: 1.0,
+          alpha_mode: AlphaMode::Opaque,
+          alpha_cutoff: 0.5,
+          maps: MaterialMaps::default(),
         }))
       };
 
+      let statistics = mesh_data.get_statistics();
+      num_vertices += statistics.num_vertices;
+      num_triangles += statistics.num_triangles;
+      area += statistics.area;
+
+      let mut geometry = Geometry::from_mesh_data(context, &mesh_data);
+      geometry.mesh_data = resource_manager.retain_mesh_data(mesh_data);
+
+      let mut mesh = Mesh::new(context, Rc::new(RefCell::new(geometry)), material,
+          resource_manager)?;
+      mesh.lods = lods;
+
       objects.push(Object {
         children: Vec::new(),
-        drawable: Some(Box::new(Mesh::new(
-            context,
-            Rc::new(RefCell::new(Geometry {
-              bounding_box,
-              indices: indices,
-              normals: normals,
-              vertices: vertices,
-              texcoords: texcoords,
-            })),
-            material,
-            resource_manager))),
+        drawable: Some(Box::new(mesh)),
         transform: Matrix4::<f32>::identity(),
         size: size,
+        bounds: None,
       });
     }
 
@@ -194,6 +291,11 @@ impl Object {
       }
     });
 
+    if resource_manager.verbose {
+      println!("from_file: {}: {} vertices, {} triangles, area {}", path.to_str().unwrap_or(""),
+          num_vertices, num_triangles, area);
+    }
+
     let lengths = global_bounding_box.1.iter().zip(global_bounding_box.0.iter()).map(|x| x.0 - x.1);
     let target_length = 450.0; // 2²+2²+2²
     let current_length = lengths.fold(0.0, |result, x| result + f32::powf(x, 2.0));
@@ -201,89 +303,440 @@ impl Object {
     let scale = Matrix4::from_scale(f32::sqrt(target_length / current_length));
     let translation = Matrix4::from_translation(Vector3::new(0.0, 1.0, 0.0));
 
-    Object {
+    Ok(Object {
       children: objects,
       drawable: None,
       transform: translation * scale,
       size: 0.0,
-    }
+      bounds: None,
+    })
   }
 
   pub fn new_plane<F>(context: &F, resource_manager: &ResourceManager,
       material: Rc<RefCell<Material>>, size: [f32;2], pos: [f32;3], rot: [f32;3], scale: [f32;3])
-      -> Object
+      -> Result<Object, EngynError>
       where F: Facade {
     let rotation = Matrix4::from(Euler { x: Rad(rot[0]), y: Rad(rot[1]), z: Rad(rot[2]) });
     let scale_mat = Matrix4::from_nonuniform_scale(scale[0], scale[1], scale[2]);
     let translation = Matrix4::from_translation(Vector3::new(pos[0], pos[1], pos[2]));
     let matrix = translation * scale_mat * rotation;
 
-    Object {
+    Ok(Object {
       children: Vec::new(),
       drawable: Some(Box::new(Mesh::new(
           context,
           Rc::new(RefCell::new(Geometry::new_quad(context, size, false))),
           material,
-          resource_manager))),
+          resource_manager)?)),
       transform: matrix,
       size: size[0] * scale[0] * size[1] * scale[1],
-    }
+      bounds: None,
+    })
   }
 
   pub fn new_triangle<F>(context: &F, resource_manager: &ResourceManager,
       material: Rc<RefCell<Material>>, size: [f32;2], pos: [f32;3], rot: [f32;3], scale: [f32;3])
-      -> Object
+      -> Result<Object, EngynError>
       where F: Facade{
     let rotation = Matrix4::from(Euler { x: Rad(rot[0]), y: Rad(rot[1]), z: Rad(rot[2]) });
     let scale_mat = Matrix4::from_nonuniform_scale(scale[0], scale[1], scale[2]);
     let translation = Matrix4::from_translation(Vector3::new(pos[0], pos[1], pos[2]));
     let matrix = translation * scale_mat * rotation;
 
-    Object {
+    Ok(Object {
       children: Vec::new(),
       drawable: Some(Box::new(Mesh::new(
           context,
           Rc::new(RefCell::new(Geometry::new_triangle(context, size))),
           material,
-          resource_manager))),
+          resource_manager)?)),
       transform: matrix,
       size: (size[0] * scale[0] * size[1] * scale[1]).sqrt(),
+      bounds: None,
+    })
+  }
+
+  // replaces the materials of this object and its children for which `replace` gives a new one
+  pub fn replace_materials(&mut self, context: &Facade, resource_manager: &ResourceManager,
+      replace: &mut FnMut(&Rc<RefCell<Material>>)
+          -> Result<Option<Rc<RefCell<Material>>>, EngynError>) -> Result<(), EngynError> {
+    if let Some(ref mut d) = self.drawable {
+      if let Some(material) = d.get_material() {
+        if let Some(new_material) = replace(&material)? {
+          d.set_material(context, resource_manager, &new_material)?;
+        }
+      }
     }
+
+    for child in &mut self.children {
+      child.replace_materials(context, resource_manager, replace)?;
+    }
+
+    Ok(())
   }
 
-  pub fn draw(&mut self, quality_level: f32, i: u32, num_objects: u32,
-      target: &mut SimpleFrameBuffer, context: &Display, projection: [[f32; 4]; 4],
-      view: [[f32; 4]; 4], render_params: &DrawParameters, num_lights: i32, lights: &[Light; 32],
-      eye_i: usize, is_anaglyph: bool, show_bbox: bool) -> u32 {
-    let root = Matrix4::<f32>::identity();
-    self.draw_recurse(quality_level, i, num_objects, target, context, projection, view, root, render_params,
-        num_lights, lights, eye_i, is_anaglyph, show_bbox)
+  // the screen-space error in pixels that is allowed at the given LOD quality level
+  pub fn get_pixel_error(quality_level: f32) -> f32 {
+    MAX_PIXEL_ERROR * (MIN_PIXEL_ERROR / MAX_PIXEL_ERROR).powf(quality_level)
+  }
+
+  // The largest geometric error in object space that stays below `pixel_error` on screen, based
+  // on the nearest point of the bounding sphere. If the whole bounding box is smaller on screen
+  // than `pixel_error`, any error is allowed, so the coarsest LOD is drawn.
+  fn get_max_error(bounding_box: &([f32; 3], [f32; 3]), model_transform: Matrix4<f32>,
+      view: Matrix4<f32>, projection: [[f32; 4]; 4], viewport_height: f32, pixel_error: f32)
+      -> f32 {
+    let min = Vector3::from(bounding_box.0);
+    let max = Vector3::from(bounding_box.1);
+    let center = (min + max) * 0.5;
+    let scale = f32::max(model_transform.x.truncate().magnitude(), f32::max(
+        model_transform.y.truncate().magnitude(),
+        model_transform.z.truncate().magnitude()));
+    let radius = (max - min).magnitude() * 0.5 * scale;
+
+    let center_eye = view * model_transform * center.extend(1.0);
+    let distance = f32::max(-center_eye.z - radius, NEAR_DISTANCE);
+    let pixels_per_unit = projection[1][1] * viewport_height * 0.5 / distance;
+
+    if 2.0 * radius * pixels_per_unit < pixel_error {
+      f32::INFINITY
+    } else {
+      pixel_error / (pixels_per_unit * scale)
+    }
+  }
+
+  // lists the objects with a drawable in the world, with their bounds in world space
+  pub fn collect_bvh_items(world: &[Object]) -> Vec<BvhItem> {
+    let mut items = Vec::new();
+
+    for (i, object) in world.iter().enumerate() {
+      object.collect_bvh_items_recurse(vec![i], Matrix4::identity(), &mut items);
+    }
+
+    items
   }
 
-  fn draw_recurse(&mut self, quality_level: f32, i: u32, num_objects: u32, target: &mut SimpleFrameBuffer, context: &Display,
-      projection: [[f32; 4]; 4], view: [[f32; 4]; 4], group: Matrix4<f32>,
-      render_params: &DrawParameters, num_lights: i32, lights: &[Light; 32], eye_i: usize,
-      is_anaglyph: bool, show_bbox: bool) -> u32 {
+  fn collect_bvh_items_recurse(&self, path: Vec<usize>, group: Matrix4<f32>,
+      items: &mut Vec<BvhItem>) {
     let model_transform = group * self.transform;
 
-    match self.drawable {
-      Some(ref mut d) => d.draw(target, context, projection, view, model_transform, render_params,
-          num_lights, lights, eye_i, is_anaglyph, show_bbox),
-      None => (),
+    if let Some(ref d) = self.drawable {
+      items.push(BvhItem {
+        path: path.clone(),
+        bounds: d.get_geometry().map(|geometry| {
+          math::transform_bounding_box(&geometry.borrow().bounding_box, &model_transform)
+        }),
+        transform: model_transform,
+        num_triangles: d.get_num_triangles(0.0),
+      });
+    }
+
+    for (i, child) in self.children.iter().enumerate() {
+      let mut child_path = path.clone();
+      child_path.push(i);
+      child.collect_bvh_items_recurse(child_path, model_transform, items);
     }
+  }
 
-    let mut result = i + 1;
+  // Updates the items of the hierarchy whose transform changed and the bounds of the objects
+  // above them, then refits it. The items are visited in the order `collect_bvh_items` lists
+  // them; if objects with a drawable were added or removed, the hierarchy is built again.
+  pub fn update_bvh(world: &mut [Object], bvh: &mut Bvh) {
+    let mut index = 0;
 
-    if i == (1 * num_objects / 4) || i == (2 * num_objects / 4) || i == (3 * num_objects / 4) {
-      context.flush();
+    for object in world.iter_mut() {
+      object.update_bvh_recurse(Matrix4::identity(), bvh, &mut index);
     }
 
-    for object in &mut self.children {
-      if quality_level > (result as f32 / num_objects as f32) {
-        result = object.draw_recurse(quality_level, result, num_objects, target, context, projection, view,
-            model_transform, render_params, num_lights, lights, eye_i, is_anaglyph, show_bbox);
+    if index != bvh.get_items().len() {
+      *bvh = Bvh::build(Object::collect_bvh_items(world));
+    } else {
+      bvh.refit();
+    }
+  }
+
+  // returns whether anything in this object moved
+  fn update_bvh_recurse(&mut self, group: Matrix4<f32>, bvh: &mut Bvh, index: &mut usize)
+      -> bool {
+    let model_transform = group * self.transform;
+    let mut is_moved = false;
+
+    if let Some(ref d) = self.drawable {
+      let is_changed = bvh.get_items().get(*index)
+          .map_or(false, |item| item.transform != model_transform);
+
+      if is_changed {
+        bvh.update_item(*index, model_transform, d.get_geometry().map(|geometry| {
+          math::transform_bounding_box(&geometry.borrow().bounding_box, &model_transform)
+        }));
+        is_moved = true;
       }
+
+      *index += 1;
     }
+
+    for child in &mut self.children {
+      is_moved = child.update_bvh_recurse(model_transform, bvh, index) || is_moved;
+    }
+
+    // the bounds of the parents follow, so hierarchical culling doesn't use stale bounds
+    if is_moved {
+      self.merge_bounds();
+    }
+
+    is_moved
+  }
+
+  // follows a path of child indices from the world to an object
+  pub fn find<'a>(world: &'a [Object], path: &[usize]) -> &'a Object {
+    let mut object = &world[path[0]];
+
+    for i in &path[1..] {
+      object = &object.children[*i];
+    }
+
+    object
+  }
+
+  pub fn find_mut<'a>(world: &'a mut [Object], path: &[usize]) -> &'a mut Object {
+    let mut object = &mut world[path[0]];
+
+    for i in &path[1..] {
+      let current = object;
+      object = &mut current.children[*i];
+    }
+
+    object
+  }
+
+  // draws the objects in the world that the hierarchy finds in the view frustum
+  pub fn draw_world(world: &mut [Object], bvh: &Bvh, pixel_error: f32,
+      target: &mut SimpleFrameBuffer, context: &Display, projection: [[f32; 4]; 4],
+      view: [[f32; 4]; 4], render_params: &DrawParameters, lighting: &Lighting,
+      eye_i: usize, is_anaglyph: bool, show_bbox: bool, selection: Option<&[usize]>,
+      occlusion: &mut OcclusionCuller,
+      view_index: usize, stats: &mut DrawStats) {
+    let frustum = Frustum::from_matrix(&(Matrix4::from(projection) * Matrix4::from(view)));
+    let visible = bvh.cull(&frustum);
+    let items = bvh.get_items();
+    let viewport_height = render_params.viewport.map_or(1.0, |v| v.height as f32);
+
+    let mut is_culled = vec![true; items.len()];
+    for index in &visible {
+      is_culled[*index] = false;
+    }
+
+    for (item, _) in items.iter().zip(is_culled.iter()).filter(|x| *x.1) {
+      stats.culled_objects += 1;
+      stats.culled_triangles += item.num_triangles;
+    }
+
+    // blended objects are drawn after the opaque ones, with the max error they were given
+    let mut blended = Vec::new();
+
+    for (n, index) in visible.iter().enumerate() {
+      let item = &items[*index];
+
+      if occlusion.is_occluded(view_index, item, &Matrix4::from(view)) {
+        stats.occluded_objects += 1;
+        stats.occluded_triangles += item.num_triangles;
+        continue;
+      }
+
+      let object = Object::find_mut(world, &item.path);
+      let show_bbox = show_bbox || selection == Some(&item.path[..]);
+
+      if let Some(ref mut d) = object.drawable {
+        let max_error = match d.get_geometry() {
+          Some(geometry) => Object::get_max_error(&geometry.borrow().bounding_box,
+              item.transform, Matrix4::from(view), projection, viewport_height, pixel_error),
+          None => 0.0,
+        };
+
+        if d.is_blended() {
+          blended.push((*index, max_error));
+        } else {
+          d.draw(target, context, projection, view, item.transform, max_error, render_params,
+              lighting, eye_i, is_anaglyph, show_bbox);
+          stats.drawn_objects += 1;
+          stats.drawn_triangles += d.get_num_triangles(max_error);
+        }
+      }
+
+      let num_visible = visible.len();
+      if n == (1 * num_visible / 4) || n == (2 * num_visible / 4) || n == (3 * num_visible / 4) {
+        context.flush();
+      }
+    }
+
+    // occluded objects are tested as well, so they can reappear in the next frame; this happens
+    // before the transparent pass, because blended objects don't occlude anything
+    let query_start = Instant::now();
+    let tested: Vec<&BvhItem> = visible.iter().map(|i| &items[*i]).collect();
+    stats.occlusion_queries += occlusion.issue_queries(context, target, projection, view,
+        render_params, view_index, &tested);
+    stats.occlusion_cpu_nanos += query_start.elapsed().subsec_nanos();
+
+    // back to front, i.e. from the most negative depth in view space
+    let view_matrix = Matrix4::from(view);
+    let mut blended: Vec<(f32, usize, f32)> = blended.into_iter().map(|(index, max_error)| {
+      let center = match items[index].bounds {
+        Some(ref b) => Vector3::new(
+            (b.0[0] + b.1[0]) * 0.5, (b.0[1] + b.1[1]) * 0.5, (b.0[2] + b.1[2]) * 0.5),
+        None => items[index].transform.w.truncate(),
+      };
+      ((view_matrix * center.extend(1.0)).z, index, max_error)
+    }).collect();
+    blended.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+
+    let mut blend_params = render_params.clone();
+    blend_params.blend = Blend::alpha_blending();
+    blend_params.depth.write = false;
+
+    for &(_, index, max_error) in &blended {
+      let item = &items[index];
+      let object = Object::find_mut(world, &item.path);
+      let show_bbox = show_bbox || selection == Some(&item.path[..]);
+
+      if let Some(ref mut d) = object.drawable {
+        d.draw(target, context, projection, view, item.transform, max_error, &blend_params,
+            lighting, eye_i, is_anaglyph, show_bbox);
+        stats.drawn_objects += 1;
+        stats.blended_objects += 1;
+        stats.drawn_triangles += d.get_num_triangles(max_error);
+      }
+    }
+  }
+
+  // computes the bounds of this object and its children, in the space of this object's parent
+  pub fn update_bounds(&mut self) {
+    for child in &mut self.children {
+      child.update_bounds();
+    }
+
+    self.merge_bounds();
+  }
+
+  // the bounds of the drawable merged with the bounds that the children already have
+  fn merge_bounds(&mut self) {
+    let mut bounds = match self.drawable {
+      Some(ref d) => match d.get_geometry() {
+        Some(geometry) => Some(geometry.borrow().bounding_box),
+        None => None,
+      },
+      None => Some(math::empty_bounding_box()),
+    };
+
+    for child in &self.children {
+      bounds = match (bounds, child.bounds) {
+        (Some(a), Some(b)) => Some(math::merge_bounding_boxes(&a, &b)),
+        _ => None,
+      };
+    }
+
+    self.bounds = bounds.map(|b| math::transform_bounding_box(&b, &self.transform));
+  }
+
+  // the number of objects that draw something and their triangles
+  fn count_recurse(&self) -> (u32, usize) {
+    let mut result = match self.drawable {
+      Some(ref d) => (1, d.get_num_triangles(0.0)),
+      None => (0, 0),
+    };
+
+    for child in &self.children {
+      let counts = child.count_recurse();
+      result = (result.0 + counts.0, result.1 + counts.1);
+    }
+
     result
   }
+
+  pub fn draw(&mut self, pixel_error: f32, target: &mut SimpleFrameBuffer, context: &Display,
+      projection: [[f32; 4]; 4], view: [[f32; 4]; 4], render_params: &DrawParameters,
+      lighting: &Lighting, eye_i: usize, is_anaglyph: bool, show_bbox: bool,
+      stats: &mut DrawStats) {
+    let root = Matrix4::<f32>::identity();
+    let frustum = Frustum::from_matrix(&(Matrix4::from(projection) * Matrix4::from(view)));
+    self.draw_recurse(pixel_error, target, context, projection, view, Some(&frustum), root,
+        render_params, lighting, eye_i, is_anaglyph, show_bbox, stats);
+  }
+
+  // `frustum` is None when this object is known to be completely inside it
+  fn draw_recurse(&mut self, pixel_error: f32, target: &mut SimpleFrameBuffer, context: &Display,
+      projection: [[f32; 4]; 4], view: [[f32; 4]; 4], frustum: Option<&Frustum>, group: Matrix4<f32>,
+      render_params: &DrawParameters, lighting: &Lighting, eye_i: usize,
+      is_anaglyph: bool, show_bbox: bool, stats: &mut DrawStats) {
+    let intersection = match (frustum, self.bounds) {
+      (Some(f), Some(bounds)) => f.intersect_box(&math::transform_bounding_box(&bounds, &group)),
+      (Some(_), None) => Intersection::Intersecting,
+      (None, _) => Intersection::Inside,
+    };
+
+    if intersection == Intersection::Outside {
+      let (num_drawables, num_triangles) = self.count_recurse();
+      stats.culled_objects += num_drawables;
+      stats.culled_triangles += num_triangles;
+      return;
+    }
+
+    let frustum = if intersection == Intersection::Inside { None } else { frustum };
+    let model_transform = group * self.transform;
+    let viewport_height = render_params.viewport.map_or(1.0, |v| v.height as f32);
+
+    match self.drawable {
+      Some(ref mut d) => {
+        let max_error = match d.get_geometry() {
+          Some(geometry) => Object::get_max_error(&geometry.borrow().bounding_box,
+              model_transform, Matrix4::from(view), projection, viewport_height, pixel_error),
+          None => 0.0,
+        };
+
+        d.draw(target, context, projection, view, model_transform, max_error, render_params,
+            lighting, eye_i, is_anaglyph, show_bbox);
+        stats.drawn_objects += 1;
+        stats.drawn_triangles += d.get_num_triangles(max_error);
+      },
+      None => (),
+    }
+
+    for object in &mut self.children {
+      object.draw_recurse(pixel_error, target, context, projection, view, frustum,
+          model_transform, render_params, lighting, eye_i, is_anaglyph, show_bbox, stats);
+    }
+  }
+}
+
+// the texture of a material, from its tobj field or else from the first MTL statement in `keys`
+// that tobj doesn't know; missing files are skipped, so the material is drawn without them
+fn get_map_path(obj_dir: &Path, mtl: &tobj::Material, texture: &str, keys: &[&str])
+    -> Option<PathBuf> {
+  let statement = if !texture.is_empty() {
+    Some(texture)
+  } else {
+    keys.iter().filter_map(|key| mtl.unknown_param.get(*key)).map(|s| &s[..]).next()
+  };
+
+  // options like `-bm 1.0` come before the file name
+  let path = match statement.and_then(|s| s.split_whitespace().last()) {
+    Some(name) => obj_dir.join(name),
+    None => return None,
+  };
+
+  if path.is_file() {
+    Some(path)
+  } else {
+    eprintln!("Could not find texture of material {}: {}", mtl.name, path.display());
+    None
+  }
+}
+
+fn parse_color(value: &str) -> Option<[f32; 3]> {
+  let components: Vec<f32> = value.split_whitespace().filter_map(|c| c.parse().ok()).collect();
+
+  if components.len() == 3 {
+    Some([components[0], components[1], components[2]])
+  } else {
+    None
+  }
 }
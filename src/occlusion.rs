@@ -0,0 +1,250 @@
+// Copyright (c) 2018 Remco Kranenburg
+//
+// GNU GENERAL PUBLIC LICENSE
+//    Version 3, 29 June 2007
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use cgmath::Matrix4;
+use cgmath::SquareMatrix;
+use glium::BackfaceCullingMode;
+use glium::Depth;
+use glium::DepthTest;
+use glium::DrawParameters;
+use glium::IndexBuffer;
+use glium::Program;
+use glium::Surface;
+use glium::VertexBuffer;
+use glium::backend::Facade;
+use glium::draw_parameters::AnySamplesPassedQuery;
+use glium::framebuffer::SimpleFrameBuffer;
+use glium::index::PrimitiveType;
+use std::collections::HashMap;
+
+use bvh::BvhItem;
+use geometry::BoundingBox;
+use geometry::Vertex;
+use quality::Quality;
+
+// visible objects are tested again after this many frames, hidden ones every frame
+const VISIBLE_QUERY_INTERVAL: u32 = 8;
+
+// what is known about the visibility of one object in one view
+struct ItemState {
+  query: Option<AnySamplesPassedQuery>,
+  is_visible: bool,
+  frames_until_query: u32,
+}
+
+// Culls objects that were hidden behind others in the previous frame. After drawing, the bounding
+// box of objects in the view frustum is drawn against the depth buffer inside an occlusion query.
+// The next frames use the results that are available by then, so it never waits for the GPU;
+// objects without a result yet are drawn. Queries can't be reused, so objects that were visible
+// are only tested every few frames, which keeps the number of queries per frame down.
+// Results are kept per view and object path, so they stay valid when the hierarchy is rebuilt.
+pub struct OcclusionCuller {
+  pub enabled: bool,
+
+  is_culling: bool,
+  program: Program,
+  box_vertices: VertexBuffer<Vertex>,
+  box_indices: IndexBuffer<u16>,
+  items: Vec<HashMap<Vec<usize>, ItemState>>,
+}
+
+impl OcclusionCuller {
+  pub fn register_quality_knobs(quality: &mut Quality) {
+    quality.register_knob("Occlusion", "Occlusion culling weight", (0.0, 1.0), 0.1, 0.5);
+  }
+
+  pub fn new(display: &Facade) -> OcclusionCuller {
+    let corners: Vec<Vertex> = (0..8).map(|i| Vertex { position: (
+      if i & 1 == 0 { 0.0 } else { 1.0 },
+      if i & 2 == 0 { 0.0 } else { 1.0 },
+      if i & 4 == 0 { 0.0 } else { 1.0 },
+    )}).collect();
+
+    OcclusionCuller {
+      enabled: false,
+      is_culling: true,
+      program: create_program(display),
+      box_vertices: VertexBuffer::new(display, &corners).unwrap(),
+      box_indices: IndexBuffer::new(display, PrimitiveType::TrianglesList, &[
+        0u16, 2, 1, 1, 2, 3, // back
+        4, 5, 6, 5, 7, 6,    // front
+        0, 1, 4, 1, 5, 4,    // bottom
+        2, 6, 3, 3, 6, 7,    // top
+        0, 4, 2, 2, 4, 6,    // left
+        1, 3, 5, 3, 7, 5,    // right
+      ]).unwrap(),
+      items: Vec::new(),
+    }
+  }
+
+  // forgets all results, e.g. when switching stereo modes changes what each view is
+  pub fn reset(&mut self) {
+    self.items.clear();
+  }
+
+  // Culling makes objects that come into view appear a frame late, so it is only done at quality
+  // levels below the middle.
+  pub fn set_quality_scale(&mut self, scale: f32) {
+    let is_culling = scale < 0.5;
+
+    // results from before culling was turned off are stale
+    if is_culling && !self.is_culling {
+      self.reset();
+    }
+
+    self.is_culling = is_culling;
+  }
+
+  pub fn is_active(&self) -> bool {
+    self.enabled && self.is_culling
+  }
+
+  // whether the item was hidden according to the latest available result for this view
+  pub fn is_occluded(&mut self, view_index: usize, item: &BvhItem, view: &Matrix4<f32>) -> bool {
+    if !self.is_active() { return false; }
+
+    let state = match self.items.get_mut(view_index).and_then(|v| v.get_mut(&item.path[..])) {
+      Some(state) => state,
+      None => return false,
+    };
+
+    let is_ready = state.query.as_ref().map_or(false, |query| query.is_ready());
+    if is_ready {
+      state.is_visible = state.query.take().unwrap().get();
+      state.frames_until_query = if state.is_visible { VISIBLE_QUERY_INTERVAL } else { 0 };
+    }
+
+    match item.bounds {
+      // a box around the camera can't be tested by drawing it
+      Some(ref bounds) if !contains_camera(bounds, view) => !state.is_visible,
+      _ => false,
+    }
+  }
+
+  // queries the visibility of the bounding boxes of the items for the next frame; returns the
+  // number of queries that were issued
+  pub fn issue_queries(&mut self, display: &Facade, target: &mut SimpleFrameBuffer,
+      projection: [[f32; 4]; 4], view: [[f32; 4]; 4], render_params: &DrawParameters,
+      view_index: usize, items: &[&BvhItem]) -> u32 {
+    if !self.is_active() { return 0; }
+
+    while self.items.len() <= view_index {
+      self.items.push(HashMap::new());
+    }
+
+    let mut num_queries = 0;
+
+    for item in items {
+      let bounds = match item.bounds {
+        Some(bounds) => bounds,
+        None => continue,
+      };
+
+      if !self.items[view_index].contains_key(&item.path[..]) {
+        self.items[view_index].insert(item.path.clone(), ItemState {
+          query: None,
+          is_visible: true,
+          frames_until_query: 0,
+        });
+      }
+
+      let state = self.items[view_index].get_mut(&item.path[..]).unwrap();
+
+      // wait for the previous query of this item instead of piling them up
+      if state.query.is_some() { continue; }
+
+      if state.frames_until_query > 0 {
+        state.frames_until_query -= 1;
+        continue;
+      }
+
+      let query = AnySamplesPassedQuery::new(display, true).unwrap();
+
+      {
+        let params = DrawParameters {
+          depth: Depth {
+            test: DepthTest::IfLessOrEqual,
+            write: false,
+            .. Default::default()
+          },
+          color_mask: (false, false, false, false),
+          backface_culling: BackfaceCullingMode::CullingDisabled,
+          viewport: render_params.viewport,
+          samples_passed_query: Some((&query).into()),
+          .. Default::default()
+        };
+
+        let uniforms = uniform! {
+          projection: projection,
+          view: view,
+          box_min: bounds.0,
+          box_max: bounds.1,
+        };
+
+        target.draw(&self.box_vertices, &self.box_indices, &self.program, &uniforms, &params)
+            .unwrap();
+      }
+
+      state.query = Some(query);
+      num_queries += 1;
+    }
+
+    num_queries
+  }
+}
+
+fn contains_camera(bounds: &BoundingBox, view: &Matrix4<f32>) -> bool {
+  let camera = match view.invert() {
+    Some(inverse) => inverse.w,
+    None => return true,
+  };
+
+  // leave some room for the near plane
+  let margin = 0.05;
+
+  (0..3).all(|i| camera[i] >= bounds.0[i] - margin && camera[i] <= bounds.1[i] + margin)
+}
+
+fn create_program(display: &Facade) -> Program {
+  Program::from_source(
+      display,
+      &r#"
+        #version 140
+
+        uniform mat4 projection;
+        uniform mat4 view;
+        uniform vec3 box_min;
+        uniform vec3 box_max;
+
+        in vec3 position;
+
+        void main() {
+          gl_Position = projection * view * vec4(mix(box_min, box_max, position), 1.0);
+        }
+      "#,
+      &r#"
+        #version 140
+
+        out vec4 color;
+
+        void main() {
+          color = vec4(1.0);
+        }
+      "#,
+      None).unwrap()
+}
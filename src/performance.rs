@@ -17,12 +17,15 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::cmp;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::f32;
 use std::fmt::Write;
 use std::time::Instant;
 use webvr::VRDisplayPtr;
 
+use prediction::FrameTimePredictor;
 use quality::Quality;
 
 const TARGET_FRAME_TIMES: [u32; 5] = [
@@ -39,13 +42,13 @@ pub struct LogEntry {
   pub sample_number: usize,
   pub event_instants: HashMap<String, Instant>,
   pub level: f32,
-  pub weight_resolution: f32,
-  pub weight_msaa: f32,
-  pub weight_lod: f32,
-  pub target_resolution: f32,
-  pub target_msaa: f32,
-  pub target_lod: f32,
+  pub weights: Vec<f32>,
+  pub targets: Vec<f32>,
   pub quality_stats: (u32, u32, f32),
+  pub controller: String,
+  pub controller_gains: (f32, f32, f32, f32),
+  pub prediction_error: Option<i64>,
+  pub counters: BTreeMap<String, f64>,
 }
 
 pub struct FramePerformance {
@@ -54,51 +57,64 @@ pub struct FramePerformance {
   current_fps_target: usize,
   frame_count: usize,
   level: f32,
-  weight_resolution: f32,
-  weight_msaa: f32,
-  weight_lod: f32,
-  target_resolution: f32,
-  target_msaa: f32,
-  target_lod: f32,
+  knob_names: Vec<String>,
+  weights: Vec<f32>,
+  targets: Vec<f32>,
   quality_stats: (u32, u32, f32),
+  controller: String,
+  controller_gains: (f32, f32, f32, f32),
+  predictor: Box<FrameTimePredictor>,
+  last_prediction: Option<u32>,
+  counters: BTreeMap<String, f64>,
 }
 
 impl FramePerformance {
-  pub fn new(vr_mode: bool) -> FramePerformance {
+  pub fn new(vr_mode: bool, predictor: Box<FrameTimePredictor>) -> FramePerformance {
     FramePerformance {
       log: Vec::new(),
       event_instants: HashMap::new(),
       current_fps_target: if vr_mode { 0 } else { 1 },
       frame_count: 0,
       level: 0.0,
-      weight_resolution: 0.0,
-      weight_msaa: 0.0,
-      weight_lod: 0.0,
-      target_resolution: 0.0,
-      target_msaa: 0.0,
-      target_lod: 0.0,
+      knob_names: Vec::new(),
+      weights: Vec::new(),
+      targets: Vec::new(),
       quality_stats: (0, 0, 0.0),
+      controller: String::new(),
+      controller_gains: (0.0, 0.0, 0.0, 0.0),
+      predictor: predictor,
+      last_prediction: None,
+      counters: BTreeMap::new(),
     }
   }
 
   pub fn reset_frame_count(&mut self) {
     self.frame_count = 0;
+    self.last_prediction = None;
   }
 
   pub fn process_event(&mut self, event: &str) {
     self.event_instants.insert(event.to_owned(), Instant::now());
   }
 
-  pub fn start_frame(&mut self, quality: &Quality) {
-    let targets = quality.get_target_levels();
+  pub fn start_frame(&mut self, quality: &Quality, targets: &[f32]) {
     self.level = *quality.level.borrow();
-    self.weight_resolution = *quality.weight_resolution.borrow();
-    self.weight_msaa = *quality.weight_msaa.borrow();
-    self.weight_lod = *quality.weight_lod.borrow();
-    self.target_resolution = targets.0;
-    self.target_msaa = targets.1;
-    self.target_lod = targets.2;
+    self.knob_names = quality.get_knob_names();
+    self.weights = quality.get_weights();
+    self.targets = targets.to_vec();
     self.quality_stats = quality.quality_stats;
+    self.controller = quality.controller.get_name().to_owned();
+    self.controller_gains = quality.controller.get_gains();
+    self.counters.clear();
+  }
+
+  // sets a named value that is logged with the current frame, e.g. the number of drawn objects
+  pub fn set_counter(&mut self, name: &str, value: f64) {
+    self.counters.insert(name.to_owned(), value);
+  }
+
+  pub fn add_counter(&mut self, name: &str, value: f64) {
+    *self.counters.entry(name.to_owned()).or_insert(0.0) += value;
   }
 
   pub fn record_frame_log(&mut self, sample_number: usize, analysis_target: &str) {
@@ -108,13 +124,13 @@ impl FramePerformance {
       sample_number: sample_number,
       event_instants: self.event_instants.clone(),
       level: self.level,
-      weight_resolution: self.weight_resolution,
-      weight_msaa: self.weight_msaa,
-      weight_lod: self.weight_lod,
-      target_resolution: self.target_resolution,
-      target_msaa: self.target_msaa,
-      target_lod: self.target_lod,
+      weights: self.weights.clone(),
+      targets: self.targets.clone(),
       quality_stats: self.quality_stats,
+      controller: self.controller.clone(),
+      controller_gains: self.controller_gains,
+      prediction_error: None,
+      counters: self.counters.clone(),
     });
     self.frame_count += 1;
   }
@@ -123,6 +139,10 @@ impl FramePerformance {
     self.frame_count
   }
 
+  pub fn get_log(&self) -> &Vec<LogEntry> {
+    &self.log
+  }
+
   pub fn get_remaining_time(&self) -> u32 {
     let mut log_rev_iter = self.log.iter().rev();
 
@@ -151,7 +171,7 @@ impl FramePerformance {
       Ok(f32::max(0.0, idle_ms * 1000f32 * 1000f32) as u32)
   }
 
-  pub fn get_predicted_remaining_time(&self, vr_display: Option<&VRDisplayPtr>) -> u32 {
+  pub fn get_predicted_remaining_time(&mut self, vr_display: Option<&VRDisplayPtr>) -> u32 {
     if self.frame_count < 1 {
       return 11_111_111u32;
     }
@@ -164,8 +184,32 @@ impl FramePerformance {
       None => self.get_remaining_time(),
     };
 
-    // predict next remaining time
-    remaining_time
+    let target_frame_time = self.get_target_frame_time();
+
+    // now that the last frame is done, we know how far off its prediction was
+    if let Some(prediction) = self.last_prediction.take() {
+      if let Some(last_frame) = self.log.last_mut() {
+        last_frame.prediction_error = Some(remaining_time as i64 - prediction as i64);
+      }
+    }
+
+    // self.level is still the level of the last frame at this point
+    self.predictor.observe(self.level, remaining_time, target_frame_time);
+
+    // the controller starts out from what happens if the level stays the same
+    self.predictor.predict(self.level, target_frame_time)
+  }
+
+  // Predicts the remaining time of the next frame if it is drawn at the given level, so a
+  // controller can try candidate levels.
+  pub fn predict_remaining_time(&self, level: f32) -> u32 {
+    self.predictor.predict(level, self.get_target_frame_time())
+  }
+
+  // Remembers the prediction for the level the next frame is actually drawn at, which is what
+  // its prediction error is measured against.
+  pub fn record_prediction(&mut self, level: f32) {
+    self.last_prediction = Some(self.predict_remaining_time(level));
   }
 
   pub fn get_target_frame_time(&self) -> u32 {
@@ -218,6 +262,8 @@ impl FramePerformance {
       "post_update_camera",
       "pre_update_world",
       "post_update_world",
+      "pre_shadows",
+      "post_shadows",
       "pre_sync_poses",
       "post_sync_poses",
       "pre_sync_frame_data",
@@ -230,7 +276,22 @@ impl FramePerformance {
     let mut log_csv = String::new();
     log_csv.push_str("AnalysisTarget,Frame,Sample,Dropped,TimeStart,TimeEnd,");
     log_csv.push_str(&keys.join(","));
-    log_csv.push_str(",Level,WeightResolution,WeightMSAA,WeightLOD,TargetResolution,TargetMSAA,TargetLOD,TargetFrameTime,PredictedRemainingTime,RatioRemaining\n");
+    log_csv.push_str(",Level,");
+    for name in &self.knob_names {
+      write!(&mut log_csv, "Weight{},", name).unwrap();
+    }
+    for name in &self.knob_names {
+      write!(&mut log_csv, "Target{},", name).unwrap();
+    }
+    log_csv.push_str("TargetFrameTime,PredictedRemainingTime,RatioRemaining,Controller,ControllerSetpoint,ControllerGainP,ControllerGainI,ControllerGainD,Predictor,PredictionError");
+
+    let counter_names: BTreeSet<&String> = self.log.iter()
+        .flat_map(|frame| frame.counters.keys())
+        .collect();
+    for name in &counter_names {
+      write!(&mut log_csv, ",{}", name).unwrap();
+    }
+    log_csv.push_str("\n");
 
     let first_frame_instant = self.log.first().unwrap().event_instants.get("frame_start").unwrap();
 
@@ -251,17 +312,31 @@ impl FramePerformance {
         let duration = event_instant.duration_since(*frame_start_instant).subsec_nanos();
         write!(&mut log_csv, "{},", duration).unwrap();
       }
-      write!(&mut log_csv, "{},{},{},{},{},{},{},{},{},{}\n",
-          frame.level,
-          frame.weight_resolution,
-          frame.weight_msaa,
-          frame.weight_lod,
-          frame.target_resolution,
-          frame.target_msaa,
-          frame.target_lod,
+      write!(&mut log_csv, "{},", frame.level).unwrap();
+      for weight in &frame.weights {
+        write!(&mut log_csv, "{},", weight).unwrap();
+      }
+      for target in &frame.targets {
+        write!(&mut log_csv, "{},", target).unwrap();
+      }
+      write!(&mut log_csv, "{},{},{},{},{},{},{},{},{},",
           frame.quality_stats.0,
           frame.quality_stats.1,
-          frame.quality_stats.2).unwrap();
+          frame.quality_stats.2,
+          frame.controller,
+          frame.controller_gains.0,
+          frame.controller_gains.1,
+          frame.controller_gains.2,
+          frame.controller_gains.3,
+          self.predictor.get_name()).unwrap();
+      // frames without a prediction (the first ones) get an empty cell
+      if let Some(prediction_error) = frame.prediction_error {
+        write!(&mut log_csv, "{}", prediction_error).unwrap();
+      }
+      for name in &counter_names {
+        write!(&mut log_csv, ",{}", frame.counters.get(*name).unwrap_or(&0.0)).unwrap();
+      }
+      log_csv.push_str("\n");
     }
     log_csv
   }
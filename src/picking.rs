@@ -0,0 +1,355 @@
+// Copyright (c) 2018 Remco Kranenburg
+//
+// GNU GENERAL PUBLIC LICENSE
+//    Version 3, 29 June 2007
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use cgmath::InnerSpace;
+use cgmath::Matrix;
+use cgmath::Matrix3;
+use cgmath::Matrix4;
+use cgmath::SquareMatrix;
+use cgmath::Vector3;
+use cgmath::Vector4;
+use std::f32;
+
+use bvh::Bvh;
+use geometry::MeshData;
+use object::Object;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Ray {
+  pub origin: Vector3<f32>,
+  pub direction: Vector3<f32>,
+}
+
+impl Ray {
+  // a ray along the negative z axis of a camera or controller pose
+  pub fn from_transform(transform: &Matrix4<f32>) -> Ray {
+    Ray {
+      origin: transform.w.truncate(),
+      direction: (-transform.z.truncate()).normalize(),
+    }
+  }
+
+  // a ray from a camera through a point on the screen, given in normalized device coordinates
+  pub fn from_screen(transform: &Matrix4<f32>, projection: &Matrix4<f32>, x: f32, y: f32) -> Ray {
+    let inverse_projection = projection.invert().unwrap_or(Matrix4::identity());
+    let target = inverse_projection * Vector4::new(x, y, 1.0, 1.0);
+
+    Ray {
+      origin: transform.w.truncate(),
+      direction: (transform * (target.truncate() / target.w).extend(0.0)).truncate().normalize(),
+    }
+  }
+
+  // the direction is not normalized, so distances along the ray stay the same in both spaces
+  pub fn transform(&self, transform: &Matrix4<f32>) -> Ray {
+    Ray {
+      origin: (transform * self.origin.extend(1.0)).truncate(),
+      direction: (transform * self.direction.extend(0.0)).truncate(),
+    }
+  }
+
+  pub fn get_point(&self, distance: f32) -> Vector3<f32> {
+    self.origin + self.direction * distance
+  }
+}
+
+// the closest surface that a ray hits, with `path` pointing to the object in the world
+#[derive(Clone, Debug)]
+pub struct Hit {
+  pub path: Vec<usize>,
+  pub distance: f32,
+  pub point: [f32; 3],
+  pub normal: [f32; 3],
+  pub texcoord: [f32; 2],
+}
+
+// finds the closest object that the ray hits: the hierarchy gives the objects whose boxes are
+// hit, nearest first, and their triangles are tested until no box can be closer than the hit
+pub fn pick(world: &[Object], bvh: &Bvh, ray: &Ray) -> Option<Hit> {
+  let items = bvh.get_items();
+  let mut closest: Option<Hit> = None;
+
+  for (index, box_distance) in bvh.intersect_ray(ray.origin.into(), ray.direction.into()) {
+    if closest.as_ref().map_or(false, |hit| box_distance > hit.distance) {
+      break;
+    }
+
+    let item = &items[index];
+
+    let mesh = match Object::find(world, &item.path).drawable {
+      Some(ref d) => match d.get_mesh_data() {
+        Some(mesh) => mesh,
+        None => continue,
+      },
+      None => continue,
+    };
+
+    let inverse = match item.transform.invert() {
+      Some(inverse) => inverse,
+      None => continue,
+    };
+
+    let local_ray = ray.transform(&inverse);
+
+    if let Some(surface) = intersect_mesh(&local_ray, &mesh) {
+      if closest.as_ref().map_or(false, |hit| surface.distance >= hit.distance) {
+        continue;
+      }
+
+      // normals transform with the inverse transpose of the model matrix
+      let normal_matrix = Matrix3::from_cols(
+          inverse.x.truncate(), inverse.y.truncate(), inverse.z.truncate()).transpose();
+      let normal = (normal_matrix * Vector3::from(surface.normal)).normalize();
+
+      closest = Some(Hit {
+        path: item.path.clone(),
+        distance: surface.distance,
+        point: ray.get_point(surface.distance).into(),
+        normal: normal.into(),
+        texcoord: surface.texcoord,
+      });
+    }
+  }
+
+  closest
+}
+
+pub struct SurfacePoint {
+  pub distance: f32,
+  pub normal: [f32; 3],
+  pub texcoord: [f32; 2],
+}
+
+// the closest triangle of the mesh that the ray hits, from either side
+pub fn intersect_mesh(ray: &Ray, mesh: &MeshData) -> Option<SurfacePoint> {
+  let num_vertices = if mesh.indices.is_empty() { mesh.positions.len() } else { mesh.indices.len() };
+  let get_index = |i: usize| if mesh.indices.is_empty() { i } else { mesh.indices[i] as usize };
+
+  let mut closest: Option<(f32, [usize; 3], f32, f32)> = None;
+
+  for t in 0..num_vertices / 3 {
+    let triangle = [get_index(3 * t), get_index(3 * t + 1), get_index(3 * t + 2)];
+
+    let hit = intersect_triangle(ray,
+        Vector3::from(mesh.positions[triangle[0]]),
+        Vector3::from(mesh.positions[triangle[1]]),
+        Vector3::from(mesh.positions[triangle[2]]));
+
+    if let Some((distance, u, v)) = hit {
+      if closest.map_or(true, |c| distance < c.0) {
+        closest = Some((distance, triangle, u, v));
+      }
+    }
+  }
+
+  closest.map(|(distance, triangle, u, v)| {
+    let weights = [1.0 - u - v, u, v];
+
+    let mut normal = Vector3::new(0.0, 0.0, 0.0);
+    let mut texcoord = [0.0, 0.0];
+
+    for i in 0..3 {
+      normal += Vector3::from(mesh.normals[triangle[i]]) * weights[i];
+      texcoord[0] += mesh.texcoords[triangle[i]][0] * weights[i];
+      texcoord[1] += mesh.texcoords[triangle[i]][1] * weights[i];
+    }
+
+    // meshes without normals use the normal of the face
+    if normal.magnitude2() < f32::EPSILON {
+      let a = Vector3::from(mesh.positions[triangle[0]]);
+      let b = Vector3::from(mesh.positions[triangle[1]]);
+      let c = Vector3::from(mesh.positions[triangle[2]]);
+      normal = (b - a).cross(c - a);
+    }
+
+    SurfacePoint {
+      distance: distance,
+      normal: normal.normalize().into(),
+      texcoord: texcoord,
+    }
+  })
+}
+
+// Möller-Trumbore: the distance along the ray and the barycentric coordinates of b and c
+pub fn intersect_triangle(ray: &Ray, a: Vector3<f32>, b: Vector3<f32>, c: Vector3<f32>)
+    -> Option<(f32, f32, f32)> {
+  let edge1 = b - a;
+  let edge2 = c - a;
+  let p = ray.direction.cross(edge2);
+  let determinant = edge1.dot(p);
+
+  if determinant.abs() < 1e-12 {
+    return None;
+  }
+
+  let inverse_determinant = 1.0 / determinant;
+  let s = ray.origin - a;
+  let u = s.dot(p) * inverse_determinant;
+
+  if u < 0.0 || u > 1.0 {
+    return None;
+  }
+
+  let q = s.cross(edge1);
+  let v = ray.direction.dot(q) * inverse_determinant;
+
+  if v < 0.0 || u + v > 1.0 {
+    return None;
+  }
+
+  let distance = edge2.dot(q) * inverse_determinant;
+
+  if distance < 0.0 { None } else { Some((distance, u, v)) }
+}
+
+#[cfg(test)]
+mod tests {
+  use cgmath;
+  use cgmath::Deg;
+  use cgmath::Matrix4;
+  use cgmath::Vector3;
+  use glium::DrawParameters;
+  use glium::backend::Facade;
+  use glium::framebuffer::SimpleFrameBuffer;
+  use std::rc::Rc;
+
+  use bvh::Bvh;
+  use bvh::BvhItem;
+  use drawable::Drawable;
+  use geometry::MeshData;
+  use gui::Action;
+  use light::Lighting;
+  use object::Object;
+  use super::*;
+
+  // only has mesh data, which is all that picking needs
+  struct PickableMesh(Rc<MeshData>);
+
+  impl Drawable for PickableMesh {
+    fn draw(&mut self, _: &mut SimpleFrameBuffer, _: &Facade, _: [[f32; 4]; 4], _: [[f32; 4]; 4],
+        _: Matrix4<f32>, _: f32, _: &DrawParameters, _: &Lighting, _: usize, _: bool, _: bool) {}
+
+    fn get_mesh_data(&self) -> Option<Rc<MeshData>> {
+      Some(Rc::clone(&self.0))
+    }
+
+    fn update(&mut self, _: &Facade, _: Matrix4<f32>, _: &Vec<Action>) {}
+  }
+
+  // a triangle around the z axis, facing +z, without normals
+  fn triangle_mesh() -> MeshData {
+    MeshData {
+      positions: vec![[-1.0, -1.0, 0.0], [1.0, -1.0, 0.0], [0.0, 1.0, 0.0]],
+      normals: vec![[0.0; 3]; 3],
+      texcoords: vec![[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]],
+      indices: vec![],
+    }
+  }
+
+  fn forward_ray(x: f32, y: f32) -> Ray {
+    Ray { origin: Vector3::new(x, y, 0.0), direction: Vector3::new(0.0, 0.0, -1.0) }
+  }
+
+  fn hit_triangle(ray: &Ray, z: f32) -> Option<(f32, f32, f32)> {
+    intersect_triangle(ray, Vector3::new(-1.0, -1.0, z), Vector3::new(1.0, -1.0, z),
+        Vector3::new(0.0, 1.0, z))
+  }
+
+  fn assert_close(a: f32, b: f32) {
+    assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+  }
+
+  #[test]
+  fn ray_hits_triangle() {
+    let (distance, u, v) = hit_triangle(&forward_ray(0.0, 0.0), -5.0).unwrap();
+    assert_close(distance, 5.0);
+    assert_close(u, 0.25);
+    assert_close(v, 0.5);
+  }
+
+  #[test]
+  fn ray_misses_triangle() {
+    assert!(hit_triangle(&forward_ray(2.0, 0.0), -5.0).is_none());
+    assert!(hit_triangle(&forward_ray(0.0, 1.5), -5.0).is_none());
+    assert!(hit_triangle(&forward_ray(-0.9, 0.9), -5.0).is_none());
+  }
+
+  #[test]
+  fn parallel_ray_misses_triangle() {
+    let ray = Ray { origin: Vector3::new(-5.0, 0.0, 0.0), direction: Vector3::new(1.0, 0.0, 0.0) };
+    assert!(hit_triangle(&ray, 0.0).is_none());
+    assert!(hit_triangle(&ray, -5.0).is_none());
+  }
+
+  #[test]
+  fn triangle_behind_origin_is_not_hit() {
+    assert!(hit_triangle(&forward_ray(0.0, 0.0), 5.0).is_none());
+  }
+
+  #[test]
+  fn screen_rays_go_through_the_frustum() {
+    let projection = cgmath::perspective(Deg(90.0), 1.0, 0.1, 100.0);
+    let transform = Matrix4::from_translation(Vector3::new(1.0, 2.0, 3.0));
+
+    let center = Ray::from_screen(&transform, &projection, 0.0, 0.0);
+    assert_eq!(center.origin, Vector3::new(1.0, 2.0, 3.0));
+    assert_close(center.direction.x, 0.0);
+    assert_close(center.direction.y, 0.0);
+    assert_close(center.direction.z, -1.0);
+
+    // the edge of a 90 degree frustum is at 45 degrees
+    let right = Ray::from_screen(&transform, &projection, 1.0, 0.0);
+    assert_close(right.direction.x, 0.5f32.sqrt());
+    assert_close(right.direction.y, 0.0);
+    assert_close(right.direction.z, -(0.5f32.sqrt()));
+  }
+
+  #[test]
+  fn pick_finds_nearest_mesh() {
+    let mesh = Rc::new(triangle_mesh());
+    let depths = [-10.0, -5.0];
+
+    let world: Vec<Object> = depths.iter().map(|&z| Object {
+      children: vec![],
+      drawable: Some(Box::new(PickableMesh(Rc::clone(&mesh)))),
+      transform: Matrix4::from_translation(Vector3::new(0.0, 0.0, z)),
+      size: 2.0,
+      bounds: None,
+    }).collect();
+
+    let bvh = Bvh::build(depths.iter().enumerate().map(|(i, &z)| BvhItem {
+      path: vec![i],
+      bounds: Some(([-1.0, -1.0, z - 0.01], [1.0, 1.0, z + 0.01])),
+      transform: Matrix4::from_translation(Vector3::new(0.0, 0.0, z)),
+      num_triangles: 1,
+    }).collect());
+
+    let hit = pick(&world, &bvh, &forward_ray(0.0, 0.0)).unwrap();
+    assert_eq!(hit.path, vec![1]);
+    assert_close(hit.distance, 5.0);
+    assert_close(hit.point[2], -5.0);
+    assert_close(hit.normal[2], 1.0);
+
+    // past the edge of both triangles, but still inside their boxes
+    assert!(pick(&world, &bvh, &forward_ray(0.9, 0.9)).is_none());
+
+    // looking the other way
+    let backwards = Ray { direction: Vector3::new(0.0, 0.0, 1.0), .. forward_ray(0.0, 0.0) };
+    assert!(pick(&world, &bvh, &backwards).is_none());
+  }
+}
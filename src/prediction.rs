@@ -0,0 +1,327 @@
+// Copyright (c) 2018 Remco Kranenburg
+//
+// GNU GENERAL PUBLIC LICENSE
+//    Version 3, 29 June 2007
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::VecDeque;
+use std::f32;
+
+pub const PREDICTOR_NAMES: [&str; 4] = ["last", "ema", "linear", "cost"];
+
+pub trait FrameTimePredictor {
+  fn get_name(&self) -> &str;
+
+  // Feeds the measured remaining time of the last frame, which was drawn at the given level.
+  fn observe(&mut self, level: f32, remaining_time: u32, target_frame_time: u32);
+
+  // Predicts the remaining time of the next frame if it is drawn at the given level.
+  fn predict(&self, level: f32, target_frame_time: u32) -> u32;
+}
+
+pub fn from_name(name: &str) -> Option<Box<FrameTimePredictor>> {
+  match name {
+    "last" => Some(Box::new(LastFramePredictor::new())),
+    "ema" => Some(Box::new(MovingAveragePredictor::new(0.3))),
+    "linear" => Some(Box::new(LinearRegressionPredictor::new(10))),
+    "cost" => Some(Box::new(CostModelPredictor::new(20, 0.1))),
+    _ => None,
+  }
+}
+
+fn clamp_time(time: f32, target_frame_time: u32) -> u32 {
+  f32::max(0.0, f32::min(target_frame_time as f32, time)) as u32
+}
+
+// Assumes the next frame will be exactly like the last one.
+pub struct LastFramePredictor {
+  last_remaining_time: Option<u32>,
+}
+
+impl LastFramePredictor {
+  pub fn new() -> LastFramePredictor {
+    LastFramePredictor { last_remaining_time: None }
+  }
+}
+
+impl FrameTimePredictor for LastFramePredictor {
+  fn get_name(&self) -> &str {
+    "last"
+  }
+
+  fn observe(&mut self, _: f32, remaining_time: u32, _: u32) {
+    self.last_remaining_time = Some(remaining_time);
+  }
+
+  fn predict(&self, _: f32, target_frame_time: u32) -> u32 {
+    self.last_remaining_time.unwrap_or(target_frame_time)
+  }
+}
+
+// Exponential moving average of the remaining time, which smooths out single slow frames.
+pub struct MovingAveragePredictor {
+  alpha: f32,
+  average: Option<f32>,
+}
+
+impl MovingAveragePredictor {
+  pub fn new(alpha: f32) -> MovingAveragePredictor {
+    MovingAveragePredictor { alpha: alpha, average: None }
+  }
+}
+
+impl FrameTimePredictor for MovingAveragePredictor {
+  fn get_name(&self) -> &str {
+    "ema"
+  }
+
+  fn observe(&mut self, _: f32, remaining_time: u32, _: u32) {
+    let remaining_time = remaining_time as f32;
+
+    self.average = Some(match self.average {
+      Some(average) => average + self.alpha * (remaining_time - average),
+      None => remaining_time,
+    });
+  }
+
+  fn predict(&self, _: f32, target_frame_time: u32) -> u32 {
+    match self.average {
+      Some(average) => clamp_time(average, target_frame_time),
+      None => target_frame_time,
+    }
+  }
+}
+
+// Fits a line through the remaining times of the last frames and extrapolates it one frame.
+pub struct LinearRegressionPredictor {
+  window: usize,
+  samples: VecDeque<f32>,
+}
+
+impl LinearRegressionPredictor {
+  pub fn new(window: usize) -> LinearRegressionPredictor {
+    LinearRegressionPredictor { window: window, samples: VecDeque::with_capacity(window) }
+  }
+}
+
+impl FrameTimePredictor for LinearRegressionPredictor {
+  fn get_name(&self) -> &str {
+    "linear"
+  }
+
+  fn observe(&mut self, _: f32, remaining_time: u32, _: u32) {
+    if self.samples.len() == self.window {
+      self.samples.pop_front();
+    }
+
+    self.samples.push_back(remaining_time as f32);
+  }
+
+  fn predict(&self, _: f32, target_frame_time: u32) -> u32 {
+    let n = self.samples.len();
+
+    if n == 0 {
+      return target_frame_time;
+    } else if n == 1 {
+      return clamp_time(self.samples[0], target_frame_time);
+    }
+
+    let mean_x = (n - 1) as f32 / 2.0;
+    let mean_y = self.samples.iter().sum::<f32>() / n as f32;
+
+    let mut covariance = 0.0;
+    let mut variance = 0.0;
+
+    for (x, y) in self.samples.iter().enumerate() {
+      let dx = x as f32 - mean_x;
+      covariance += dx * (y - mean_y);
+      variance += dx * dx;
+    }
+
+    let slope = covariance / variance;
+    let intercept = mean_y - slope * mean_x;
+
+    clamp_time(intercept + slope * n as f32, target_frame_time)
+  }
+}
+
+// Learns how much of the frame time is used at each quality level. Levels are divided into
+// buckets, each holding a moving average of the frame cost measured at that level.
+pub struct CostModelPredictor {
+  alpha: f32,
+  costs: Vec<Option<f32>>,
+}
+
+impl CostModelPredictor {
+  pub fn new(num_buckets: usize, alpha: f32) -> CostModelPredictor {
+    CostModelPredictor { alpha: alpha, costs: vec![None; num_buckets] }
+  }
+
+  fn get_bucket(&self, level: f32) -> usize {
+    let bucket = (level * self.costs.len() as f32) as usize;
+    usize::min(bucket, self.costs.len() - 1)
+  }
+
+  fn get_bucket_level(&self, bucket: usize) -> f32 {
+    (bucket as f32 + 0.5) / self.costs.len() as f32
+  }
+}
+
+impl FrameTimePredictor for CostModelPredictor {
+  fn get_name(&self) -> &str {
+    "cost"
+  }
+
+  fn observe(&mut self, level: f32, remaining_time: u32, target_frame_time: u32) {
+    let bucket = self.get_bucket(level);
+    let cost = target_frame_time as f32 - remaining_time as f32;
+
+    self.costs[bucket] = Some(match self.costs[bucket] {
+      Some(average) => average + self.alpha * (cost - average),
+      None => cost,
+    });
+  }
+
+  fn predict(&self, level: f32, target_frame_time: u32) -> u32 {
+    let bucket = self.get_bucket(level);
+
+    let cost = match self.costs[bucket] {
+      Some(cost) => cost,
+      None => {
+        // we have not been at this level yet, so scale the cost of the nearest level we know
+        let nearest = (0..self.costs.len())
+          .filter(|b| self.costs[*b].is_some())
+          .min_by_key(|b| (*b as isize - bucket as isize).abs());
+
+        match nearest {
+          Some(b) => self.costs[b].unwrap() * level / self.get_bucket_level(b),
+          None => return target_frame_time,
+        }
+      },
+    };
+
+    clamp_time(target_frame_time as f32 - cost, target_frame_time)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const TARGET: u32 = 16_000_000;
+
+  #[test]
+  fn predicts_target_before_any_observation() {
+    for name in &PREDICTOR_NAMES {
+      let predictor = from_name(name).unwrap();
+      assert_eq!(predictor.get_name(), *name);
+      assert_eq!(predictor.predict(0.5, TARGET), TARGET);
+    }
+
+    assert!(from_name("unknown").is_none());
+  }
+
+  #[test]
+  fn last_frame_repeats_last_observation() {
+    let mut predictor = LastFramePredictor::new();
+    predictor.observe(0.5, 3_000_000, TARGET);
+    predictor.observe(0.5, 5_000_000, TARGET);
+    assert_eq!(predictor.predict(0.9, TARGET), 5_000_000);
+  }
+
+  #[test]
+  fn moving_average_converges_to_constant() {
+    let mut predictor = MovingAveragePredictor::new(0.3);
+    predictor.observe(0.5, 0, TARGET);
+
+    for _ in 0..100 {
+      predictor.observe(0.5, 8_000_000, TARGET);
+    }
+
+    let prediction = predictor.predict(0.5, TARGET) as i64;
+    assert!((prediction - 8_000_000).abs() < 1_000);
+  }
+
+  #[test]
+  fn moving_average_smooths_single_slow_frame() {
+    let mut predictor = MovingAveragePredictor::new(0.3);
+
+    for _ in 0..10 {
+      predictor.observe(0.5, 8_000_000, TARGET);
+    }
+
+    predictor.observe(0.5, 0, TARGET);
+    let prediction = predictor.predict(0.5, TARGET) as i64;
+    assert!((prediction - 5_600_000).abs() < 1_000);
+  }
+
+  #[test]
+  fn linear_regression_extrapolates_ramp() {
+    let mut predictor = LinearRegressionPredictor::new(10);
+
+    predictor.observe(0.5, 1_000_000, TARGET);
+    assert_eq!(predictor.predict(0.5, TARGET), 1_000_000);
+
+    // only the last 10 samples of the ramp are kept
+    for i in 2..16 {
+      predictor.observe(0.5, i * 500_000, TARGET);
+    }
+
+    let prediction = predictor.predict(0.5, TARGET) as i64;
+    assert!((prediction - 8_000_000).abs() < 1_000);
+  }
+
+  #[test]
+  fn linear_regression_is_clamped_to_target() {
+    let mut predictor = LinearRegressionPredictor::new(10);
+    predictor.observe(0.5, 10_000_000, TARGET);
+    predictor.observe(0.5, 14_000_000, TARGET);
+    assert_eq!(predictor.predict(0.5, TARGET), TARGET);
+  }
+
+  #[test]
+  fn cost_model_buckets_levels() {
+    let predictor = CostModelPredictor::new(20, 0.1);
+    assert_eq!(predictor.get_bucket(0.0), 0);
+    assert_eq!(predictor.get_bucket(0.5), 10);
+    assert_eq!(predictor.get_bucket(0.999), 19);
+    assert_eq!(predictor.get_bucket(1.0), 19);
+    assert!((predictor.get_bucket_level(10) - 0.525).abs() < 1e-6);
+  }
+
+  #[test]
+  fn cost_model_uses_bucket_of_level() {
+    let mut predictor = CostModelPredictor::new(20, 0.1);
+    predictor.observe(0.5, 6_000_000, TARGET);
+    predictor.observe(0.92, 2_000_000, TARGET);
+
+    // same buckets as observed
+    assert_eq!(predictor.predict(0.52, TARGET), 6_000_000);
+    assert_eq!(predictor.predict(0.93, TARGET), 2_000_000);
+
+    // unseen bucket: the cost of the nearest bucket, scaled by the level
+    let prediction = predictor.predict(0.26, TARGET) as i64;
+    let expected = TARGET as f32 - 10_000_000.0 * 0.26 / 0.525;
+    assert!((prediction - expected as i64).abs() < 1_000);
+  }
+
+  #[test]
+  fn cost_model_averages_within_bucket() {
+    let mut predictor = CostModelPredictor::new(20, 0.5);
+    predictor.observe(0.5, 6_000_000, TARGET);
+    predictor.observe(0.51, 4_000_000, TARGET);
+    assert_eq!(predictor.predict(0.5, TARGET), 5_000_000);
+  }
+}
@@ -21,77 +21,202 @@ use std::rc::Rc;
 use std::f32;
 use webvr::VRDisplayPtr;
 
+use controller::QualityController;
+use cost_model::CostModel;
 use performance::FramePerformance;
 
+// A feature whose quality can be tuned. The name is used in the performance log (as WeightX and
+// TargetX columns) and in cost models, the label is shown in the GUI. The cost hint is a rough
+// estimate of the fraction of the frame time the feature takes at full quality, which is used for
+// knobs that a cost model does not know about.
+pub struct QualityKnob {
+  pub name: String,
+  pub label: String,
+  pub range: (f32, f32),
+  pub cost_hint: f32,
+  pub weight: Rc<RefCell<f32>>,
+}
+
+impl QualityKnob {
+  pub fn denormalize(&self, level: f32) -> f32 {
+    self.range.0 + level * (self.range.1 - self.range.0)
+  }
+}
+
 pub struct Quality {
   pub adaptive_quality: bool,
   pub level: Rc<RefCell<f32>>,
-  pub weight_resolution: Rc<RefCell<f32>>,
-  pub weight_msaa: Rc<RefCell<f32>>,
-  pub weight_lod: Rc<RefCell<f32>>,
+  pub knobs: Vec<QualityKnob>,
   pub quality_stats: (u32, u32, f32),
+  pub controller: Box<QualityController>,
+  pub cost_model: Option<Rc<CostModel>>,
+  cost_model_levels: Option<Vec<f32>>,
 }
 
 impl Quality {
-  pub fn new(weights: (f32, f32, f32)) -> Quality {
-    let (weight_resolution, weight_msaa, weight_lod) = weights;
-
+  pub fn new(controller: Box<QualityController>) -> Quality {
     Quality {
       adaptive_quality: true,
       level: Rc::new(RefCell::new(0.5)),
-      weight_resolution: Rc::new(RefCell::new(weight_resolution)),
-      weight_msaa: Rc::new(RefCell::new(weight_msaa)),
-      weight_lod: Rc::new(RefCell::new(weight_lod)),
-      quality_stats: (0, 0, 0.0)
+      knobs: Vec::new(),
+      quality_stats: (0, 0, 0.0),
+      controller: controller,
+      cost_model: None,
+      cost_model_levels: None,
     }
   }
 
-  pub fn set_level(&mut self, frame_performance: &FramePerformance, vr_display: Option<&VRDisplayPtr>) {
+  pub fn register_knob(&mut self, name: &str, label: &str, range: (f32, f32), cost_hint: f32,
+      default_weight: f32) {
+    if self.find_knob(name).is_some() {
+      panic!("Quality knob {} is already registered", name);
+    }
+
+    self.knobs.push(QualityKnob {
+      name: name.to_owned(),
+      label: label.to_owned(),
+      range: range,
+      cost_hint: cost_hint,
+      weight: Rc::new(RefCell::new(default_weight)),
+    });
+  }
+
+  pub fn find_knob(&self, name: &str) -> Option<usize> {
+    self.knobs.iter().position(|k| k.name == name)
+  }
+
+  pub fn get_knob_names(&self) -> Vec<String> {
+    self.knobs.iter().map(|k| k.name.clone()).collect()
+  }
+
+  // Returns the value a subsystem should use for the named knob, given the normalized levels of
+  // all knobs.
+  pub fn get_knob_value(&self, levels: &[f32], name: &str) -> f32 {
+    let i = self.find_knob(name).expect(&format!("Quality knob {} is not registered", name));
+    self.knobs[i].denormalize(levels[i])
+  }
+
+  // Fills in the weights of the knobs after the given ones with their defaults, so weights that
+  // were given for the first knobs, e.g. by scripts that predate the others, still apply.
+  pub fn complete_weights(&self, weights: &[f32]) -> Result<Vec<f32>, String> {
+    if weights.len() > self.knobs.len() {
+      return Err(format!("{} weights given, but there are only {} quality knobs: {}",
+          weights.len(), self.knobs.len(), self.get_knob_names().join(", ")));
+    }
+
+    Ok(weights.iter().cloned()
+        .chain(self.knobs[weights.len()..].iter().map(|k| *k.weight.borrow()))
+        .collect())
+  }
+
+  pub fn get_weights(&self) -> Vec<f32> {
+    self.knobs.iter().map(|k| *k.weight.borrow()).collect()
+  }
+
+  pub fn set_weights(&mut self, weights: &[f32]) {
+    for (knob, weight) in self.knobs.iter().zip(weights.iter()) {
+      *knob.weight.borrow_mut() = *weight;
+    }
+  }
+
+  pub fn set_level(&mut self, frame_performance: &mut FramePerformance, vr_display: Option<&VRDisplayPtr>) {
     let predicted_remaining_time = frame_performance.get_predicted_remaining_time(vr_display);
     let target_frame_time = frame_performance.get_target_frame_time();
     let ratio_remaining = f32::max(0.0, predicted_remaining_time as f32 / target_frame_time as f32);
 
     // println!("target: {}, remaining: {}, ratio: {}", target_frame_time, predicted_remaining_time, ratio_remaining);
 
-    const EMERGENCY_ZONE: f32 = 0.05;   // 0.00 - 0.05
-    const DANGER_ZONE: f32 = 0.1;       // 0.05 - 0.10
-    const SAFE_ZONE: f32 = 0.3;         // 0.10 - 0.30
-    const EASY_ZONE: f32 = 0.8;         // 0.30 - 0.80
-    // IDLE_ZONE                           0.80 - 1.00
-
     let original_level = *self.level.borrow();
+    let new_level = self.controller.next_level(original_level, frame_performance,
+        predicted_remaining_time, target_frame_time);
+    *self.level.borrow_mut() = new_level;
+    frame_performance.record_prediction(new_level);
 
-    if ratio_remaining < EMERGENCY_ZONE {
-      *self.level.borrow_mut() = f32::max(original_level * 0.5, 0.0001);
-    } else if ratio_remaining < DANGER_ZONE {
-       *self.level.borrow_mut() = f32::max(original_level * 0.99, 0.0001);
-    } else if ratio_remaining < SAFE_ZONE {
-      // in safe zone, do nothing
-    } else if ratio_remaining < EASY_ZONE {
-      *self.level.borrow_mut() = f32::min(original_level * 1.01, 1.0);
-    } else {
-      *self.level.borrow_mut() = f32::min(original_level * 2.0, 1.0);
-    }
+    // With a cost model, the level is the fraction of the frame time we allow drawing to take, and
+    // the model decides how to spend it. The controller still corrects for the model's errors.
+    self.cost_model_levels = match self.cost_model {
+      Some(ref cost_model) => {
+        let budget = new_level * target_frame_time as f32;
+        Some(self.choose_levels(cost_model, budget, target_frame_time))
+      },
+      None => None,
+    };
 
     self.quality_stats = (target_frame_time, predicted_remaining_time, ratio_remaining);
   }
 
-  pub fn get_target_levels(&self) -> (f32, f32, f32) {
-    let weight_resolution = *self.weight_resolution.borrow();
-    let weight_msaa = *self.weight_msaa.borrow();
-    let weight_lod = *self.weight_lod.borrow();
+  pub fn get_target_levels(&self) -> Vec<f32> {
+    let weights = self.get_weights();
 
-    if self.adaptive_quality {
-      let lowest_weight = f32::max(0.01, f32::min(weight_resolution, f32::min(weight_msaa, weight_lod)));
+    if self.adaptive_quality && self.cost_model_levels.is_some() {
+      self.cost_model_levels.clone().unwrap()
+    } else if self.adaptive_quality {
+      let lowest_weight = f32::max(0.01, weights.iter().cloned().fold(f32::INFINITY, f32::min));
       let level = *self.level.borrow();
       let denormalized_level = level / lowest_weight;
-      (
-        f32::min(1.0, weight_resolution * denormalized_level),
-        f32::min(1.0, weight_msaa * denormalized_level),
-        f32::min(1.0, weight_lod * denormalized_level),
-      )
+      weights.iter().map(|w| f32::min(1.0, w * denormalized_level)).collect()
     } else {
-      (weight_resolution, weight_msaa, weight_lod)
+      weights
+    }
+  }
+
+  fn predict_cost(&self, cost_model: &CostModel, levels: &[f32], target_frame_time: u32) -> f32 {
+    let mut model_levels = vec![0.0; cost_model.knobs.len()];
+    let mut cost = 0.0;
+
+    for (knob, level) in self.knobs.iter().zip(levels.iter()) {
+      match cost_model.knobs.iter().position(|k| *k == knob.name) {
+        Some(i) => model_levels[i] = *level,
+        None => cost += knob.cost_hint * level * target_frame_time as f32,
+      }
+    }
+
+    cost + cost_model.predict(&model_levels)
+  }
+
+  // Greedily raises the knob that is cheapest to raise according to the model until the budget is
+  // used up, so the most quality is bought for the budget. The user weights are not used here;
+  // every step of every knob counts the same.
+  fn choose_levels(&self, cost_model: &CostModel, budget: f32, target_frame_time: u32)
+      -> Vec<f32> {
+    const STEP: f32 = 0.05;
+
+    let mut levels = vec![0.0; self.knobs.len()];
+    let mut cost = self.predict_cost(cost_model, &levels, target_frame_time);
+
+    loop {
+      let mut best: Option<(usize, f32, f32)> = None;
+
+      for i in 0..levels.len() {
+        if levels[i] + STEP > 1.0 + f32::EPSILON {
+          continue;
+        }
+
+        let mut candidate = levels.clone();
+        candidate[i] = f32::min(1.0, levels[i] + STEP);
+        let candidate_cost = self.predict_cost(cost_model, &candidate, target_frame_time);
+
+        if candidate_cost > budget {
+          continue;
+        }
+
+        // knobs that are free according to the model count as costing a nanosecond
+        let score = STEP / f32::max(1.0, candidate_cost - cost);
+
+        if best.map_or(true, |(_, best_score, _)| score > best_score) {
+          best = Some((i, score, candidate_cost));
+        }
+      }
+
+      match best {
+        Some((i, _, candidate_cost)) => {
+          levels[i] = f32::min(1.0, levels[i] + STEP);
+          cost = candidate_cost;
+        },
+        None => break,
+      }
     }
+
+    levels
   }
 }
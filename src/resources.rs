@@ -18,50 +18,122 @@
 
 use glium::backend::Facade;
 use glium::Program;
+use glium::ProgramCreationError;
 use glium::texture::RawImage2d;
 use glium::texture::SrgbTexture2d;
+use glium::texture::Texture2d;
 use image;
+use std::cell::Cell;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::error::Error;
 use std::path::Path;
 use std::path::PathBuf;
 use std::rc::Rc;
+use std::usize;
+
+use error::EngynError;
+use geometry::MeshData;
+use material::Material;
 
 pub enum Resource {
   Program(Rc<RefCell<Program>>),
   SrgbTexture2d(Rc<RefCell<SrgbTexture2d>>),
+  Texture2d(Rc<RefCell<Texture2d>>),
 }
 
 pub struct ResourceManager<'a> {
+  // the number of bytes of mesh data that may be kept on the CPU
+  pub mesh_data_budget: usize,
+  // whether to print statistics of what is loaded
+  pub verbose: bool,
+  // whether the LOD levels of OBJ files are read from and written to a cache file next to them
+  pub use_lod_cache: bool,
+
   context: &'a Facade,
   resources: RefCell<HashMap<PathBuf, Resource>>,
+  // the materials of scene files, by the path of the scene and their name in it
+  scene_materials: RefCell<HashMap<(PathBuf, String), Rc<RefCell<Material>>>>,
+  mesh_data_size: Cell<usize>,
+  is_budget_exceeded: Cell<bool>,
 }
 
 impl<'a> ResourceManager<'a> {
   pub fn new(context: &Facade) -> ResourceManager {
     ResourceManager {
+      mesh_data_budget: usize::MAX,
+      verbose: false,
+      use_lod_cache: false,
       resources: RefCell::new(HashMap::new()),
+      scene_materials: RefCell::new(HashMap::new()),
       context: context,
+      mesh_data_size: Cell::new(0),
+      is_budget_exceeded: Cell::new(false),
+    }
+  }
+
+  /**
+   * Keeps a CPU-side copy of mesh data, unless it doesn't fit in the budget anymore.
+   */
+
+  pub fn retain_mesh_data(&self, mesh: MeshData) -> Option<Rc<MeshData>> {
+    let size = self.mesh_data_size.get() + mesh.get_memory_size();
+
+    if size > self.mesh_data_budget {
+      if !self.is_budget_exceeded.get() {
+        eprintln!("Warning: mesh data budget of {} bytes exceeded, the meshes loaded from now on \
+            can't be picked (see --mesh-data-budget)", self.mesh_data_budget);
+        self.is_budget_exceeded.set(true);
+      }
+
+      None
+    } else {
+      self.mesh_data_size.set(size);
+      Some(Rc::new(mesh))
     }
   }
 
+  pub fn get_mesh_data_size(&self) -> usize {
+    self.mesh_data_size.get()
+  }
+
+  /**
+   * Retrieves a named material of a scene from the ResourceManager, creating it the first time
+   */
+
+  pub fn get_scene_material(&self, scene_path: &Path, name: &str,
+      create: &Fn() -> Result<Material, EngynError>)
+      -> Result<Rc<RefCell<Material>>, EngynError> {
+    println!("get_scene_material: {} in {}", name, scene_path.to_str().unwrap_or(""));
+    let key = (scene_path.to_path_buf(), name.to_owned());
+
+    if let Some(material) = self.scene_materials.borrow().get(&key) {
+      return Ok(Rc::clone(material));
+    }
+
+    let material = Rc::new(RefCell::new(create()?));
+    self.scene_materials.borrow_mut().insert(key, Rc::clone(&material));
+    Ok(material)
+  }
+
   /**
    * Retrieves a program from the ResourceManager
    */
 
-  pub fn get_program(&self, path: &Path, compile: &Fn() -> Program)
-      -> Result<Rc<RefCell<Program>>, &str> {
+  pub fn get_program(&self, path: &Path, compile: &Fn() -> Result<Program, ProgramCreationError>)
+      -> Result<Rc<RefCell<Program>>, EngynError> {
     println!("get_program: {}", path.to_str().unwrap_or(""));
     if self.resources.borrow().contains_key(path) {
       match self.resources.borrow().get(path) {
         Some(&Resource::Program(ref p)) => Ok(Rc::clone(p)),
-        Some(_) => Err("Not a program"),
+        Some(_) => Err(EngynError::resource(path, "expected a program")),
         None => panic!(),
       }
     } else {
+      let program = compile()
+          .map_err(|e| EngynError::shader(path.to_str().unwrap_or(""), e))?;
       self.resources.borrow_mut().insert(path.to_path_buf(),
-          Resource::Program(Rc::new(RefCell::new(compile()))));
+          Resource::Program(Rc::new(RefCell::new(program))));
       match self.resources.borrow().get(path) {
         Some(&Resource::Program(ref p)) => Ok(Rc::clone(p)),
         _ => panic!()
@@ -73,12 +145,12 @@ impl<'a> ResourceManager<'a> {
    * Retrieves a texture from the ResourceManager.
    */
 
-  pub fn get_texture(&self, path: &Path) -> Result<Rc<RefCell<SrgbTexture2d>>, &str> {
+  pub fn get_texture(&self, path: &Path) -> Result<Rc<RefCell<SrgbTexture2d>>, EngynError> {
     println!("get_texture: {}", path.to_str().unwrap_or(""));
     if self.resources.borrow().contains_key(path) {
       match self.resources.borrow().get(path) {
         Some(&Resource::SrgbTexture2d(ref t)) => Ok(Rc::clone(t)),
-        Some(_) => Err("Not a texture"),
+        Some(_) => Err(EngynError::resource(path, "expected a texture")),
         None => panic!(),
       }
     } else {
@@ -94,14 +166,51 @@ impl<'a> ResourceManager<'a> {
             _ => panic!()
           }
         },
-        _ => {
-          eprintln!("Could not load texture: {}", path.to_str().unwrap_or(""));
-          Ok(Rc::new(RefCell::new(SrgbTexture2d::empty(self.context, 1, 1).unwrap())))
+        Err(e) => {
+          // a missing texture is not fatal, the material is drawn without it
+          eprintln!("Could not load texture: {}: {}", path.to_str().unwrap_or(""), e);
+          let empty = SrgbTexture2d::empty(self.context, 1, 1)
+              .map_err(|e| EngynError::texture(path, &e.to_string()))?;
+          Ok(Rc::new(RefCell::new(empty)))
         }
       }
     }
   }
 
+  /**
+   * Retrieves a texture that holds data instead of colors, like a normal map, from the
+   * ResourceManager.
+   */
+
+  pub fn get_linear_texture(&self, path: &Path) -> Result<Rc<RefCell<Texture2d>>, EngynError> {
+    println!("get_linear_texture: {}", path.to_str().unwrap_or(""));
+    if self.resources.borrow().contains_key(path) {
+      match self.resources.borrow().get(path) {
+        Some(&Resource::Texture2d(ref t)) => Ok(Rc::clone(t)),
+        Some(_) => Err(EngynError::resource(path, "expected a linear texture")),
+        None => panic!(),
+      }
+    } else {
+      let texture = self.load_linear_texture(Path::new(path))
+          .map_err(|e| EngynError::texture(path, &e.to_string()))?;
+      self.resources.borrow_mut().insert(path.to_path_buf(),
+          Resource::Texture2d(Rc::new(RefCell::new(texture))));
+      match self.resources.borrow().get(path) {
+        Some(&Resource::Texture2d(ref t)) => Ok(Rc::clone(t)),
+        _ => panic!()
+      }
+    }
+  }
+
+  fn load_linear_texture(&self, name: &Path) -> Result<Texture2d, Box<Error>> {
+    let image = image::open(name)?.to_rgba();
+    let image_dimensions = image.dimensions();
+    let image = RawImage2d::from_raw_rgba_reversed(&image.into_raw(), image_dimensions);
+    let texture = Texture2d::new(self.context, image)?;
+
+    Ok(texture)
+  }
+
   fn load_texture(&self, name: &Path) -> Result<SrgbTexture2d, Box<Error>> {
     let image = image::open(name)?.to_rgba();
     let image_dimensions = image.dimensions();
@@ -21,78 +21,475 @@ use cgmath::SquareMatrix;
 use glium::backend::Facade;
 use math;
 use serde_yaml;
+use serde_yaml::Value;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::fs::File;
-use std::io::Result;
+use std::io::Read;
 use std::path::Path;
 use std::path::PathBuf;
+use std::rc::Rc;
+use tobj;
 
 use benchmark::Benchmark;
+use controller;
+use error::EngynError;
 use light::Light;
+use light::LightKind;
+use material::AlphaMode;
+use material::Material;
+use material::MaterialMaps;
+use material::Shading;
 use network_graph::Network;
 use object::Object;
 use resources::ResourceManager;
+use uniforms;
+
+pub const SCENE_VERSION: &str = "1.1";
+
+// upgrades a scene file from one version to the next, before it is deserialized
+struct Migration {
+  from: &'static str,
+  to: &'static str,
+  apply: fn(&mut Value),
+}
+
+const MIGRATIONS: [Migration; 1] = [
+  Migration { from: "1.0", to: "1.1", apply: migrate_light_intensity },
+];
+
+// lights used to be equally bright everywhere; 1.1 lights fall off with the square of the
+// distance, so they get the intensity that keeps them as bright at the origin
+fn migrate_light_intensity(scene: &mut Value) {
+  let lights = match scene.as_mapping_mut()
+      .and_then(|s| s.get_mut(&key("lights")))
+      .and_then(|l| l.as_sequence_mut()) {
+    Some(lights) => lights,
+    None => return,
+  };
+
+  for light in lights.iter_mut().filter_map(|l| l.as_mapping_mut()) {
+    let distance_squared = light.get(&key("position"))
+        .and_then(|p| p.as_sequence())
+        .map_or(0.0, |p| p.iter().filter_map(|x| x.as_f64()).map(|x| x * x).sum());
+
+    light.insert(key("intensity"), Value::from(f64::max(distance_squared, 1.0)));
+  }
+}
+
+fn key(name: &str) -> Value {
+  Value::String(name.to_owned())
+}
+
+// just the version of a scene file, read before the rest to see if it needs to be upgraded
+#[derive(Deserialize)]
+struct SceneVersion {
+  version: Option<String>,
+}
+
+// brings a scene file up to the current version, returning the version it had
+fn migrate(scene: &mut Value) -> Result<String, String> {
+  let original_version = match scene.get("version").and_then(|v| v.as_str()) {
+    Some(version) => version.to_owned(),
+    None => return Err("scene has no version".to_owned()),
+  };
+
+  let mut version = original_version.clone();
+
+  while version != SCENE_VERSION {
+    let migration = match MIGRATIONS.iter().find(|m| m.from == version) {
+      Some(migration) => migration,
+      None => {
+        let mut supported: Vec<&str> = MIGRATIONS.iter().map(|m| m.from).collect();
+        supported.push(SCENE_VERSION);
+
+        return Err(format!("unsupported scene version {} (supported: {})", original_version,
+            supported.join(", ")));
+      },
+    };
+
+    (migration.apply)(scene);
+    version = migration.to.to_owned();
+  }
+
+  if let Some(mapping) = scene.as_mapping_mut() {
+    mapping.insert(key("version"), key(SCENE_VERSION));
+  }
+
+  Ok(original_version)
+}
+
+// something wrong with a scene file, found without loading it onto the GPU
+#[derive(Debug)]
+pub struct SceneProblem {
+  pub location: String,
+  pub message: String,
+}
+
+impl fmt::Display for SceneProblem {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}: {}", self.location, self.message)
+  }
+}
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 #[serde(rename_all = "camelCase")]
 pub enum SceneDrawable {
   Benchmark { path: String },
   Obj { path: String },
-  Network { num_nodes: usize, num_links: usize },
+  Network {
+    num_nodes: usize,
+    num_links: usize,
+  },
+  Plane { size: [f32; 2] },
+  Triangle { size: [f32; 2] },
   None,
 }
 
+// a material that objects in the scene refer to by name, with the albedo map relative to the
+// scene file
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SceneMaterial {
+  pub albedo_map: Option<String>,
+  pub ambient_color: [f32; 3],
+  pub diffuse_color: [f32; 3],
+  pub specular_color: [f32; 3],
+  pub shininess: f32,
+  pub metalness: f32,
+  pub reflectivity: f32,
+  pub roughness: f32,
+  pub shading: Shading,
+  pub opacity: f32,
+  pub alpha_mode: AlphaMode,
+  pub alpha_cutoff: f32,
+}
+
+impl Default for SceneMaterial {
+  fn default() -> SceneMaterial {
+    SceneMaterial {
+      albedo_map: None,
+      ambient_color: [0.0, 0.0, 0.0],
+      diffuse_color: [1.0, 1.0, 1.0],
+      specular_color: [0.0, 0.0, 0.0],
+      shininess: 0.0,
+      metalness: 0.0,
+      reflectivity: 0.0,
+      roughness: 0.5,
+      shading: Shading::BlinnPhong,
+      opacity: 1.0,
+      alpha_mode: AlphaMode::Opaque,
+      alpha_cutoff: 0.5,
+    }
+  }
+}
+
+impl SceneMaterial {
+  pub fn as_material(&self, name: &str, resource_manager: &ResourceManager, base_path: &Path)
+      -> Result<Material, EngynError> {
+    let albedo_map = match self.albedo_map {
+      Some(ref path) => resource_manager.get_texture(&base_path.join(path))?,
+      None => resource_manager.get_texture(&Path::new("data/empty.bmp"))?,
+    };
+
+    Ok(Material {
+      name: name.to_owned(),
+      albedo_map: albedo_map,
+      ambient_color: self.ambient_color,
+      diffuse_color: self.diffuse_color,
+      specular_color: self.specular_color,
+      shininess: self.shininess,
+      metalness: self.metalness,
+      reflectivity: self.reflectivity,
+      roughness: self.roughness,
+      shading: self.shading,
+      emissive_color: [0.0, 0.0, 0.0],
+      dissolve: self.opacity,
+      alpha_mode: self.alpha_mode,
+      alpha_cutoff: self.alpha_cutoff,
+      maps: MaterialMaps::default(),
+    })
+  }
+}
+
+// changes one material of an OBJ file: either replaces it with a scene material, or overrides
+// the properties that are set
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MaterialOverride {
+  #[serde(skip_serializing_if = "Option::is_none")] pub material: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")] pub albedo_map: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")] pub ambient_color: Option<[f32; 3]>,
+  #[serde(skip_serializing_if = "Option::is_none")] pub diffuse_color: Option<[f32; 3]>,
+  #[serde(skip_serializing_if = "Option::is_none")] pub specular_color: Option<[f32; 3]>,
+  #[serde(skip_serializing_if = "Option::is_none")] pub shininess: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")] pub metalness: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")] pub reflectivity: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")] pub roughness: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")] pub shading: Option<Shading>,
+  #[serde(skip_serializing_if = "Option::is_none")] pub opacity: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")] pub alpha_mode: Option<AlphaMode>,
+  #[serde(skip_serializing_if = "Option::is_none")] pub alpha_cutoff: Option<f32>,
+}
+
+impl MaterialOverride {
+  fn has_properties(&self) -> bool {
+    *self != MaterialOverride { material: self.material.clone(), ..MaterialOverride::default() }
+  }
+
+  fn apply(&self, material: &mut Material, resource_manager: &ResourceManager, base_path: &Path)
+      -> Result<(), EngynError> {
+    if let Some(ref path) = self.albedo_map {
+      material.albedo_map = resource_manager.get_texture(&base_path.join(path))?;
+    }
+
+    if let Some(color) = self.ambient_color { material.ambient_color = color }
+    if let Some(color) = self.diffuse_color { material.diffuse_color = color }
+    if let Some(color) = self.specular_color { material.specular_color = color }
+    if let Some(shininess) = self.shininess { material.shininess = shininess }
+    if let Some(metalness) = self.metalness { material.metalness = metalness }
+    if let Some(reflectivity) = self.reflectivity { material.reflectivity = reflectivity }
+    if let Some(roughness) = self.roughness { material.roughness = roughness }
+    if let Some(shading) = self.shading { material.shading = shading }
+    if let Some(opacity) = self.opacity { material.dissolve = opacity }
+    if let Some(alpha_mode) = self.alpha_mode { material.alpha_mode = alpha_mode }
+    if let Some(alpha_cutoff) = self.alpha_cutoff { material.alpha_cutoff = alpha_cutoff }
+
+    Ok(())
+  }
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct SceneObject {
   pub children: Vec<SceneObject>,
   pub drawable: SceneDrawable,
   pub transform: [f32; 16],
+  // the name of a scene material, which replaces the materials of an OBJ file that have no
+  // override
+  #[serde(default, skip_serializing_if = "Option::is_none")] pub material: Option<String>,
+  // changes to the materials of an OBJ file, by their name in the MTL file
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub material_overrides: BTreeMap<String, MaterialOverride>,
 }
 
 impl SceneObject {
-  pub fn as_object<F>(&self, context: &F, resource_manager: &ResourceManager, base_path: &Path)
-      -> Object where F: Facade {
+  pub fn as_object<F>(&self, context: &F, resource_manager: &ResourceManager, scene: &Scene)
+      -> Result<Object, EngynError> where F: Facade {
+    let base_path = &scene.base_path;
+    let material = match self.material {
+      Some(ref name) => Some(scene.get_material(name, resource_manager)?),
+      None => None,
+    };
+
+    // primitives have no material of their own
+    let primitive_material = || -> Result<Rc<RefCell<Material>>, EngynError> {
+      match material {
+        Some(ref material) => Ok(Rc::clone(material)),
+        None => Ok(Rc::new(RefCell::new(
+            SceneMaterial::default().as_material("", resource_manager, base_path)?))),
+      }
+    };
+
     let mut object = match &self.drawable {
       &SceneDrawable::Benchmark { ref path } => {
-        Benchmark::from_file(context, &base_path.join(path)).as_object()
+        Benchmark::from_file(context, &base_path.join(path))?.as_object()
       },
       &SceneDrawable::Obj { ref path } => {
-        Object::from_file(context, resource_manager, &base_path.join(path))
+        let mut object = Object::from_file(context, resource_manager, &base_path.join(path))?;
+
+        // the materials of an OBJ file belong to this object, so they are overridden in place
+        let mut replace = |original: &Rc<RefCell<Material>>|
+            -> Result<Option<Rc<RefCell<Material>>>, EngynError> {
+          let mtl_name = original.borrow().name.clone();
+
+          match self.material_overrides.get(&mtl_name) {
+            Some(&MaterialOverride { material: Some(ref name), .. }) => {
+              Ok(Some(scene.get_material(name, resource_manager)?))
+            },
+            Some(material_override) => {
+              material_override.apply(&mut original.borrow_mut(), resource_manager, base_path)?;
+              Ok(None)
+            },
+            None => Ok(material.as_ref().map(Rc::clone)),
+          }
+        };
+
+        object.replace_materials(context, resource_manager, &mut replace)?;
+
+        object
       },
       &SceneDrawable::Network { num_nodes, num_links } => {
         Network::new(context, num_nodes, num_links).as_object()
       },
+      &SceneDrawable::Plane { size } => {
+        Object::new_plane(context, resource_manager, primitive_material()?, size, [0.0; 3],
+            [0.0; 3], [1.0; 3])?
+      },
+      &SceneDrawable::Triangle { size } => {
+        Object::new_triangle(context, resource_manager, primitive_material()?, size, [0.0; 3],
+            [0.0; 3], [1.0; 3])?
+      },
       &SceneDrawable::None => Object {
         children: vec![],
         drawable: None,
         transform: Matrix4::identity(),
         size: 0.0,
+        bounds: None,
       },
     };
 
     for child in &self.children {
-      object.children.push(child.as_object(context, resource_manager, base_path));
+      object.children.push(child.as_object(context, resource_manager, scene)?);
     }
 
     object.transform = math::vec_to_matrix(&self.transform);
 
-    object
+    Ok(object)
+  }
+
+  fn validate(&self, scene: &Scene, location: &str, problems: &mut Vec<SceneProblem>) {
+    let base_path = &scene.base_path;
+
+    {
+      let mut report = |message: String| problems.push(SceneProblem {
+        location: location.to_owned(),
+        message: message,
+      });
+
+      match &self.drawable {
+        &SceneDrawable::Benchmark { ref path } => {
+          if !base_path.join(path).is_file() {
+            report(format!("benchmark file {} not found", path));
+          }
+        },
+        &SceneDrawable::Obj { ref path } => {
+          for message in validate_obj(&base_path.join(path)) {
+            report(message);
+          }
+        },
+        &SceneDrawable::Network { num_nodes, num_links } => {
+          if num_nodes == 0 && num_links > 0 {
+            report(format!("network has {} links but no nodes", num_links));
+          }
+        },
+        &SceneDrawable::Plane { size } | &SceneDrawable::Triangle { size } => {
+          if size[0] <= 0.0 || size[1] <= 0.0 {
+            report(format!("primitive has size {:?}, which is not positive", size));
+          }
+        },
+        &SceneDrawable::None => (),
+      }
+
+      if let Some(ref name) = self.material {
+        if !scene.materials.contains_key(name) {
+          report(format!("unknown material {}", name));
+        }
+      }
+
+      let is_obj = match self.drawable { SceneDrawable::Obj { .. } => true, _ => false };
+
+      if !is_obj && !self.material_overrides.is_empty() {
+        report("material overrides only apply to OBJ files".to_owned());
+      }
+
+      for (mtl_name, material_override) in &self.material_overrides {
+        if let Some(ref name) = material_override.material {
+          if !scene.materials.contains_key(name) {
+            report(format!("override of {} has unknown material {}", mtl_name, name));
+          }
+
+          if material_override.has_properties() {
+            report(format!("override of {} sets both a material and properties", mtl_name));
+          }
+        }
+
+        if let Some(ref path) = material_override.albedo_map {
+          if !base_path.join(path).is_file() {
+            report(format!("albedo map {} of override of {} not found", path, mtl_name));
+          }
+        }
+      }
+
+      if self.transform.iter().any(|x| !x.is_finite()) {
+        report("transform contains values that are not finite".to_owned());
+      } else if math::vec_to_matrix(&self.transform).determinant().abs() < 1e-12 {
+        report("transform is singular".to_owned());
+      }
+    }
+
+    for (i, child) in self.children.iter().enumerate() {
+      child.validate(scene, &format!("{}.children[{}]", location, i), problems);
+    }
   }
 }
 
+// the problems with an OBJ file and the materials and textures it refers to
+fn validate_obj(path: &Path) -> Vec<String> {
+  if !path.is_file() {
+    return vec![format!("OBJ file {} not found", path.display())];
+  }
+
+  let (models, materials) = match tobj::load_obj(path) {
+    Ok(result) => result,
+    Err(e) => return vec![EngynError::obj(path, e).to_string()],
+  };
+
+  let mut problems = Vec::new();
+  let obj_dir = path.parent().unwrap_or(Path::new(""));
+
+  if models.is_empty() {
+    problems.push(format!("{} contains no meshes", path.display()));
+  }
+
+  for model in &models {
+    if model.mesh.indices.len() % 3 != 0 {
+      problems.push(format!("{}: mesh {} is not made of triangles", path.display(), model.name));
+    }
+
+    if let Some(material_id) = model.mesh.material_id {
+      if material_id >= materials.len() {
+        problems.push(format!("{}: mesh {} refers to missing material {}", path.display(),
+            model.name, material_id));
+      }
+    }
+  }
+
+  for material in &materials {
+    let textures = [&material.diffuse_texture, &material.normal_texture,
+        &material.specular_texture, &material.shininess_texture, &material.dissolve_texture];
+
+    for texture in textures.iter().filter(|t| !t.is_empty()) {
+      let file_name = texture.split_whitespace().last().unwrap_or("");
+
+      if !obj_dir.join(file_name).is_file() {
+        problems.push(format!("{}: texture {} of material {} not found", path.display(),
+            file_name, material.name));
+      }
+    }
+  }
+
+  problems
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Scene {
   pub version: String,
   pub scene_objects: Vec<SceneObject>,
   pub lights: Vec<Light>,
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub materials: BTreeMap<String, SceneMaterial>,
+  #[serde(default)] pub quality_controller: Option<String>,
+  #[serde(skip)] path: PathBuf,
   #[serde(skip)] base_path: PathBuf,
 }
 
 impl Scene {
   pub fn new() -> Scene {
     Scene {
-      version: "1.0".to_owned(),
+      version: SCENE_VERSION.to_owned(),
       scene_objects: vec![
         SceneObject {
           children: vec![
@@ -100,52 +497,181 @@ impl Scene {
               children: vec![],
               drawable: SceneDrawable::Obj { path: "cube.obj".to_owned() },
               transform: math::matrix_to_vec(&Matrix4::identity()),
+              material: None,
+              material_overrides: BTreeMap::new(),
             },
           ],
           drawable: SceneDrawable::None,
           transform: math::matrix_to_vec(&Matrix4::identity()),
+          material: None,
+          material_overrides: BTreeMap::new(),
         },
         SceneObject {
           children: vec![],
           drawable: SceneDrawable::Network { num_nodes: 10, num_links: 10 },
           transform: math::matrix_to_vec(&Matrix4::identity()),
+          material: None,
+          material_overrides: BTreeMap::new(),
         },
       ],
       lights: vec![
-        Light { color: [1.0, 0.9, 0.9], position: [10.0, 10.0, 10.0] },
+        Light {
+          color: [1.0, 0.9, 0.9],
+          intensity: 300.0,
+          position: [10.0, 10.0, 10.0],
+          .. Default::default()
+        },
       ],
+      materials: BTreeMap::new(),
+      quality_controller: None,
+      path: PathBuf::new(),
       base_path: PathBuf::new(),
     }
   }
 
-  pub fn from_yaml(filename: &str) -> Result<Scene> {
+  pub fn from_yaml(filename: &str) -> Result<Scene, EngynError> {
     let path = Path::new(filename);
-    let file = File::open(&path)?;
-    let base = path.parent().unwrap();
-    let mut scene: Scene = serde_yaml::from_reader(&file).unwrap();
+    let mut contents = String::new();
+    File::open(&path)
+      .and_then(|mut file| file.read_to_string(&mut contents))
+      .map_err(|e| EngynError::io(path, e))?;
+    let base = path.parent().unwrap_or(Path::new(""));
+
+    let file_version: SceneVersion = serde_yaml::from_str(&contents)
+      .map_err(|e| EngynError::yaml(path, e))?;
+    let is_current = file_version.version.as_ref().map_or(false, |v| v == SCENE_VERSION);
+
+    // current files are read directly, so errors point at their line and column; only old ones go
+    // through a Value to be upgraded
+    let mut scene: Scene = if is_current {
+      serde_yaml::from_str(&contents).map_err(|e| EngynError::yaml(path, e))?
+    } else {
+      let mut value: Value = serde_yaml::from_str(&contents).map_err(|e| EngynError::yaml(path, e))?;
+
+      let version = migrate(&mut value).map_err(|e| EngynError::Scene {
+        path: path.to_path_buf(),
+        message: e,
+      })?;
+
+      println!("Upgraded scene {} from version {} to {}", filename, version, SCENE_VERSION);
+      serde_yaml::from_value(value).map_err(|e| EngynError::yaml(path, e))?
+    };
+    scene.path = path.to_path_buf();
     scene.base_path = base.to_path_buf();
     Ok(scene)
   }
 
-  pub fn to_yaml(&self, filename: &str) -> Result<()> {
-    let file = File::create(filename)?;
-    serde_yaml::to_writer(file, self).unwrap();
-    Ok(())
+  pub fn to_yaml(&self, filename: &str) -> Result<(), EngynError> {
+    let path = Path::new(filename);
+    let file = File::create(path).map_err(|e| EngynError::io(path, e))?;
+    serde_yaml::to_writer(file, self).map_err(|e| EngynError::yaml(path, e))
+  }
+
+  // checks everything that the file refers to, so problems show up before the scene is loaded
+  pub fn validate(&self) -> Vec<SceneProblem> {
+    let mut problems = Vec::new();
+
+    for (i, scene_object) in self.scene_objects.iter().enumerate() {
+      scene_object.validate(self, &format!("sceneObjects[{}]", i), &mut problems);
+    }
+
+    for (name, material) in &self.materials {
+      if let Some(ref albedo_map) = material.albedo_map {
+        if !self.base_path.join(albedo_map).is_file() {
+          problems.push(SceneProblem {
+            location: format!("materials.{}", name),
+            message: format!("albedo map {} not found", albedo_map),
+          });
+        }
+      }
+
+      let fractions = [
+        ("metalness", material.metalness),
+        ("roughness", material.roughness),
+        ("opacity", material.opacity),
+        ("alphaCutoff", material.alpha_cutoff),
+      ];
+
+      for &(field, value) in &fractions {
+        if value < 0.0 || value > 1.0 {
+          problems.push(SceneProblem {
+            location: format!("materials.{}", name),
+            message: format!("{} {} is outside of 0 to 1", field, value),
+          });
+        }
+      }
+    }
+
+    for (i, light) in self.lights.iter().enumerate() {
+      let mut report = |message: String| problems.push(SceneProblem {
+        location: format!("lights[{}]", i),
+        message: message,
+      });
+
+      if light.intensity < 0.0 || light.range < 0.0 {
+        report("intensity and range can't be negative".to_owned());
+      }
+
+      if light.kind != LightKind::Point && light.direction == [0.0, 0.0, 0.0] {
+        report("directional and spot lights need a direction".to_owned());
+      }
+
+      if light.kind == LightKind::Spot && light.inner_cone_angle > light.outer_cone_angle {
+        report("the inner cone angle is larger than the outer cone angle".to_owned());
+      }
+    }
+
+    if self.lights.len() > uniforms::MAX_NUM_LIGHTS {
+      problems.push(SceneProblem {
+        location: "lights".to_owned(),
+        message: format!("{} lights, but only the first {} are used", self.lights.len(),
+            uniforms::MAX_NUM_LIGHTS),
+      });
+    }
+
+    if let Some(ref name) = self.quality_controller {
+      if !controller::CONTROLLER_NAMES.contains(&&name[..]) {
+        problems.push(SceneProblem {
+          location: "qualityController".to_owned(),
+          message: format!("unknown quality controller {} (available: {})", name,
+              controller::CONTROLLER_NAMES.join(", ")),
+        });
+      }
+    }
+
+    problems
+  }
+
+  // the named material, shared between all objects that use it
+  fn get_material(&self, name: &str, resource_manager: &ResourceManager)
+      -> Result<Rc<RefCell<Material>>, EngynError> {
+    let scene_material = match self.materials.get(name) {
+      Some(material) => material,
+      None => return Err(EngynError::Scene {
+        path: self.path.clone(),
+        message: format!("unknown material {}", name),
+      }),
+    };
+
+    resource_manager.get_scene_material(&self.path, name, &|| {
+      scene_material.as_material(name, resource_manager, &self.base_path)
+    })
   }
 
-  pub fn as_object<F>(&self, context: &F, resource_manager: &ResourceManager) -> Object
-      where F: Facade {
+  pub fn as_object<F>(&self, context: &F, resource_manager: &ResourceManager)
+      -> Result<Object, EngynError> where F: Facade {
     let mut objects = vec![];
 
     for scene_object in &self.scene_objects {
-      objects.push(scene_object.as_object(context, resource_manager, &self.base_path));
+      objects.push(scene_object.as_object(context, resource_manager, self)?);
     }
 
-    Object {
+    Ok(Object {
       children: objects,
       drawable: None,
       transform: Matrix4::identity(),
       size: 0.0,
-    }
+      bounds: None,
+    })
   }
 }
@@ -0,0 +1,380 @@
+// Copyright (c) 2018 Remco Kranenburg
+//
+// GNU GENERAL PUBLIC LICENSE
+//    Version 3, 29 June 2007
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use cgmath::EuclideanSpace;
+use cgmath::InnerSpace;
+use cgmath::Matrix4;
+use cgmath::Point3;
+use cgmath::Rad;
+use cgmath::Vector3;
+use cgmath;
+use glium::Depth;
+use glium::DepthTest;
+use glium::DrawParameters;
+use glium::Program;
+use glium::ProgramCreationError;
+use glium::Rect;
+use glium::Surface;
+use glium::backend::Facade;
+use glium::framebuffer::SimpleFrameBuffer;
+use glium::index::NoIndices;
+use glium::index::PrimitiveType;
+use glium::texture::DepthTexture2d;
+use glium::texture::Texture2d;
+use std::cell::RefCell;
+use std::f32;
+use std::path::Path;
+use std::rc::Rc;
+
+use bvh::Bvh;
+use error::EngynError;
+use frustum::Frustum;
+use geometry::BoundingBox;
+use light::Light;
+use light::LightKind;
+use material::AlphaMode;
+use math;
+use object::Object;
+use quality::Quality;
+use resources::ResourceManager;
+
+// the first lights cast shadows, each into its own tile of one atlas
+pub const MAX_NUM_SHADOWS: usize = 4;
+pub const SHADOW_ATLAS_TILES: usize = 2;
+
+const MAX_TILE_RESOLUTION: u32 = 2048;
+const MIN_TILE_RESOLUTION: u32 = 256;
+const MAX_FILTER_RADIUS: f32 = 2.0;
+
+// Shadow maps for the lights of the world. Directional lights get an orthographic projection
+// around the world and spot lights a perspective that covers their cone. A point light outside
+// the world is shadowed like a spot light that looks at the bounds of the world; point lights
+// inside the world would need a cube map and are not shadowed. The resolution and the size of
+// the PCF kernel are quality knobs.
+pub struct ShadowMaps {
+  pub enabled: bool,
+  pub tile_resolution: u32,
+  // the PCF kernel is (2r + 1)² samples, each of which is filtered by the hardware as well
+  pub filter_radius: i32,
+  pub num_shadows: i32,
+  // the tile of each of the first lights, or -1 if it casts no shadow
+  pub light_tiles: [i32; MAX_NUM_SHADOWS],
+  // from world space to the texture coordinates and depth in the atlas
+  pub matrices: [[[f32; 4]; 4]; MAX_NUM_SHADOWS],
+  pub num_casters: u32,
+
+  atlas: DepthTexture2d,
+  program: Rc<RefCell<Program>>,
+  // for alpha tested materials, which discard the fragments that are cut out
+  masked_program: Rc<RefCell<Program>>,
+  // stands in for the opacity map of masked materials that don't have one
+  blank_map: Texture2d,
+}
+
+impl ShadowMaps {
+  pub fn new(display: &Facade, resource_manager: &ResourceManager)
+      -> Result<ShadowMaps, EngynError> {
+    let program = resource_manager.get_program(&Path::new("programs/shadow_program"), &|| {
+      construct_program(display)
+    })?;
+    let masked_program = resource_manager.get_program(
+        &Path::new("programs/shadow_masked_program"), &|| construct_masked_program(display))?;
+    let blank_map = Texture2d::new(display, vec![vec![(1.0f32, 1.0f32, 1.0f32, 1.0f32)]])
+        .map_err(|e| EngynError::texture(&Path::new("shadow_blank_map"), &e.to_string()))?;
+
+    Ok(ShadowMaps {
+      enabled: false,
+      tile_resolution: MIN_TILE_RESOLUTION,
+      filter_radius: 1,
+      num_shadows: 0,
+      light_tiles: [-1; MAX_NUM_SHADOWS],
+      matrices: [[[0.0; 4]; 4]; MAX_NUM_SHADOWS],
+      num_casters: 0,
+      atlas: create_atlas(display, MIN_TILE_RESOLUTION)?,
+      program: program,
+      masked_program: masked_program,
+      blank_map: blank_map,
+    })
+  }
+
+  pub fn register_quality_knobs(quality: &mut Quality) {
+    quality.register_knob("ShadowResolution", "Shadow resolution weight", (0.0, 1.0), 0.2, 0.2);
+    quality.register_knob("ShadowFilter", "Shadow filter weight", (0.0, 1.0), 0.1, 0.1);
+  }
+
+  // powers of two, so the atlas is only recreated when the scale changes a lot
+  pub fn set_resolution_scale(&mut self, display: &Facade, scale: f32)
+      -> Result<(), EngynError> {
+    let mut resolution = MIN_TILE_RESOLUTION;
+    while resolution < MAX_TILE_RESOLUTION
+        && (resolution * 2) as f32 <= MAX_TILE_RESOLUTION as f32 * scale {
+      resolution *= 2;
+    }
+
+    if resolution != self.tile_resolution {
+      self.atlas = create_atlas(display, resolution)?;
+      self.tile_resolution = resolution;
+    }
+
+    Ok(())
+  }
+
+  pub fn set_filter_scale(&mut self, scale: f32) {
+    self.filter_radius = (scale * MAX_FILTER_RADIUS).round() as i32;
+  }
+
+  pub fn get_atlas(&self) -> &DepthTexture2d {
+    &self.atlas
+  }
+
+  // draws the depth of the world as seen from each light that casts a shadow
+  pub fn render(&mut self, display: &Facade, world: &[Object], bvh: &Bvh, lights: &[Light]) {
+    self.num_shadows = 0;
+    self.num_casters = 0;
+    self.light_tiles = [-1; MAX_NUM_SHADOWS];
+
+    if !self.enabled { return; }
+
+    let items = bvh.get_items();
+    let world_bounds = items.iter()
+        .filter_map(|item| item.bounds)
+        .fold(math::empty_bounding_box(), |a, b| math::merge_bounding_boxes(&a, &b));
+
+    if math::is_empty_bounding_box(&world_bounds) { return; }
+
+    let mut framebuffer = SimpleFrameBuffer::depth_only(display, &self.atlas).unwrap();
+    framebuffer.clear_depth(1.0);
+
+    for (i, light) in lights.iter().take(MAX_NUM_SHADOWS).enumerate() {
+      let light_matrix = match get_light_matrix(light, &world_bounds) {
+        Some(matrix) => matrix,
+        None => continue,
+      };
+
+      let tile = self.num_shadows as usize;
+      let tile_x = (tile % SHADOW_ATLAS_TILES) as u32;
+      let tile_y = (tile / SHADOW_ATLAS_TILES) as u32;
+
+      let params = DrawParameters {
+        depth: Depth {
+          test: DepthTest::IfLess,
+          write: true,
+          .. Default::default()
+        },
+        viewport: Some(Rect {
+          left: tile_x * self.tile_resolution,
+          bottom: tile_y * self.tile_resolution,
+          width: self.tile_resolution,
+          height: self.tile_resolution,
+        }),
+        .. Default::default()
+      };
+
+      let frustum = Frustum::from_matrix(&light_matrix);
+
+      for index in bvh.cull(&frustum) {
+        let item = &items[index];
+        if item.bounds.is_none() { continue; }
+
+        // blended objects don't cast shadows, and drawables without geometry are skipped
+        let drawable = match Object::find(world, &item.path).drawable {
+          Some(ref d) if !d.is_blended() => d,
+          _ => continue,
+        };
+        let geometry = match drawable.get_geometry() {
+          Some(geometry) => geometry,
+          None => continue,
+        };
+        let geometry = geometry.borrow();
+        let material = match drawable.get_material() {
+          Some(ref m) if m.borrow().alpha_mode == AlphaMode::Mask => Some(Rc::clone(m)),
+          _ => None,
+        };
+
+        if let Some(material) = material {
+          let material = material.borrow();
+          let opacity_map = material.maps.opacity.as_ref().map(|m| m.borrow());
+          let uniforms = uniform! {
+            light_matrix: math::matrix_to_uniform(light_matrix),
+            model: math::matrix_to_uniform(item.transform),
+            opacity_map: opacity_map.as_ref().map_or(&self.blank_map, |m| &**m),
+            dissolve: material.dissolve,
+            alpha_cutoff: material.alpha_cutoff,
+          };
+          let vertices = (&geometry.vertices, &geometry.texcoords);
+
+          match geometry.indices {
+            Some(ref indices) => framebuffer.draw(vertices, indices,
+                &self.masked_program.borrow(), &uniforms, &params).unwrap(),
+            None => framebuffer.draw(vertices, NoIndices(PrimitiveType::TrianglesList),
+                &self.masked_program.borrow(), &uniforms, &params).unwrap(),
+          }
+        } else {
+          let uniforms = uniform! {
+            light_matrix: math::matrix_to_uniform(light_matrix),
+            model: math::matrix_to_uniform(item.transform),
+          };
+
+          match geometry.indices {
+            Some(ref indices) => framebuffer.draw(&geometry.vertices, indices,
+                &self.program.borrow(), &uniforms, &params).unwrap(),
+            None => framebuffer.draw(&geometry.vertices, NoIndices(PrimitiveType::TrianglesList),
+                &self.program.borrow(), &uniforms, &params).unwrap(),
+          }
+        }
+
+        self.num_casters += 1;
+      }
+
+      // from clip space to the tile of this light in the atlas
+      let tile_size = 1.0 / SHADOW_ATLAS_TILES as f32;
+      let to_tile = Matrix4::from_translation(Vector3::new(
+          (tile_x as f32 + 0.5) * tile_size, (tile_y as f32 + 0.5) * tile_size, 0.5))
+          * Matrix4::from_nonuniform_scale(tile_size * 0.5, tile_size * 0.5, 0.5);
+
+      self.matrices[tile] = math::matrix_to_uniform(to_tile * light_matrix);
+      self.light_tiles[i] = tile as i32;
+      self.num_shadows += 1;
+    }
+  }
+}
+
+// the projection and view of the light, fitted to the bounding sphere of the world
+fn get_light_matrix(light: &Light, world_bounds: &BoundingBox) -> Option<Matrix4<f32>> {
+  let min = Vector3::from(world_bounds.0);
+  let max = Vector3::from(world_bounds.1);
+  let center = (min + max) * 0.5;
+  let radius = f32::max((max - min).magnitude() * 0.5, 0.01);
+
+  let position = Vector3::from(light.position);
+  let distance = (center - position).magnitude();
+
+  match light.kind {
+    LightKind::Directional => {
+      let direction = Vector3::from(light.direction);
+      if direction.magnitude2() == 0.0 { return None; }
+
+      let direction = direction.normalize();
+      let eye = center - direction * radius * 2.0;
+      let view = Matrix4::look_at(Point3::from_vec(eye), Point3::from_vec(center),
+          get_up(direction));
+      let projection = cgmath::ortho(-radius, radius, -radius, radius, radius, radius * 3.0);
+
+      Some(projection * view)
+    },
+    LightKind::Point => {
+      if distance <= radius { return None; }
+
+      let direction = (center - position) / distance;
+      let view = Matrix4::look_at(Point3::from_vec(position), Point3::from_vec(center),
+          get_up(direction));
+      let fov: Rad<f32> = Rad(2.0 * (radius / distance).asin());
+      let near = f32::max(distance - radius, 0.01);
+      let projection = cgmath::perspective(fov, 1.0, near, distance + radius);
+
+      Some(projection * view)
+    },
+    LightKind::Spot => {
+      let direction = Vector3::from(light.direction);
+      if direction.magnitude2() == 0.0 { return None; }
+
+      let direction = direction.normalize();
+      let view = Matrix4::look_at(Point3::from_vec(position),
+          Point3::from_vec(position + direction), get_up(direction));
+      let (_, cos_outer) = light.get_cone_cosines();
+      let fov: Rad<f32> = Rad(f32::min(2.0 * cos_outer.acos(), f32::consts::PI * 0.9));
+      let far = if light.range > 0.0 { light.range } else { distance + radius };
+      let projection = cgmath::perspective(fov, 1.0, 0.05, f32::max(far, 0.1));
+
+      Some(projection * view)
+    },
+  }
+}
+
+fn get_up(direction: Vector3<f32>) -> Vector3<f32> {
+  if direction.y.abs() < 0.99 { Vector3::unit_y() } else { Vector3::unit_x() }
+}
+
+fn create_atlas(display: &Facade, tile_resolution: u32) -> Result<DepthTexture2d, EngynError> {
+  let size = tile_resolution * SHADOW_ATLAS_TILES as u32;
+
+  DepthTexture2d::empty(display, size, size)
+      .map_err(|e| EngynError::texture(&Path::new("shadow_atlas"), &e.to_string()))
+}
+
+fn construct_program(display: &Facade) -> Result<Program, ProgramCreationError> {
+  Program::from_source(
+      display,
+      &r#"
+        #version 140
+
+        uniform mat4 light_matrix;
+        uniform mat4 model;
+
+        in vec3 position;
+
+        void main() {
+          gl_Position = light_matrix * model * vec4(position, 1.0);
+        }
+      "#,
+      &r#"
+        #version 140
+
+        void main() {
+        }
+      "#,
+      None)
+}
+
+// like the mesh program, discards the fragments that are less opaque than the cutoff
+fn construct_masked_program(display: &Facade) -> Result<Program, ProgramCreationError> {
+  Program::from_source(
+      display,
+      &r#"
+        #version 140
+
+        uniform mat4 light_matrix;
+        uniform mat4 model;
+
+        in vec3 position;
+        in vec2 texcoord;
+
+        out vec2 v_texcoord;
+
+        void main() {
+          v_texcoord = texcoord;
+          gl_Position = light_matrix * model * vec4(position, 1.0);
+        }
+      "#,
+      &r#"
+        #version 140
+
+        uniform sampler2D opacity_map;
+        uniform float dissolve;
+        uniform float alpha_cutoff;
+
+        in vec2 v_texcoord;
+
+        void main() {
+          if(dissolve * texture(opacity_map, v_texcoord).r < alpha_cutoff) {
+            discard;
+          }
+        }
+      "#,
+      None)
+}
@@ -0,0 +1,612 @@
+// Copyright (c) 2018 Remco Kranenburg
+//
+// GNU GENERAL PUBLIC LICENSE
+//    Version 3, 29 June 2007
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use bincode;
+use bincode::Infinite;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::f64;
+use std::ffi::OsString;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use geometry::MeshData;
+
+// how much more moving a vertex away from an open edge costs than moving it off a face
+const BOUNDARY_WEIGHT: f64 = 100.0;
+
+// collapses that turn a face further than this (as a cosine) are not allowed
+const MIN_NORMAL_DOT: f64 = 0.2;
+
+// bumped whenever the simplification changes, so older cache files are built again
+const LOD_CACHE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+pub struct LodLevel {
+  pub mesh: MeshData,
+  pub error: f32,
+}
+
+// the LOD chains of all meshes in an OBJ file, in the order of the file
+#[derive(Serialize, Deserialize)]
+struct LodCache {
+  version: u32,
+  ratios: Vec<f32>,
+  chains: Vec<Vec<LodLevel>>,
+}
+
+// symmetric 4x4 matrix that sums the squared distances to a set of planes
+#[derive(Copy, Clone)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+  fn zero() -> Quadric {
+    Quadric([0.0; 10])
+  }
+
+  fn from_plane(normal: [f64; 3], point: [f64; 3], weight: f64) -> Quadric {
+    let (a, b, c) = (normal[0], normal[1], normal[2]);
+    let d = -dot(normal, point);
+
+    Quadric([
+      weight * a * a, weight * a * b, weight * a * c, weight * a * d,
+      weight * b * b, weight * b * c, weight * b * d,
+      weight * c * c, weight * c * d,
+      weight * d * d,
+    ])
+  }
+
+  fn add(&self, other: &Quadric) -> Quadric {
+    let mut result = [0.0; 10];
+    for i in 0..10 {
+      result[i] = self.0[i] + other.0[i];
+    }
+    Quadric(result)
+  }
+
+  fn evaluate(&self, p: [f64; 3]) -> f64 {
+    let q = &self.0;
+    let (x, y, z) = (p[0], p[1], p[2]);
+
+    q[0] * x * x + 2.0 * q[1] * x * y + 2.0 * q[2] * x * z + 2.0 * q[3] * x
+        + q[4] * y * y + 2.0 * q[5] * y * z + 2.0 * q[6] * y
+        + q[7] * z * z + 2.0 * q[8] * z
+        + q[9]
+  }
+}
+
+struct Collapse {
+  cost: f64,
+  from: usize,
+  to: usize,
+  versions: (u32, u32),
+}
+
+impl PartialEq for Collapse {
+  fn eq(&self, other: &Collapse) -> bool {
+    self.cost == other.cost
+  }
+}
+
+impl Eq for Collapse {}
+
+impl PartialOrd for Collapse {
+  fn partial_cmp(&self, other: &Collapse) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for Collapse {
+  // reversed, so that the binary heap pops the cheapest collapse first
+  fn cmp(&self, other: &Collapse) -> Ordering {
+    other.cost.partial_cmp(&self.cost).unwrap_or(Ordering::Equal)
+  }
+}
+
+pub fn position_key(p: &[f32; 3]) -> [u32; 3] {
+  [p[0].to_bits(), p[1].to_bits(), p[2].to_bits()]
+}
+
+fn attribute_key(mesh: &MeshData, v: usize) -> [u32; 5] {
+  let (n, t) = (mesh.normals[v], mesh.texcoords[v]);
+  [n[0].to_bits(), n[1].to_bits(), n[2].to_bits(), t[0].to_bits(), t[1].to_bits()]
+}
+
+// The LOD chains of the meshes of an OBJ file, read from the cache file next to it if that is
+// newer than the OBJ file and was built with the same ratios. Otherwise `build` simplifies each
+// mesh and the result is written to the cache; failing to write it is only a warning.
+pub fn get_cached_lod_chains<F>(path: &Path, num_meshes: usize, ratios: &[f32], build: F)
+    -> Vec<Vec<LodLevel>> where F: Fn(usize) -> Vec<LodLevel> {
+  let mut cache_path = OsString::from(path.as_os_str());
+  cache_path.push(".lod");
+  let cache_path = PathBuf::from(cache_path);
+
+  if let Some(cache) = read_lod_cache(path, &cache_path) {
+    if cache.version == LOD_CACHE_VERSION && cache.ratios == ratios
+        && cache.chains.len() == num_meshes {
+      return cache.chains;
+    }
+  }
+
+  let cache = LodCache {
+    version: LOD_CACHE_VERSION,
+    ratios: ratios.to_vec(),
+    chains: (0..num_meshes).map(|i| build(i)).collect(),
+  };
+
+  let written = bincode::serialize(&cache, Infinite).ok()
+      .and_then(|bytes| File::create(&cache_path).and_then(|mut f| f.write_all(&bytes)).ok());
+
+  if written.is_none() {
+    eprintln!("Warning: could not write LOD cache {}", cache_path.display());
+  }
+
+  cache.chains
+}
+
+// None if there is no cache file or it is older than the OBJ file
+fn read_lod_cache(path: &Path, cache_path: &Path) -> Option<LodCache> {
+  let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+  let cache_modified = fs::metadata(cache_path).and_then(|m| m.modified()).ok()?;
+
+  if cache_modified < modified {
+    return None;
+  }
+
+  let mut bytes = Vec::new();
+  File::open(cache_path).and_then(|mut f| f.read_to_end(&mut bytes)).ok()?;
+  bincode::deserialize(&bytes).ok()
+}
+
+// Simplifies a mesh with quadric error metrics, using half-edge collapses so that the remaining
+// vertices keep their normals and texcoords. Vertices with the same position and attributes are
+// welded into one first. Positions with more than one set of attributes lie on a UV or normal
+// seam and are never removed, and neither are the positions in `locked_positions`, e.g. where
+// objects with different materials meet. Open edges are kept in place by extra planes along them,
+// and collapses that would make the mesh non-manifold are skipped.
+//
+// Returns a level for each of `ratios` (fractions of the original number of triangles, from high
+// to low), along with the largest geometric error of any collapse so far. Stops early when the
+// mesh can't be simplified any further.
+pub fn build_lod_chain(mesh: &MeshData, ratios: &[f32], locked_positions: &HashSet<[u32; 3]>)
+    -> Vec<LodLevel> {
+  let num_vertices = mesh.positions.len();
+  let positions: Vec<[f64; 3]> = mesh.positions.iter()
+      .map(|p| [p[0] as f64, p[1] as f64, p[2] as f64])
+      .collect();
+
+  // each vertex is replaced by the first one with the same position and attributes; positions
+  // with more than one set of attributes are counted, as those can't move
+  let mut welded = HashMap::new();
+  let mut num_attribute_sets = HashMap::new();
+  let weld: Vec<usize> = (0..num_vertices).map(|v| {
+    let key = (position_key(&mesh.positions[v]), attribute_key(mesh, v));
+
+    *welded.entry(key).or_insert_with(|| {
+      *num_attribute_sets.entry(key.0).or_insert(0) += 1;
+      v
+    })
+  }).collect();
+
+  let locked: Vec<bool> = mesh.positions.iter().map(|p| {
+    let key = position_key(p);
+    num_attribute_sets[&key] > 1 || locked_positions.contains(&key)
+  }).collect();
+
+  let mut triangles: Vec<[usize; 3]> = mesh.indices.chunks(3)
+      .filter(|t| t.len() == 3)
+      .map(|t| [weld[t[0] as usize], weld[t[1] as usize], weld[t[2] as usize]])
+      .filter(|t| t[0] != t[1] && t[1] != t[2] && t[2] != t[0])
+      .collect();
+  let mut triangle_alive = vec![true; triangles.len()];
+  let mut num_alive = triangles.len();
+
+  let mut vertex_triangles = vec![Vec::new(); num_vertices];
+  for (t, triangle) in triangles.iter().enumerate() {
+    for v in triangle {
+      vertex_triangles[*v].push(t);
+    }
+  }
+
+  // the error quadrics: one set to measure the error and one that also protects open edges
+  let mut error_quadrics = vec![Quadric::zero(); num_vertices];
+  let mut edge_counts = HashMap::new();
+
+  for triangle in &triangles {
+    let p = [positions[triangle[0]], positions[triangle[1]], positions[triangle[2]]];
+    let normal = normalize(cross(sub(p[1], p[0]), sub(p[2], p[0])));
+    let quadric = Quadric::from_plane(normal, p[0], 1.0);
+
+    for v in triangle {
+      error_quadrics[*v] = error_quadrics[*v].add(&quadric);
+    }
+
+    for i in 0..3 {
+      let edge = ordered_edge(triangle[i], triangle[(i + 1) % 3]);
+      *edge_counts.entry(edge).or_insert(0) += 1;
+    }
+  }
+
+  let mut quadrics = error_quadrics.clone();
+
+  for triangle in &triangles {
+    let p = [positions[triangle[0]], positions[triangle[1]], positions[triangle[2]]];
+    let normal = normalize(cross(sub(p[1], p[0]), sub(p[2], p[0])));
+
+    for i in 0..3 {
+      let (a, b) = (triangle[i], triangle[(i + 1) % 3]);
+
+      if edge_counts[&ordered_edge(a, b)] == 1 {
+        let edge = sub(positions[b], positions[a]);
+        let edge_normal = normalize(cross(edge, normal));
+        let quadric = Quadric::from_plane(edge_normal, positions[a],
+            BOUNDARY_WEIGHT * dot(edge, edge));
+
+        quadrics[a] = quadrics[a].add(&quadric);
+        quadrics[b] = quadrics[b].add(&quadric);
+      }
+    }
+  }
+
+  let mut vertex_alive = vec![true; num_vertices];
+  let mut versions = vec![0u32; num_vertices];
+  let mut heap = BinaryHeap::new();
+
+  for &(a, b) in edge_counts.keys() {
+    push_collapse(&mut heap, &quadrics, &positions, &locked, &versions, a, b);
+    push_collapse(&mut heap, &quadrics, &positions, &locked, &versions, b, a);
+  }
+
+  let mut levels = Vec::new();
+  let mut max_error = 0.0;
+
+  for ratio in ratios {
+    let target = (ratio * triangles.len() as f32) as usize;
+    let num_before = num_alive;
+
+    while num_alive > target {
+      let collapse = match heap.pop() {
+        Some(collapse) => collapse,
+        None => break,
+      };
+
+      let (from, to) = (collapse.from, collapse.to);
+
+      if !vertex_alive[from] || !vertex_alive[to]
+          || versions[from] != collapse.versions.0 || versions[to] != collapse.versions.1 {
+        continue;
+      }
+
+      if flips_triangle(&triangles, &triangle_alive, &vertex_triangles[from], &positions, from, to)
+          || !satisfies_link_condition(&triangles, &triangle_alive, &vertex_triangles, from, to) {
+        continue;
+      }
+
+      for t in vertex_triangles[from].clone() {
+        if !triangle_alive[t] { continue; }
+
+        if triangles[t].contains(&to) {
+          triangle_alive[t] = false;
+          num_alive -= 1;
+        } else {
+          for v in triangles[t].iter_mut() {
+            if *v == from { *v = to; }
+          }
+          vertex_triangles[to].push(t);
+        }
+      }
+
+      let error = error_quadrics[from].add(&error_quadrics[to]).evaluate(positions[to]);
+      max_error = f64::max(max_error, f64::max(0.0, error).sqrt());
+
+      quadrics[to] = quadrics[to].add(&quadrics[from]);
+      error_quadrics[to] = error_quadrics[to].add(&error_quadrics[from]);
+      vertex_alive[from] = false;
+      versions[to] += 1;
+
+      vertex_triangles[to].retain(|t| triangle_alive[*t]);
+
+      let mut neighbours: Vec<usize> = vertex_triangles[to].iter()
+          .flat_map(|t| triangles[*t].iter().cloned())
+          .filter(|v| *v != to)
+          .collect();
+      neighbours.sort();
+      neighbours.dedup();
+
+      for v in neighbours {
+        push_collapse(&mut heap, &quadrics, &positions, &locked, &versions, to, v);
+        push_collapse(&mut heap, &quadrics, &positions, &locked, &versions, v, to);
+      }
+    }
+
+    // not worth another level if barely anything changed
+    if num_alive as f32 > 0.9 * num_before as f32 {
+      break;
+    }
+
+    levels.push(LodLevel {
+      mesh: compact(mesh, &triangles, &triangle_alive),
+      error: max_error as f32,
+    });
+  }
+
+  levels
+}
+
+fn push_collapse(heap: &mut BinaryHeap<Collapse>, quadrics: &[Quadric], positions: &[[f64; 3]],
+    locked: &[bool], versions: &[u32], from: usize, to: usize) {
+  if locked[from] { return; }
+
+  heap.push(Collapse {
+    cost: quadrics[from].add(&quadrics[to]).evaluate(positions[to]),
+    from: from,
+    to: to,
+    versions: (versions[from], versions[to]),
+  });
+}
+
+// whether moving `from` onto `to` would turn one of the remaining triangles around
+fn flips_triangle(triangles: &[[usize; 3]], triangle_alive: &[bool], around: &[usize],
+    positions: &[[f64; 3]], from: usize, to: usize) -> bool {
+  for t in around {
+    if !triangle_alive[*t] || triangles[*t].contains(&to) { continue; }
+
+    let triangle = triangles[*t];
+    let before = [positions[triangle[0]], positions[triangle[1]], positions[triangle[2]]];
+    let mut after = before;
+
+    for i in 0..3 {
+      if triangle[i] == from { after[i] = positions[to]; }
+    }
+
+    let normal_before = normalize(cross(sub(before[1], before[0]), sub(before[2], before[0])));
+    let normal_after = normalize(cross(sub(after[1], after[0]), sub(after[2], after[0])));
+
+    if dot(normal_before, normal_after) < MIN_NORMAL_DOT {
+      return true;
+    }
+  }
+
+  false
+}
+
+// Whether collapsing the edge keeps the mesh manifold: the only vertices next to both ends may be
+// the ones opposite the edge, or the collapse would pinch the surface or fold it onto itself.
+fn satisfies_link_condition(triangles: &[[usize; 3]], triangle_alive: &[bool],
+    vertex_triangles: &[Vec<usize>], from: usize, to: usize) -> bool {
+  let neighbours = |v: usize| -> HashSet<usize> {
+    vertex_triangles[v].iter()
+        .filter(|t| triangle_alive[**t])
+        .flat_map(|t| triangles[*t].iter().cloned())
+        .filter(|u| *u != v)
+        .collect()
+  };
+
+  let num_opposite = vertex_triangles[from].iter()
+      .filter(|t| triangle_alive[**t] && triangles[**t].contains(&to))
+      .count();
+
+  neighbours(from).intersection(&neighbours(to)).count() == num_opposite
+}
+
+// keeps only the vertices that are still used, with their original attributes
+fn compact(mesh: &MeshData, triangles: &[[usize; 3]], triangle_alive: &[bool]) -> MeshData {
+  let mut remap = HashMap::new();
+  let mut result = MeshData {
+    positions: Vec::new(),
+    normals: Vec::new(),
+    texcoords: Vec::new(),
+    indices: Vec::new(),
+  };
+
+  for (triangle, _) in triangles.iter().zip(triangle_alive.iter()).filter(|x| *x.1) {
+    for v in triangle {
+      let index = *remap.entry(*v).or_insert_with(|| {
+        result.positions.push(mesh.positions[*v]);
+        result.normals.push(mesh.normals[*v]);
+        result.texcoords.push(mesh.texcoords[*v]);
+        result.positions.len() as u32 - 1
+      });
+
+      result.indices.push(index);
+    }
+  }
+
+  result
+}
+
+fn ordered_edge(a: usize, b: usize) -> (usize, usize) {
+  if a < b { (a, b) } else { (b, a) }
+}
+
+fn sub(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+  [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+  a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+  [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn normalize(a: [f64; 3]) -> [f64; 3] {
+  let length = dot(a, a).sqrt();
+  if length > 0.0 { [a[0] / length, a[1] / length, a[2] / length] } else { a }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+  use std::collections::HashSet;
+
+  use geometry::MeshData;
+  use super::*;
+
+  const RATIOS: [f32; 4] = [0.5, 0.25, 0.125, 0.0625];
+
+  // a flat, unit sized grid of n by n squares in the xy plane, two triangles each
+  fn grid(n: usize) -> MeshData {
+    let mut mesh = MeshData {
+      positions: Vec::new(),
+      normals: Vec::new(),
+      texcoords: Vec::new(),
+      indices: Vec::new(),
+    };
+
+    for j in 0..n + 1 {
+      for i in 0..n + 1 {
+        let (x, y) = (i as f32 / n as f32, j as f32 / n as f32);
+        mesh.positions.push([x, y, 0.0]);
+        mesh.normals.push([0.0, 0.0, 1.0]);
+        mesh.texcoords.push([x, y]);
+      }
+    }
+
+    for j in 0..n {
+      for i in 0..n {
+        let a = (j * (n + 1) + i) as u32;
+        let (b, c, d) = (a + 1, a + n as u32 + 2, a + n as u32 + 1);
+        mesh.indices.extend_from_slice(&[a, b, c, a, c, d]);
+      }
+    }
+
+    mesh
+  }
+
+  // the same grid with a UV seam down the middle column: the squares to the right of it use
+  // copies of its vertices with other texcoords
+  fn grid_with_seam(n: usize) -> MeshData {
+    let mut mesh = grid(n);
+    let mut copies = HashMap::new();
+
+    for j in 0..n + 1 {
+      let v = j * (n + 1) + n / 2;
+      copies.insert(v as u32, mesh.positions.len() as u32);
+      mesh.positions.push(mesh.positions[v]);
+      mesh.normals.push(mesh.normals[v]);
+      mesh.texcoords.push([mesh.texcoords[v][0] + 1.0, mesh.texcoords[v][1]]);
+    }
+
+    // the triangles of each row are listed from left to right, 2 per square
+    for t in 0..mesh.indices.len() / 3 {
+      if (t / 2) % n >= n / 2 {
+        for index in &mut mesh.indices[3 * t..3 * t + 3] {
+          *index = copies.get(index).cloned().unwrap_or(*index);
+        }
+      }
+    }
+
+    mesh
+  }
+
+  // every triangle with its own three vertices, as some OBJ exporters write them
+  fn split(mesh: &MeshData) -> MeshData {
+    MeshData {
+      positions: mesh.indices.iter().map(|i| mesh.positions[*i as usize]).collect(),
+      normals: mesh.indices.iter().map(|i| mesh.normals[*i as usize]).collect(),
+      texcoords: mesh.indices.iter().map(|i| mesh.texcoords[*i as usize]).collect(),
+      indices: (0..mesh.indices.len() as u32).collect(),
+    }
+  }
+
+  fn contains_position(mesh: &MeshData, p: [f32; 3]) -> bool {
+    mesh.positions.iter().any(|q| *q == p)
+  }
+
+  #[test]
+  fn flat_grid_simplifies_without_error() {
+    let mesh = grid(16);
+    let levels = build_lod_chain(&mesh, &RATIOS, &HashSet::new());
+
+    assert_eq!(levels.len(), RATIOS.len());
+
+    let mut num_triangles = mesh.get_num_triangles();
+    for (level, ratio) in levels.iter().zip(RATIOS.iter()) {
+      assert!(level.mesh.get_num_triangles() < num_triangles);
+      assert!(level.mesh.get_num_triangles() as f32 <= ratio * mesh.get_num_triangles() as f32);
+      assert!(level.error < 1e-4);
+      assert_eq!(level.mesh.get_bounding_box(), mesh.get_bounding_box());
+      num_triangles = level.mesh.get_num_triangles();
+    }
+  }
+
+  #[test]
+  fn split_vertices_are_welded() {
+    let levels = build_lod_chain(&split(&grid(16)), &RATIOS, &HashSet::new());
+
+    assert_eq!(levels.len(), RATIOS.len());
+    assert!(levels[0].mesh.get_num_triangles() <= 256);
+  }
+
+  #[test]
+  fn seams_stay_in_place() {
+    let n = 16;
+    let levels = build_lod_chain(&grid_with_seam(n), &RATIOS, &HashSet::new());
+
+    assert!(!levels.is_empty());
+
+    for level in &levels {
+      for j in 0..n + 1 {
+        assert!(contains_position(&level.mesh, [0.5, j as f32 / n as f32, 0.0]));
+      }
+    }
+  }
+
+  #[test]
+  fn locked_positions_stay_in_place() {
+    let centre = [0.5, 0.5, 0.0];
+    let locked: HashSet<[u32; 3]> = [position_key(&centre)].iter().cloned().collect();
+    let levels = build_lod_chain(&grid(16), &RATIOS, &locked);
+
+    assert_eq!(levels.len(), RATIOS.len());
+
+    for level in &levels {
+      assert!(contains_position(&level.mesh, centre));
+    }
+  }
+
+  #[test]
+  fn levels_stay_manifold() {
+    for level in build_lod_chain(&grid_with_seam(16), &RATIOS, &HashSet::new()) {
+      let mut edge_counts = HashMap::new();
+
+      for t in level.mesh.indices.chunks(3) {
+        let keys: Vec<[u32; 3]> = t.iter()
+            .map(|i| position_key(&level.mesh.positions[*i as usize]))
+            .collect();
+
+        assert!(keys[0] != keys[1] && keys[1] != keys[2] && keys[2] != keys[0]);
+
+        for i in 0..3 {
+          let (a, b) = (keys[i], keys[(i + 1) % 3]);
+          *edge_counts.entry(if a < b { (a, b) } else { (b, a) }).or_insert(0) += 1;
+        }
+      }
+
+      assert!(edge_counts.values().all(|count| *count <= 2));
+    }
+  }
+}
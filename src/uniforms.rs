@@ -16,13 +16,41 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use glium::texture::DepthTexture2d;
 use glium::texture::SrgbTexture2d;
+use glium::texture::Texture2d;
+use glium::texture::UnsignedTexture2d;
+use glium::uniforms::AsUniformValue;
+use glium::uniforms::DepthTextureComparison;
+use glium::uniforms::MagnifySamplerFilter;
+use glium::uniforms::MinifySamplerFilter;
+use glium::uniforms::SamplerBehavior;
+use glium::uniforms::UniformBuffer;
 use glium::uniforms::Uniforms;
 use glium::uniforms::UniformValue;
 
-use light::Light;
+use material::AlphaMode;
+use shadow::MAX_NUM_SHADOWS;
 
-pub const MAX_NUM_LIGHTS: usize = 32usize;
+pub const MAX_NUM_LIGHTS: usize = 256usize;
+pub const LIGHT_BLOCK_STRIDE: usize = 4usize;
+
+// spelled out so that drawing doesn't format them; the length follows MAX_NUM_SHADOWS
+const SHADOW_MATRIX_NAMES: [&str; MAX_NUM_SHADOWS] = [
+  "shadow_matrices[0]",
+  "shadow_matrices[1]",
+  "shadow_matrices[2]",
+  "shadow_matrices[3]",
+];
+
+// Every light takes LIGHT_BLOCK_STRIDE vec4s: the position and kind, the radiance and range, the
+// direction and shadow tile, and the cosines of the cone angles.
+pub struct LightBlock {
+  pub light_data: [[f32; 4]],
+}
+
+implement_buffer_content!(LightBlock);
+implement_uniform_block!(LightBlock, light_data);
 
 pub struct ObjectUniforms<'a> {
   pub projection: [[f32; 4]; 4],
@@ -35,8 +63,29 @@ pub struct ObjectUniforms<'a> {
   pub shininess: f32,
   pub metalness: f32,
   pub reflectivity: f32,
-  pub num_lights: i32,
-  pub lights: [Light; MAX_NUM_LIGHTS],
+  pub roughness: f32,
+  pub emissive_color: [f32; 3],
+  pub dissolve: f32,
+  pub alpha_mode: AlphaMode,
+  pub alpha_cutoff: f32,
+  pub normal_map: Option<&'a Texture2d>,
+  pub specular_map: Option<&'a SrgbTexture2d>,
+  pub shininess_map: Option<&'a Texture2d>,
+  pub roughness_map: Option<&'a Texture2d>,
+  pub emissive_map: Option<&'a SrgbTexture2d>,
+  pub opacity_map: Option<&'a Texture2d>,
+  pub use_pbr: bool,
+  pub camera_position: [f32; 3],
+  pub light_buffer: &'a UniformBuffer<LightBlock>,
+  pub cluster_grid: &'a UnsignedTexture2d,
+  pub cluster_indices: &'a UnsignedTexture2d,
+  pub cluster_matrix: [[f32; 4]; 4],
+  pub cluster_depth_range: [f32; 2],
+  pub num_global_lights: i32,
+  pub shadow_map: &'a DepthTexture2d,
+  pub num_shadows: i32,
+  pub shadow_matrices: [[[f32; 4]; 4]; MAX_NUM_SHADOWS],
+  pub shadow_filter_radius: i32,
   pub eye_i: usize,
   pub is_anaglyph: bool,
 }
@@ -53,11 +102,56 @@ impl<'a> Uniforms for ObjectUniforms<'a> {
     f("shininess", UniformValue::Float(self.shininess));
     f("metalness", UniformValue::Float(self.metalness));
     f("reflectivity", UniformValue::Float(self.reflectivity));
-    f("num_lights", UniformValue::SignedInt(self.num_lights));
+    f("roughness", UniformValue::Float(self.roughness));
+    f("emissive_color", UniformValue::Vec3(self.emissive_color));
+    f("dissolve", UniformValue::Float(self.dissolve));
+    f("alpha_mode", UniformValue::SignedInt(match self.alpha_mode {
+      AlphaMode::Opaque => 0,
+      AlphaMode::Mask => 1,
+      AlphaMode::Blend => 2,
+    }));
+    f("alpha_cutoff", UniformValue::Float(self.alpha_cutoff));
+
+    // the maps that are missing aren't declared by the permutation of the shader
+    if let Some(map) = self.normal_map { f("normal_map", UniformValue::Texture2d(map, None)) }
+    if let Some(map) = self.specular_map { f("specular_map", UniformValue::SrgbTexture2d(map, None)) }
+    if let Some(map) = self.shininess_map { f("shininess_map", UniformValue::Texture2d(map, None)) }
+    if let Some(map) = self.roughness_map { f("roughness_map", UniformValue::Texture2d(map, None)) }
+    if let Some(map) = self.emissive_map { f("emissive_map", UniformValue::SrgbTexture2d(map, None)) }
+    if let Some(map) = self.opacity_map { f("opacity_map", UniformValue::Texture2d(map, None)) }
+
+    f("use_pbr", UniformValue::Bool(self.use_pbr));
+    f("camera_position", UniformValue::Vec3(self.camera_position));
+    f("LightBlock", self.light_buffer.as_uniform_value());
+
+    // integer textures can't be filtered, and are incomplete when asked to
+    let cluster_sampler = SamplerBehavior {
+      minify_filter: MinifySamplerFilter::Nearest,
+      magnify_filter: MagnifySamplerFilter::Nearest,
+      .. Default::default()
+    };
+
+    f("cluster_grid", UniformValue::UnsignedTexture2d(self.cluster_grid, Some(cluster_sampler)));
+    f("cluster_indices",
+        UniformValue::UnsignedTexture2d(self.cluster_indices, Some(cluster_sampler)));
+    f("cluster_matrix", UniformValue::Mat4(self.cluster_matrix));
+    f("cluster_depth_range", UniformValue::Vec2(self.cluster_depth_range));
+    f("num_global_lights", UniformValue::SignedInt(self.num_global_lights));
+
+    // compares with the depth in the shadow map, with bilinear filtering of the results
+    let shadow_sampler = SamplerBehavior {
+      depth_texture_comparison: Some(DepthTextureComparison::LessOrEqual),
+      minify_filter: MinifySamplerFilter::Linear,
+      magnify_filter: MagnifySamplerFilter::Linear,
+      .. Default::default()
+    };
+
+    f("shadow_map", UniformValue::DepthTexture2d(self.shadow_map, Some(shadow_sampler)));
+    f("num_shadows", UniformValue::SignedInt(self.num_shadows));
+    f("shadow_filter_radius", UniformValue::SignedInt(self.shadow_filter_radius));
 
-    for i in 0..MAX_NUM_LIGHTS {
-      f(&format!("lights[{}].color", i)[..], UniformValue::Vec3(self.lights[i].color));
-      f(&format!("lights[{}].position", i)[..], UniformValue::Vec3(self.lights[i].position));
+    for (name, matrix) in SHADOW_MATRIX_NAMES.iter().zip(self.shadow_matrices.iter()) {
+      f(*name, UniformValue::Mat4(*matrix));
     }
 
     f("eye_i", UniformValue::UnsignedInt(self.eye_i as u32));
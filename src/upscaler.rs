@@ -0,0 +1,212 @@
+// Copyright (c) 2018 Remco Kranenburg
+//
+// GNU GENERAL PUBLIC LICENSE
+//    Version 3, 29 June 2007
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use glium::DrawParameters;
+use glium::Program;
+use glium::Rect;
+use glium::Surface;
+use glium::backend::Facade;
+use glium::framebuffer::SimpleFrameBuffer;
+use glium::texture::Texture2d;
+use glium::uniforms::MagnifySamplerFilter;
+use glium::uniforms::MinifySamplerFilter;
+use glium::uniforms::Sampler;
+
+use geometry::Geometry;
+
+const JITTER_SEQUENCE_LENGTH: usize = 8;
+
+// Reconstructs a full resolution image from low resolution frames that are rendered with a
+// sub-pixel jitter. Each frame is blended into a history buffer, after clamping the history to
+// the neighbourhood of the new frame to limit ghosting. There are no motion vectors, so the
+// history is not reprojected when the camera moves.
+pub struct TemporalUpscaler {
+  pub feedback: f32,
+
+  program: Program,
+  quad: Geometry,
+  history: [Texture2d; 2],
+  current: usize,
+  frame: usize,
+  history_valid: bool,
+}
+
+impl TemporalUpscaler {
+  pub fn new(display: &Facade, width: u32, height: u32) -> TemporalUpscaler {
+    TemporalUpscaler {
+      feedback: 0.9,
+      program: create_program(display),
+      quad: Geometry::new_quad(display, [2.0, 2.0], false),
+      history: [
+        Texture2d::empty(display, width, height).unwrap(),
+        Texture2d::empty(display, width, height).unwrap(),
+      ],
+      current: 0,
+      frame: 0,
+      history_valid: false,
+    }
+  }
+
+  // the sub-pixel offset to render the current frame with, in pixels
+  pub fn get_jitter(&self) -> [f32; 2] {
+    let index = self.frame % JITTER_SEQUENCE_LENGTH + 1;
+    [halton(index, 2) - 0.5, halton(index, 3) - 0.5]
+  }
+
+  // drops the history, e.g. when a frame was not upscaled
+  pub fn invalidate(&mut self) {
+    self.history_valid = false;
+  }
+
+  // upscales each source rect of `source` to the corresponding target rect of the output
+  pub fn upscale(&mut self, display: &Facade, source: &Texture2d, rects: &[(Rect, Rect)]) {
+    let next = 1 - self.current;
+    let jitter = self.get_jitter();
+    let source_size = (source.get_width() as f32, source.get_height().unwrap() as f32);
+    let history_size = (
+      self.history[next].get_width() as f32,
+      self.history[next].get_height().unwrap() as f32,
+    );
+    let feedback = if self.history_valid { self.feedback } else { 0.0 };
+
+    {
+      let mut framebuffer = SimpleFrameBuffer::new(display, &self.history[next]).unwrap();
+
+      for &(source_rect, target_rect) in rects {
+        let uniforms = uniform! {
+          current: linear_sampled(source),
+          history: linear_sampled(&self.history[self.current]),
+          current_rect: normalize_rect(&source_rect, source_size),
+          history_rect: normalize_rect(&target_rect, history_size),
+          current_texel: [1.0 / source_size.0, 1.0 / source_size.1],
+          jitter: [jitter[0] / source_size.0, jitter[1] / source_size.1],
+          feedback: feedback,
+        };
+
+        let params = DrawParameters {
+          viewport: Some(target_rect),
+          .. Default::default()
+        };
+
+        framebuffer.draw(
+            (&self.quad.vertices, &self.quad.texcoords),
+            self.quad.indices.as_ref().unwrap(),
+            &self.program,
+            &uniforms,
+            &params).unwrap();
+      }
+    }
+
+    self.current = next;
+    self.frame += 1;
+    self.history_valid = true;
+  }
+
+  // the latest history, which must not be drawn into
+  pub fn get_output(&self) -> &Texture2d {
+    &self.history[self.current]
+  }
+}
+
+fn linear_sampled(texture: &Texture2d) -> Sampler<Texture2d> {
+  texture.sampled()
+      .magnify_filter(MagnifySamplerFilter::Linear)
+      .minify_filter(MinifySamplerFilter::Linear)
+}
+
+fn normalize_rect(rect: &Rect, size: (f32, f32)) -> [f32; 4] {
+  [
+    rect.left as f32 / size.0,
+    rect.bottom as f32 / size.1,
+    rect.width as f32 / size.0,
+    rect.height as f32 / size.1,
+  ]
+}
+
+fn halton(index: usize, base: usize) -> f32 {
+  let mut fraction = 1.0;
+  let mut result = 0.0;
+  let mut i = index;
+
+  while i > 0 {
+    fraction /= base as f32;
+    result += fraction * (i % base) as f32;
+    i /= base;
+  }
+
+  result
+}
+
+fn create_program(display: &Facade) -> Program {
+  Program::from_source(
+      display,
+      &r#"
+        #version 140
+
+        in vec3 position;
+        in vec2 texcoord;
+
+        out vec2 v_texcoord;
+
+        void main() {
+          v_texcoord = texcoord;
+          gl_Position = vec4(position, 1.0);
+        }
+      "#,
+      &r#"
+        #version 140
+
+        uniform sampler2D current;
+        uniform sampler2D history;
+        uniform vec4 current_rect;
+        uniform vec4 history_rect;
+        uniform vec2 current_texel;
+        uniform vec2 jitter;
+        uniform float feedback;
+
+        in vec2 v_texcoord;
+
+        out vec4 color;
+
+        void main() {
+          vec2 current_min = current_rect.xy + 0.5 * current_texel;
+          vec2 current_max = current_rect.xy + current_rect.zw - 0.5 * current_texel;
+          vec2 uv = clamp(current_rect.xy + v_texcoord * current_rect.zw + jitter, current_min,
+              current_max);
+
+          vec3 current_color = texture(current, uv).rgb;
+          vec3 neighbourhood_min = current_color;
+          vec3 neighbourhood_max = current_color;
+
+          for (int x = -1; x <= 1; x++) {
+            for (int y = -1; y <= 1; y++) {
+              vec2 neighbour_uv = clamp(uv + vec2(x, y) * current_texel, current_min, current_max);
+              vec3 neighbour = texture(current, neighbour_uv).rgb;
+              neighbourhood_min = min(neighbourhood_min, neighbour);
+              neighbourhood_max = max(neighbourhood_max, neighbour);
+            }
+          }
+
+          vec3 history_color = texture(history, history_rect.xy + v_texcoord * history_rect.zw).rgb;
+          history_color = clamp(history_color, neighbourhood_min, neighbourhood_max);
+
+          color = vec4(mix(current_color, history_color, feedback), 1.0);
+        }
+      "#,
+      None).unwrap()
+}